@@ -0,0 +1,276 @@
+use std::sync::Arc;
+
+use tfhe::boolean::prelude::*;
+
+use crate::dag::{DagBuilder, GateDag};
+use crate::folding::{fold_and, fold_mux, fold_or, fold_xor};
+use crate::sbox::SBoxPlans;
+
+/// This trait abstracts the gates that are used to evaluate AES on encrypted bits.
+///
+/// `FHEByte`, `State`, `Key` and the `Runnable` of the staged S-box evaluation only ever go through these gates, so the same code runs
+/// homomorphically with the tfhe `ServerKey`, or on plain booleans with the `ClearBackend` to check the pipeline without any bootstrapping.
+pub trait Backend: Sync {
+    type Bit: Clone + Send + Sync;
+
+    fn trivial(&self, value: bool) -> Self::Bit;
+
+    fn not(&self, bit: &Self::Bit) -> Self::Bit;
+
+    fn and(&self, lhs: &Self::Bit, rhs: &Self::Bit) -> Self::Bit;
+
+    fn or(&self, lhs: &Self::Bit, rhs: &Self::Bit) -> Self::Bit;
+
+    fn xor(&self, lhs: &Self::Bit, rhs: &Self::Bit) -> Self::Bit;
+
+    /// Returns `then` if `condition` is true and `otherwise` if it is false
+    fn mux(&self, condition: &Self::Bit, then: &Self::Bit, otherwise: &Self::Bit) -> Self::Bit;
+
+    fn not_assign(&self, bit: &mut Self::Bit) {
+        *bit = self.not(bit);
+    }
+
+    /// The value of a bit known in the clear, such as a trivial encryption, or `None` for an encrypted bit.
+    ///
+    /// Backends which track known bits fold the gates reading them into copies, nots and constants, see the `folding` module.
+    fn constant(&self, _bit: &Self::Bit) -> Option<bool> {
+        None
+    }
+
+    fn and_assign(&self, lhs: &mut Self::Bit, rhs: &Self::Bit) {
+        *lhs = self.and(lhs, rhs);
+    }
+
+    fn or_assign(&self, lhs: &mut Self::Bit, rhs: &Self::Bit) {
+        *lhs = self.or(lhs, rhs);
+    }
+
+    fn xor_assign(&self, lhs: &mut Self::Bit, rhs: &Self::Bit) {
+        *lhs = self.xor(lhs, rhs);
+    }
+
+    /// The S-Box evaluation plans used by `sub_byte` and `inv_sub_byte`, shared by the whole process unless the backend holds its own.
+    fn s_box_plans(&self) -> &Arc<SBoxPlans> {
+        SBoxPlans::shared()
+    }
+
+    /// Runs `op` on the thread pool of the backend, the global rayon pool unless the backend holds its own.
+    fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        op()
+    }
+
+    /// Runs a circuit on the bits as a single DAG, so that its independent gates overlap on the thread pool instead of waiting for the barriers
+    /// of the parallel loops, see `GateDag::run`. The circuit receives the wire of every bit and returns the wires of its outputs.
+    fn run_dag(
+        &self,
+        bits: &[Self::Bit],
+        circuit: impl FnOnce(&DagBuilder, &[usize]) -> Vec<usize>,
+    ) -> Vec<Self::Bit> {
+        GateDag::run(bits, &self, circuit)
+    }
+}
+
+/// This trait abstracts the encryption and decryption of bits, the client side counterpart of `Backend`.
+pub trait ClientBackend {
+    type Bit;
+
+    fn encrypt(&self, value: bool) -> Self::Bit;
+
+    fn decrypt(&self, bit: &Self::Bit) -> bool;
+}
+
+impl<B: Backend + ?Sized> Backend for &B {
+    type Bit = B::Bit;
+
+    fn trivial(&self, value: bool) -> B::Bit {
+        (**self).trivial(value)
+    }
+
+    fn not(&self, bit: &B::Bit) -> B::Bit {
+        (**self).not(bit)
+    }
+
+    fn and(&self, lhs: &B::Bit, rhs: &B::Bit) -> B::Bit {
+        (**self).and(lhs, rhs)
+    }
+
+    fn or(&self, lhs: &B::Bit, rhs: &B::Bit) -> B::Bit {
+        (**self).or(lhs, rhs)
+    }
+
+    fn xor(&self, lhs: &B::Bit, rhs: &B::Bit) -> B::Bit {
+        (**self).xor(lhs, rhs)
+    }
+
+    fn mux(&self, condition: &B::Bit, then: &B::Bit, otherwise: &B::Bit) -> B::Bit {
+        (**self).mux(condition, then, otherwise)
+    }
+
+    fn not_assign(&self, bit: &mut B::Bit) {
+        (**self).not_assign(bit)
+    }
+
+    fn constant(&self, bit: &B::Bit) -> Option<bool> {
+        (**self).constant(bit)
+    }
+
+    fn and_assign(&self, lhs: &mut B::Bit, rhs: &B::Bit) {
+        (**self).and_assign(lhs, rhs)
+    }
+
+    fn or_assign(&self, lhs: &mut B::Bit, rhs: &B::Bit) {
+        (**self).or_assign(lhs, rhs)
+    }
+
+    fn xor_assign(&self, lhs: &mut B::Bit, rhs: &B::Bit) {
+        (**self).xor_assign(lhs, rhs)
+    }
+
+    fn s_box_plans(&self) -> &Arc<SBoxPlans> {
+        (**self).s_box_plans()
+    }
+
+    fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        (**self).install(op)
+    }
+
+    fn run_dag(
+        &self,
+        bits: &[B::Bit],
+        circuit: impl FnOnce(&DagBuilder, &[usize]) -> Vec<usize>,
+    ) -> Vec<B::Bit> {
+        (**self).run_dag(bits, circuit)
+    }
+}
+
+impl<C: ClientBackend + ?Sized> ClientBackend for &C {
+    type Bit = C::Bit;
+
+    fn encrypt(&self, value: bool) -> C::Bit {
+        (**self).encrypt(value)
+    }
+
+    fn decrypt(&self, bit: &C::Bit) -> bool {
+        (**self).decrypt(bit)
+    }
+}
+
+impl Backend for ServerKey {
+    type Bit = Ciphertext;
+
+    fn trivial(&self, value: bool) -> Ciphertext {
+        self.trivial_encrypt(value)
+    }
+
+    fn not(&self, bit: &Ciphertext) -> Ciphertext {
+        ServerKey::not(self, bit)
+    }
+
+    fn and(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        fold_and(self, lhs, rhs).unwrap_or_else(|| BinaryBooleanGates::and(self, lhs, rhs))
+    }
+
+    fn or(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        fold_or(self, lhs, rhs).unwrap_or_else(|| BinaryBooleanGates::or(self, lhs, rhs))
+    }
+
+    fn xor(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        fold_xor(self, lhs, rhs).unwrap_or_else(|| BinaryBooleanGates::xor(self, lhs, rhs))
+    }
+
+    fn mux(&self, condition: &Ciphertext, then: &Ciphertext, otherwise: &Ciphertext) -> Ciphertext {
+        fold_mux(self, condition, then, otherwise)
+            .unwrap_or_else(|| ServerKey::mux(self, condition, then, otherwise))
+    }
+
+    fn not_assign(&self, bit: &mut Ciphertext) {
+        ServerKey::not_assign(self, bit)
+    }
+
+    fn constant(&self, bit: &Ciphertext) -> Option<bool> {
+        match bit {
+            Ciphertext::Trivial(value) => Some(*value),
+            Ciphertext::Encrypted(_) => None,
+        }
+    }
+
+    fn and_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        match fold_and(self, lhs, rhs) {
+            Some(folded) => *lhs = folded,
+            None => BinaryBooleanGatesAssign::and_assign(self, lhs, rhs),
+        }
+    }
+
+    fn or_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        match fold_or(self, lhs, rhs) {
+            Some(folded) => *lhs = folded,
+            None => BinaryBooleanGatesAssign::or_assign(self, lhs, rhs),
+        }
+    }
+
+    fn xor_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        match fold_xor(self, lhs, rhs) {
+            Some(folded) => *lhs = folded,
+            None => BinaryBooleanGatesAssign::xor_assign(self, lhs, rhs),
+        }
+    }
+}
+
+impl ClientBackend for ClientKey {
+    type Bit = Ciphertext;
+
+    fn encrypt(&self, value: bool) -> Ciphertext {
+        ClientKey::encrypt(self, value)
+    }
+
+    fn decrypt(&self, bit: &Ciphertext) -> bool {
+        ClientKey::decrypt(self, bit)
+    }
+}
+
+/// ClearBackend evaluates the gates on plain booleans.
+///
+/// It plays the part of both the client key and the server key, which makes it a drop-in replacement for `gen_keys()` when testing
+/// the AES pipeline in the clear.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClearBackend;
+
+impl Backend for ClearBackend {
+    type Bit = bool;
+
+    fn trivial(&self, value: bool) -> bool {
+        value
+    }
+
+    fn not(&self, bit: &bool) -> bool {
+        !bit
+    }
+
+    fn and(&self, lhs: &bool, rhs: &bool) -> bool {
+        lhs & rhs
+    }
+
+    fn or(&self, lhs: &bool, rhs: &bool) -> bool {
+        lhs | rhs
+    }
+
+    fn xor(&self, lhs: &bool, rhs: &bool) -> bool {
+        lhs ^ rhs
+    }
+
+    fn mux(&self, condition: &bool, then: &bool, otherwise: &bool) -> bool {
+        if *condition { *then } else { *otherwise }
+    }
+}
+
+impl ClientBackend for ClearBackend {
+    type Bit = bool;
+
+    fn encrypt(&self, value: bool) -> bool {
+        value
+    }
+
+    fn decrypt(&self, bit: &bool) -> bool {
+        *bit
+    }
+}
@@ -70,6 +70,33 @@ impl From<bool> for Operand {
 }
 
 impl Operand {
+    /// The operands in the order of their declaration, which is also their order as expressions
+    pub const ALL: [Operand; 18] = [
+        Operand::True,
+        Operand::False,
+        Operand::Bit0,
+        Operand::NotBit0,
+        Operand::Bit1,
+        Operand::NotBit1,
+        Operand::Bit2,
+        Operand::NotBit2,
+        Operand::Bit3,
+        Operand::NotBit3,
+        Operand::Bit4,
+        Operand::NotBit4,
+        Operand::Bit5,
+        Operand::NotBit5,
+        Operand::Bit6,
+        Operand::NotBit6,
+        Operand::Bit7,
+        Operand::NotBit7,
+    ];
+
+    /// The selector bit `index`, from Bit0 to Bit7
+    pub fn bit(index: usize) -> Operand {
+        Operand::ALL[2 + 2 * index]
+    }
+
     fn evaluate(&self, bits: &[Ciphertext], server_key: &ServerKey) -> Ciphertext {
         //depreciated method, used for testing purposes
         match self {
@@ -131,123 +158,20 @@ impl Not for BooleanExpr {
     type Output = Self;
 
     fn not(self) -> Self::Output {
-        match self {
-            BooleanExpr::Operand(inner) => BooleanExpr::Operand(!inner),
-            BooleanExpr::And(inner_0, inner_1) => BooleanExpr::ordered_or(!*inner_0, !*inner_1),
-            BooleanExpr::Or(inner_0, inner_1) => BooleanExpr::ordered_and(!*inner_0, !*inner_1),
-            BooleanExpr::Xor(inner_0, inner_1) => BooleanExpr::ordered_xor(!*inner_0, *inner_1),
-            BooleanExpr::Mux(mux, inner_0, inner_1) => {
-                BooleanExpr::Mux(mux, Box::new(!*inner_0), Box::new(!*inner_1))
-            }
-        }
+        let mut arena = ExprArena::new();
+        let id = arena.intern(&self);
+        let negation = arena.not(id);
+        arena.to_expr(negation)
     }
 }
 
 impl BooleanExpr {
-    // This function reduces the number of MUX in the expression tree if for MUX(A, B, C), B == true
-    fn mux_left_true(mux: &Operand, right: &BooleanExpr) -> BooleanExpr {
-        match right {
-            BooleanExpr::Operand(Operand::True) => BooleanExpr::Operand(Operand::True),
-            BooleanExpr::Operand(Operand::False) => BooleanExpr::Operand(*mux),
-            _ => BooleanExpr::ordered_or(BooleanExpr::Operand(*mux), right.clone()),
-        }
-    }
-
-    // This function reduces the number of MUX in the expression tree if for MUX(A, B, C), B == false
-    fn mux_left_false(mux: &Operand, right: &BooleanExpr) -> BooleanExpr {
-        match right {
-            BooleanExpr::Operand(Operand::True) => BooleanExpr::Operand(!*mux),
-            BooleanExpr::Operand(Operand::False) => BooleanExpr::Operand(Operand::False),
-            _ => BooleanExpr::ordered_and(BooleanExpr::Operand(!*mux), right.clone()),
-        }
-    }
-
-    // This function reduces the number of MUX in the expression tree if for MUX(A, B, C), B == X
-    fn mux_left_x(mux: &Operand, left: &BooleanExpr, right: &BooleanExpr) -> BooleanExpr {
-        match right {
-            BooleanExpr::Operand(Operand::True) => Self::mux_left_true(&!*mux, left),
-            BooleanExpr::Operand(Operand::False) => Self::mux_left_false(&!*mux, left),
-            _ if left == right => left.clone(),
-            _ if *left == !right.clone() => {
-                BooleanExpr::ordered_xor(BooleanExpr::Operand(!*mux), left.clone())
-            }
-            _ => BooleanExpr::Mux(*mux, Box::new(left.clone()), Box::new(right.clone())),
-        }
-    }
-
-    // This function reduces the number of MUX in the expression tree
-    fn mux(mux: &Operand, left: &BooleanExpr, right: &BooleanExpr) -> BooleanExpr {
-        match left {
-            BooleanExpr::Operand(Operand::True) => Self::mux_left_true(mux, right),
-            BooleanExpr::Operand(Operand::False) => Self::mux_left_false(mux, right),
-            _ => Self::mux_left_x(mux, left, right),
-        }
-    }
-
-    fn order_expr(op_1: BooleanExpr, op_2: BooleanExpr) -> (BooleanExpr, BooleanExpr) {
-        if op_1 < op_2 {
-            (op_1, op_2)
-        } else {
-            (op_2, op_1)
-        }
-    }
-
-    fn ordered_xor(op_1: BooleanExpr, op_2: BooleanExpr) -> BooleanExpr {
-        let (left, right) = BooleanExpr::order_expr(op_1, op_2);
-        BooleanExpr::Xor(Box::new(left), Box::new(right))
-    }
-
-    fn ordered_and(op_1: BooleanExpr, op_2: BooleanExpr) -> BooleanExpr {
-        let (left, right) = BooleanExpr::order_expr(op_1, op_2);
-        BooleanExpr::And(Box::new(left), Box::new(right))
-    }
-
-    fn ordered_or(op_1: BooleanExpr, op_2: BooleanExpr) -> BooleanExpr {
-        let (left, right) = BooleanExpr::order_expr(op_1, op_2);
-        BooleanExpr::Or(Box::new(left), Box::new(right))
-    }
-
-    // This function reduces the number of MUX in the expression tree by calling the functions mux (mux_left_true, mux_left_false and mux_left_x) above
+    /// Reduces the multiplexer tree of the truth table `items`, see `ExprArena::reduce_mux`.
     pub fn reduce_mux(items: &Vec<BooleanExpr>) -> BooleanExpr {
-        assert!(
-            items.len() & (items.len() - 1) == 0,
-            "Input was not a power of 2! It was {:?}",
-            items.len()
-        );
-
-        if items.len() == 0 {
-            return BooleanExpr::Operand(Operand::True);
-        }
-
-        if items.len() == 1 {
-            return items[0].clone();
-        }
-
-        let operands = [
-            Operand::Bit0,
-            Operand::Bit1,
-            Operand::Bit2,
-            Operand::Bit3,
-            Operand::Bit4,
-            Operand::Bit5,
-            Operand::Bit6,
-            Operand::Bit7,
-        ];
-
-        let size_log_2: usize =
-            (usize::BITS as i32 - (items.len().leading_zeros() as i32)) as usize;
-
-        let result = operands[..size_log_2 - 1]
-            .iter()
-            .fold(items.clone(), |acc, &operand| {
-                acc.array_chunks::<2>()
-                    .map(|x| Self::mux(&operand, &x[0], &x[1]))
-                    .collect::<Vec<_>>()
-            });
-
-        assert!(result.len() == 1, "Something went wrong with the fold");
-
-        result[0].clone()
+        let mut arena = ExprArena::new();
+        let items: Vec<ExprId> = items.iter().map(|item| arena.intern(item)).collect();
+        let id = arena.reduce_mux(&items);
+        arena.to_expr(id)
     }
 
     pub fn evaluate(
@@ -600,17 +524,348 @@ impl Hash for BooleanExpr {
     }
 }
 
+/// ExprId is the index of an expression in an `ExprArena`.
+pub type ExprId = usize;
+
+/// A node of an `ExprArena`, whose fields are the ids of its sub-expressions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExprNode {
+    Operand(Operand),
+    And(ExprId, ExprId),
+    Or(ExprId, ExprId),
+    Xor(ExprId, ExprId),
+    Mux(Operand, ExprId, ExprId),
+}
+
+impl ExprNode {
+    fn discriminant(&self) -> u8 {
+        match self {
+            ExprNode::Operand(_) => 0,
+            ExprNode::And(_, _) => 1,
+            ExprNode::Or(_, _) => 2,
+            ExprNode::Xor(_, _) => 3,
+            ExprNode::Mux(_, _, _) => 4,
+        }
+    }
+}
+
+/// ExprArena is the hash-consed form of `BooleanExpr`: every unique sub-expression is stored once and referred to by its id.
+///
+/// A `BooleanExpr` is a tree of boxes, so the sub-expressions shared by the branches of a mux tree are copied, and hashing or comparing an
+/// expression walks the whole tree. In the arena, two expressions are equal if and only if their ids are, the sharing is built in and the
+/// stage of every node is computed once, when it is inserted. The constructors follow the rules of `BooleanExpr`: the operands of and/or/xor
+/// are ordered as by `Ord for BooleanExpr`, the negations are pushed down to the operands and `reduce_mux` folds the same muxes, so
+/// `to_expr` gives back the same trees as the boxed constructors.
+///
+/// The first 18 ids are the operands, in the order of `Operand::ALL`, as the operand slots of an `EvaluationPlan`.
+#[derive(Clone, Debug)]
+pub struct ExprArena {
+    nodes: Vec<ExprNode>,
+    stages: Vec<u8>,
+    ids: HashMap<ExprNode, ExprId>,
+    negations: HashMap<ExprId, ExprId>,
+}
+
+impl Default for ExprArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExprArena {
+    pub fn new() -> Self {
+        let mut arena = ExprArena {
+            nodes: Vec::new(),
+            stages: Vec::new(),
+            ids: HashMap::new(),
+            negations: HashMap::new(),
+        };
+        for operand in Operand::ALL {
+            arena.insert(ExprNode::Operand(operand));
+        }
+        arena
+    }
+
+    pub fn node(&self, id: ExprId) -> ExprNode {
+        self.nodes[id]
+    }
+
+    /// The height of the expression, as `BooleanExpr::stage`
+    pub fn stage(&self, id: ExprId) -> u8 {
+        self.stages[id]
+    }
+
+    pub fn operand(&self, operand: Operand) -> ExprId {
+        operand as usize
+    }
+
+    /// Returns the id of the node, inserting it if it is new.
+    fn insert(&mut self, node: ExprNode) -> ExprId {
+        if let Some(&id) = self.ids.get(&node) {
+            return id;
+        }
+
+        let stage = match node {
+            ExprNode::Operand(operand) => operand.stage(),
+            ExprNode::And(lhs, rhs)
+            | ExprNode::Or(lhs, rhs)
+            | ExprNode::Xor(lhs, rhs)
+            | ExprNode::Mux(_, lhs, rhs) => self.stages[lhs].max(self.stages[rhs]) + 1,
+        };
+
+        let id = self.nodes.len();
+        self.nodes.push(node);
+        self.stages.push(stage);
+        self.ids.insert(node, id);
+        id
+    }
+
+    /// Compares two expressions as `Ord for BooleanExpr`.
+    ///
+    /// Distinct ids are distinct expressions, so only the first differing branch is walked.
+    pub fn compare(&self, lhs: ExprId, rhs: ExprId) -> Ordering {
+        if lhs == rhs {
+            return Ordering::Equal;
+        }
+
+        let (lhs, rhs) = (self.nodes[lhs], self.nodes[rhs]);
+        lhs.discriminant()
+            .cmp(&rhs.discriminant())
+            .then_with(|| match (lhs, rhs) {
+                (ExprNode::Operand(a), ExprNode::Operand(b)) => a.cmp(&b),
+                (ExprNode::And(lhs1, rhs1), ExprNode::And(lhs2, rhs2))
+                | (ExprNode::Or(lhs1, rhs1), ExprNode::Or(lhs2, rhs2))
+                | (ExprNode::Xor(lhs1, rhs1), ExprNode::Xor(lhs2, rhs2)) => self
+                    .compare(lhs1, lhs2)
+                    .then_with(|| self.compare(rhs1, rhs2)),
+                (ExprNode::Mux(op1, lhs1, rhs1), ExprNode::Mux(op2, lhs2, rhs2)) => op1
+                    .cmp(&op2)
+                    .then_with(|| self.compare(lhs1, lhs2))
+                    .then_with(|| self.compare(rhs1, rhs2)),
+                _ => Ordering::Equal,
+            })
+    }
+
+    fn order(&self, op_1: ExprId, op_2: ExprId) -> (ExprId, ExprId) {
+        if self.compare(op_1, op_2) == Ordering::Less {
+            (op_1, op_2)
+        } else {
+            (op_2, op_1)
+        }
+    }
+
+    pub fn and(&mut self, op_1: ExprId, op_2: ExprId) -> ExprId {
+        let (lhs, rhs) = self.order(op_1, op_2);
+        self.insert(ExprNode::And(lhs, rhs))
+    }
+
+    pub fn or(&mut self, op_1: ExprId, op_2: ExprId) -> ExprId {
+        let (lhs, rhs) = self.order(op_1, op_2);
+        self.insert(ExprNode::Or(lhs, rhs))
+    }
+
+    pub fn xor(&mut self, op_1: ExprId, op_2: ExprId) -> ExprId {
+        let (lhs, rhs) = self.order(op_1, op_2);
+        self.insert(ExprNode::Xor(lhs, rhs))
+    }
+
+    /// The negation of the expression, pushed down to its operands: !(A AND B) is (!A OR !B), !(A XOR B) is (!A XOR B) and !MUX(A, B, C) is
+    /// MUX(A, !B, !C).
+    pub fn not(&mut self, id: ExprId) -> ExprId {
+        if let Some(&negation) = self.negations.get(&id) {
+            return negation;
+        }
+
+        let negation = match self.nodes[id] {
+            ExprNode::Operand(operand) => self.operand(!operand),
+            ExprNode::And(lhs, rhs) => {
+                let (lhs, rhs) = (self.not(lhs), self.not(rhs));
+                self.or(lhs, rhs)
+            }
+            ExprNode::Or(lhs, rhs) => {
+                let (lhs, rhs) = (self.not(lhs), self.not(rhs));
+                self.and(lhs, rhs)
+            }
+            ExprNode::Xor(lhs, rhs) => {
+                let lhs = self.not(lhs);
+                self.xor(lhs, rhs)
+            }
+            ExprNode::Mux(mux, lhs, rhs) => {
+                let (lhs, rhs) = (self.not(lhs), self.not(rhs));
+                self.insert(ExprNode::Mux(mux, lhs, rhs))
+            }
+        };
+
+        self.negations.insert(id, negation);
+        negation
+    }
+
+    // This function reduces the number of MUX in the expression tree if for MUX(A, B, C), B == true
+    fn mux_left_true(&mut self, mux: Operand, right: ExprId) -> ExprId {
+        let node = self.nodes[right];
+        match node {
+            ExprNode::Operand(Operand::True) => self.operand(Operand::True),
+            ExprNode::Operand(Operand::False) => self.operand(mux),
+            _ => {
+                let mux = self.operand(mux);
+                self.or(mux, right)
+            }
+        }
+    }
+
+    // This function reduces the number of MUX in the expression tree if for MUX(A, B, C), B == false
+    fn mux_left_false(&mut self, mux: Operand, right: ExprId) -> ExprId {
+        let node = self.nodes[right];
+        match node {
+            ExprNode::Operand(Operand::True) => self.operand(!mux),
+            ExprNode::Operand(Operand::False) => self.operand(Operand::False),
+            _ => {
+                let mux = self.operand(!mux);
+                self.and(mux, right)
+            }
+        }
+    }
+
+    // This function reduces the number of MUX in the expression tree if for MUX(A, B, C), B == X
+    fn mux_left_x(&mut self, mux: Operand, left: ExprId, right: ExprId) -> ExprId {
+        let node = self.nodes[right];
+        match node {
+            ExprNode::Operand(Operand::True) => self.mux_left_true(!mux, left),
+            ExprNode::Operand(Operand::False) => self.mux_left_false(!mux, left),
+            _ if left == right => left,
+            _ if left == self.not(right) => {
+                let mux = self.operand(!mux);
+                self.xor(mux, left)
+            }
+            _ => self.insert(ExprNode::Mux(mux, left, right)),
+        }
+    }
+
+    /// MUX(mux, left, right), reduced to a single and/or/xor or to one of its operands when possible
+    pub fn mux(&mut self, mux: Operand, left: ExprId, right: ExprId) -> ExprId {
+        let node = self.nodes[left];
+        match node {
+            ExprNode::Operand(Operand::True) => self.mux_left_true(mux, right),
+            ExprNode::Operand(Operand::False) => self.mux_left_false(mux, right),
+            _ => self.mux_left_x(mux, left, right),
+        }
+    }
+
+    /// Reduces the multiplexer tree of the truth table `items`, whose length is a power of 2, the selector of the first level being Bit0.
+    pub fn reduce_mux(&mut self, items: &[ExprId]) -> ExprId {
+        let size_log_2 = items.len().trailing_zeros() as usize;
+        let order: Vec<usize> = (0..size_log_2).collect();
+        self.reduce_mux_ordered(items, &order)
+    }
+
+    /// Reduces the multiplexer tree of the truth table `items` with the selector bits in the given order.
+    ///
+    /// `order` is a permutation of the bits of the indices of `items`: the selector of the level `i` of the tree, the first level being the
+    /// closest to the leaves, is `Bit{order[i]}`. Every order gives the same function, but not the same number of unique nodes, as in a
+    /// BDD whose variable order is `order` reversed.
+    pub fn reduce_mux_ordered(&mut self, items: &[ExprId], order: &[usize]) -> ExprId {
+        assert!(
+            items.len() & (items.len() - 1) == 0,
+            "Input was not a power of 2! It was {:?}",
+            items.len()
+        );
+
+        if items.is_empty() {
+            return self.operand(Operand::True);
+        }
+
+        let size_log_2 = items.len().trailing_zeros() as usize;
+        assert!(
+            order.len() == size_log_2 && (0..size_log_2).all(|bit| order.contains(&bit)),
+            "{:?} is not an order of the {} selector bits",
+            order,
+            size_log_2
+        );
+
+        // The bit `i` of the permuted index is the bit `order[i]` of the index in `items`
+        let permuted: Vec<ExprId> = (0..items.len())
+            .map(|index| {
+                let original = order.iter().enumerate().fold(0, |acc, (level, &bit)| {
+                    acc | (((index >> level) & 1) << bit)
+                });
+                items[original]
+            })
+            .collect();
+
+        let result = order.iter().fold(permuted, |acc, &bit| {
+            acc.array_chunks::<2>()
+                .map(|x| self.mux(Operand::bit(bit), x[0], x[1]))
+                .collect::<Vec<_>>()
+        });
+
+        assert!(result.len() == 1, "Something went wrong with the fold");
+
+        result[0]
+    }
+
+    /// Inserts the expression as it is, sharing its common sub-expressions.
+    pub fn intern(&mut self, expr: &BooleanExpr) -> ExprId {
+        let node = match expr {
+            BooleanExpr::Operand(operand) => ExprNode::Operand(*operand),
+            BooleanExpr::And(lhs, rhs) => ExprNode::And(self.intern(lhs), self.intern(rhs)),
+            BooleanExpr::Or(lhs, rhs) => ExprNode::Or(self.intern(lhs), self.intern(rhs)),
+            BooleanExpr::Xor(lhs, rhs) => ExprNode::Xor(self.intern(lhs), self.intern(rhs)),
+            BooleanExpr::Mux(mux, lhs, rhs) => {
+                ExprNode::Mux(*mux, self.intern(lhs), self.intern(rhs))
+            }
+        };
+        self.insert(node)
+    }
+
+    /// The boxed tree of the expression
+    pub fn to_expr(&self, id: ExprId) -> BooleanExpr {
+        let boxed = |id| Box::new(self.to_expr(id));
+        match self.nodes[id] {
+            ExprNode::Operand(operand) => BooleanExpr::Operand(operand),
+            ExprNode::And(lhs, rhs) => BooleanExpr::And(boxed(lhs), boxed(rhs)),
+            ExprNode::Or(lhs, rhs) => BooleanExpr::Or(boxed(lhs), boxed(rhs)),
+            ExprNode::Xor(lhs, rhs) => BooleanExpr::Xor(boxed(lhs), boxed(rhs)),
+            ExprNode::Mux(mux, lhs, rhs) => BooleanExpr::Mux(mux, boxed(lhs), boxed(rhs)),
+        }
+    }
+
+    /// The ids of the expressions reachable from `outputs`, the outputs included, in increasing order.
+    pub fn reachable(&self, outputs: &[ExprId]) -> Vec<ExprId> {
+        let mut reached = vec![false; self.nodes.len()];
+        for &output in outputs.iter() {
+            reached[output] = true;
+        }
+        // The sub-expressions of a node are inserted before it
+        for id in (0..self.nodes.len()).rev() {
+            if !reached[id] {
+                continue;
+            }
+            match self.nodes[id] {
+                ExprNode::Operand(_) => {}
+                ExprNode::And(lhs, rhs)
+                | ExprNode::Or(lhs, rhs)
+                | ExprNode::Xor(lhs, rhs)
+                | ExprNode::Mux(_, lhs, rhs) => {
+                    reached[lhs] = true;
+                    reached[rhs] = true;
+                }
+            }
+        }
+        (0..self.nodes.len()).filter(|&id| reached[id]).collect()
+    }
+}
+
 /// This struct allows us to evaluate a boolean expression in a staged manner by encapsulating 2-3 Cipertexts in a Vec and then
-/// allowing rayon to evaluate the expression in parallel.
-pub struct Runnable {
+/// allowing rayon to evaluate the expression in parallel. The Cipertexts can be the bits of any `Backend`.
+pub struct Runnable<T = Ciphertext> {
     bool_expr: BooleanExpr,
-    operands: Vec<Ciphertext>,
+    operands: Vec<T>,
 }
 
-impl Runnable {
+impl<T: Clone> Runnable<T> {
     pub fn new(
-        operands_: &HashMap<Operand, Ciphertext>,
-        hashmap: &HashMap<BooleanExpr, Ciphertext>,
+        operands_: &HashMap<Operand, T>,
+        hashmap: &HashMap<BooleanExpr, T>,
         bool_expr: BooleanExpr,
     ) -> Self {
         let mut operands: Vec<_> = Vec::with_capacity(3);
@@ -638,10 +893,10 @@ impl Runnable {
 
         Self {
             bool_expr,
-            operands: operands.try_into().unwrap(),
+            operands,
         }
     }
-    pub fn run(&self, server_key: &ServerKey) -> Ciphertext {
+    pub fn run<B: crate::backend::Backend<Bit = T>>(&self, server_key: &B) -> T {
         match self.bool_expr {
             BooleanExpr::Operand(_) => self.operands[0].clone(),
             BooleanExpr::And(_, _) => server_key.and(&self.operands[0], &self.operands[1]),
@@ -779,6 +1034,45 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_expr_arena() {
+        let mut arena = ExprArena::new();
+        let bit0 = arena.operand(Operand::Bit0);
+        let bit1 = arena.operand(Operand::Bit1);
+        assert_eq!(arena.and(bit0, bit1), arena.and(bit1, bit0));
+        let or = arena.or(bit1, bit0);
+        assert_eq!(arena.node(or), ExprNode::Or(bit0, bit1));
+
+        for truth_table in generate_bits(4) {
+            let items: Vec<_> = truth_table
+                .iter()
+                .map(|&x| arena.operand(x.into()))
+                .collect();
+            let id = arena.reduce_mux(&items);
+            let expr = arena.to_expr(id);
+
+            // Every expression is stored once, with its stage
+            assert_eq!(arena.intern(&expr), id);
+            assert_eq!(arena.reduce_mux(&items), id);
+            assert_eq!(arena.stage(id), expr.stage());
+
+            let negation = arena.not(id);
+            assert_eq!(arena.not(negation), id);
+            assert_eq!(arena.to_expr(negation), !expr);
+        }
+
+        // The shared branches of a mux tree are a single node
+        let truth_table = u256_to_bool(23456789, 234567, 256);
+        let items: Vec<_> = truth_table
+            .iter()
+            .map(|&x| arena.operand(x.into()))
+            .collect();
+        let id = arena.reduce_mux(&items);
+        let mut hashset = HashSet::new();
+        arena.to_expr(id).to_hashset(&mut hashset);
+        assert_eq!(arena.reachable(&[id]).len(), hashset.len());
+    }
+
     #[test]
     fn test_evaluate_true() {
         let (client_key, server_key) = gen_keys();
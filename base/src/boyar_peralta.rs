@@ -0,0 +1,448 @@
+use std::collections::BTreeSet;
+
+use crate::backend::{Backend, ClearBackend};
+
+/// The low gate count S-Box circuits of Boyar and Peralta, an alternative to the mux trees of `generate_reduced_bool_expr`.
+///
+/// The forward S-Box is the depth 16 circuit of "A depth-16 circuit for the AES S-box" (Boyar, Peralta, 2011): a top linear layer of 27 xor,
+/// a non linear middle of 30 xor and 34 and computing the inversion in GF(2^8) through GF(2^4), and a bottom linear layer of 30 xor and 8
+/// xor/xnor. As not gates are free, this is 128 bootstraps per byte.
+///
+/// The inverse S-Box reuses the same middle, since `InvSubByte(y) = Inv(A^-1(y ^ 0x63))` and `A(Inv(z)) ^ 0x63` is what the forward circuit
+/// computes: its top and bottom layers are the forward ones composed with the inverse affine map of AES, synthesised with Paar's greedy
+/// algorithm.
+///
+/// The circuits take the 8 bits of the byte most significant first (`U0` to `U7`) and return the bits of the result most significant first.
+pub fn s_box<B: Backend>(u: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+    let t = top_linear(u, server_key);
+    let m = non_linear(&t, server_key);
+    bottom_linear(&m, server_key)
+}
+
+pub fn inv_s_box<B: Backend>(u: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+    let (top, bottom) = inverse_layers();
+    let t = top.apply(u, server_key);
+    let m = non_linear(&t, server_key);
+    bottom.apply(&m, server_key)
+}
+
+/// The number of bits going from the top linear layer to the non linear middle
+const TOP_OUTPUTS: usize = 22;
+
+/// The number of bits going from the non linear middle to the bottom linear layer
+const MIDDLE_OUTPUTS: usize = 18;
+
+/// Returns T1, T2, T3, T4, T6, T8, T9, T10, T13, T14, T15, T16, T17, T19, T20, T22, T23, T24, T25, T26, T27 and U7, the bits read by the middle.
+fn top_linear<B: Backend>(u: &[B::Bit], sk: &B) -> Vec<B::Bit> {
+    assert!(u.len() == 8, "The S-Box is evaluated on 8 bits !");
+
+    let t1 = sk.xor(&u[0], &u[3]);
+    let t2 = sk.xor(&u[0], &u[5]);
+    let t3 = sk.xor(&u[0], &u[6]);
+    let t4 = sk.xor(&u[3], &u[5]);
+    let t5 = sk.xor(&u[4], &u[6]);
+    let t6 = sk.xor(&t1, &t5);
+    let t7 = sk.xor(&u[1], &u[2]);
+    let t8 = sk.xor(&u[7], &t6);
+    let t9 = sk.xor(&u[7], &t7);
+    let t10 = sk.xor(&t6, &t7);
+    let t11 = sk.xor(&u[1], &u[5]);
+    let t12 = sk.xor(&u[2], &u[5]);
+    let t13 = sk.xor(&t3, &t4);
+    let t14 = sk.xor(&t6, &t11);
+    let t15 = sk.xor(&t5, &t11);
+    let t16 = sk.xor(&t5, &t12);
+    let t17 = sk.xor(&t9, &t16);
+    let t18 = sk.xor(&u[3], &u[7]);
+    let t19 = sk.xor(&t7, &t18);
+    let t20 = sk.xor(&t1, &t19);
+    let t21 = sk.xor(&u[6], &u[7]);
+    let t22 = sk.xor(&t7, &t21);
+    let t23 = sk.xor(&t2, &t22);
+    let t24 = sk.xor(&t2, &t10);
+    let t25 = sk.xor(&t20, &t17);
+    let t26 = sk.xor(&t3, &t16);
+    let t27 = sk.xor(&t1, &t12);
+
+    vec![
+        t1,
+        t2,
+        t3,
+        t4,
+        t6,
+        t8,
+        t9,
+        t10,
+        t13,
+        t14,
+        t15,
+        t16,
+        t17,
+        t19,
+        t20,
+        t22,
+        t23,
+        t24,
+        t25,
+        t26,
+        t27,
+        u[7].clone(),
+    ]
+}
+
+/// Returns M46 to M63, the products read by the bottom layer.
+fn non_linear<B: Backend>(t: &[B::Bit], sk: &B) -> Vec<B::Bit> {
+    let [
+        t1,
+        t2,
+        t3,
+        t4,
+        t6,
+        t8,
+        t9,
+        t10,
+        t13,
+        t14,
+        t15,
+        t16,
+        t17,
+        t19,
+        t20,
+        t22,
+        t23,
+        t24,
+        t25,
+        t26,
+        t27,
+        d,
+    ] = t
+    else {
+        panic!("The middle of the S-Box reads {} bits !", TOP_OUTPUTS);
+    };
+
+    let m1 = sk.and(t13, t6);
+    let m2 = sk.and(t23, t8);
+    let m3 = sk.xor(t14, &m1);
+    let m4 = sk.and(t19, d);
+    let m5 = sk.xor(&m4, &m1);
+    let m6 = sk.and(t3, t16);
+    let m7 = sk.and(t22, t9);
+    let m8 = sk.xor(t26, &m6);
+    let m9 = sk.and(t20, t17);
+    let m10 = sk.xor(&m9, &m6);
+    let m11 = sk.and(t1, t15);
+    let m12 = sk.and(t4, t27);
+    let m13 = sk.xor(&m12, &m11);
+    let m14 = sk.and(t2, t10);
+    let m15 = sk.xor(&m14, &m11);
+    let m16 = sk.xor(&m3, &m2);
+    let m17 = sk.xor(&m5, t24);
+    let m18 = sk.xor(&m8, &m7);
+    let m19 = sk.xor(&m10, &m15);
+    let m20 = sk.xor(&m16, &m13);
+    let m21 = sk.xor(&m17, &m15);
+    let m22 = sk.xor(&m18, &m13);
+    let m23 = sk.xor(&m19, t25);
+    let m24 = sk.xor(&m22, &m23);
+    let m25 = sk.and(&m22, &m20);
+    let m26 = sk.xor(&m21, &m25);
+    let m27 = sk.xor(&m20, &m21);
+    let m28 = sk.xor(&m23, &m25);
+    let m29 = sk.and(&m28, &m27);
+    let m30 = sk.and(&m26, &m24);
+    let m31 = sk.and(&m20, &m23);
+    let m32 = sk.and(&m27, &m31);
+    let m33 = sk.xor(&m27, &m25);
+    let m34 = sk.and(&m21, &m22);
+    let m35 = sk.and(&m24, &m34);
+    let m36 = sk.xor(&m24, &m25);
+    let m37 = sk.xor(&m21, &m29);
+    let m38 = sk.xor(&m32, &m33);
+    let m39 = sk.xor(&m23, &m30);
+    let m40 = sk.xor(&m35, &m36);
+    let m41 = sk.xor(&m38, &m40);
+    let m42 = sk.xor(&m37, &m39);
+    let m43 = sk.xor(&m37, &m38);
+    let m44 = sk.xor(&m39, &m40);
+    let m45 = sk.xor(&m42, &m41);
+
+    vec![
+        sk.and(&m44, t6),
+        sk.and(&m40, t8),
+        sk.and(&m39, d),
+        sk.and(&m43, t16),
+        sk.and(&m38, t9),
+        sk.and(&m37, t17),
+        sk.and(&m42, t15),
+        sk.and(&m45, t27),
+        sk.and(&m41, t10),
+        sk.and(&m44, t13),
+        sk.and(&m40, t23),
+        sk.and(&m39, t19),
+        sk.and(&m43, t3),
+        sk.and(&m38, t22),
+        sk.and(&m37, t20),
+        sk.and(&m42, t1),
+        sk.and(&m45, t4),
+        sk.and(&m41, t2),
+    ]
+}
+
+/// Returns the bits of the S-Box, most significant first.
+fn bottom_linear<B: Backend>(m: &[B::Bit], sk: &B) -> Vec<B::Bit> {
+    let [
+        m46,
+        m47,
+        m48,
+        m49,
+        m50,
+        m51,
+        m52,
+        m53,
+        m54,
+        m55,
+        m56,
+        m57,
+        m58,
+        m59,
+        m60,
+        m61,
+        m62,
+        m63,
+    ] = m
+    else {
+        panic!("The bottom of the S-Box reads {} bits !", MIDDLE_OUTPUTS);
+    };
+    let xnor = |lhs: &B::Bit, rhs: &B::Bit| sk.not(&sk.xor(lhs, rhs));
+
+    let l0 = sk.xor(m61, m62);
+    let l1 = sk.xor(m50, m56);
+    let l2 = sk.xor(m46, m48);
+    let l3 = sk.xor(m47, m55);
+    let l4 = sk.xor(m54, m58);
+    let l5 = sk.xor(m49, m61);
+    let l6 = sk.xor(m62, &l5);
+    let l7 = sk.xor(m46, &l3);
+    let l8 = sk.xor(m51, m59);
+    let l9 = sk.xor(m52, m53);
+    let l10 = sk.xor(m53, &l4);
+    let l11 = sk.xor(m60, &l2);
+    let l12 = sk.xor(m48, m51);
+    let l13 = sk.xor(m50, &l0);
+    let l14 = sk.xor(m52, m61);
+    let l15 = sk.xor(m55, &l1);
+    let l16 = sk.xor(m56, &l0);
+    let l17 = sk.xor(m57, &l1);
+    let l18 = sk.xor(m58, &l8);
+    let l19 = sk.xor(m63, &l4);
+    let l20 = sk.xor(&l0, &l1);
+    let l21 = sk.xor(&l1, &l7);
+    let l22 = sk.xor(&l3, &l12);
+    let l23 = sk.xor(&l18, &l2);
+    let l24 = sk.xor(&l15, &l9);
+    let l25 = sk.xor(&l6, &l10);
+    let l26 = sk.xor(&l7, &l9);
+    let l27 = sk.xor(&l8, &l10);
+    let l28 = sk.xor(&l11, &l14);
+    let l29 = sk.xor(&l11, &l17);
+
+    vec![
+        sk.xor(&l6, &l24),
+        xnor(&l16, &l26),
+        xnor(&l19, &l28),
+        sk.xor(&l6, &l21),
+        sk.xor(&l20, &l22),
+        sk.xor(&l25, &l29),
+        xnor(&l13, &l27),
+        xnor(&l6, &l23),
+    ]
+}
+
+/// The linear map of the inverse of the affine transformation of the S-Box, without its constant 0x05
+fn inv_affine_linear(x: u8) -> u8 {
+    x.rotate_left(1) ^ x.rotate_left(3) ^ x.rotate_left(6)
+}
+
+fn to_bits(x: u8) -> Vec<bool> {
+    (0..8).rev().map(|i| x & (1 << i) != 0).collect()
+}
+
+fn from_bits(bits: &[bool]) -> u8 {
+    bits.iter().fold(0, |acc, &bit| (acc << 1) | bit as u8)
+}
+
+/// LinearLayer is an affine map over GF(2) compiled into a list of xor, each output being the xor of a set of inputs and a constant.
+#[derive(Clone, Debug)]
+struct LinearLayer {
+    num_inputs: usize,
+    /// The xor of two signals, the inputs being the first signals
+    xors: Vec<(usize, usize)>,
+    /// The signal and the constant of every output, `None` being the zero signal
+    outputs: Vec<(Option<usize>, bool)>,
+}
+
+impl LinearLayer {
+    /// Synthesises the layer with Paar's greedy algorithm: the pair of signals shared by the most outputs is xored first, until every output
+    /// is a single signal.
+    fn synthesize(num_inputs: usize, targets: &[(BTreeSet<usize>, bool)]) -> Self {
+        let mut sets: Vec<BTreeSet<usize>> = targets.iter().map(|(set, _)| set.clone()).collect();
+        let mut xors = Vec::new();
+        let mut num_signals = num_inputs;
+
+        loop {
+            let mut best: Option<((usize, usize), usize)> = None;
+            for lhs in 0..num_signals {
+                for rhs in lhs + 1..num_signals {
+                    let count = sets
+                        .iter()
+                        .filter(|set| set.contains(&lhs) && set.contains(&rhs))
+                        .count();
+                    if count > best.map_or(0, |(_, count)| count) {
+                        best = Some(((lhs, rhs), count));
+                    }
+                }
+            }
+
+            let Some(((lhs, rhs), _)) = best else {
+                break;
+            };
+            xors.push((lhs, rhs));
+            for set in sets.iter_mut() {
+                if set.contains(&lhs) && set.contains(&rhs) {
+                    set.remove(&lhs);
+                    set.remove(&rhs);
+                    set.insert(num_signals);
+                }
+            }
+            num_signals += 1;
+        }
+
+        let outputs = sets
+            .iter()
+            .zip(targets.iter())
+            .map(|(set, (_, constant))| (set.first().copied(), *constant))
+            .collect();
+
+        LinearLayer {
+            num_inputs,
+            xors,
+            outputs,
+        }
+    }
+
+    fn apply<B: Backend>(&self, inputs: &[B::Bit], sk: &B) -> Vec<B::Bit> {
+        assert!(inputs.len() == self.num_inputs);
+
+        let mut signals = inputs.to_vec();
+        for &(lhs, rhs) in self.xors.iter() {
+            signals.push(sk.xor(&signals[lhs], &signals[rhs]));
+        }
+
+        self.outputs
+            .iter()
+            .map(|&(signal, constant)| match (signal, constant) {
+                (None, constant) => sk.trivial(constant),
+                (Some(signal), false) => signals[signal].clone(),
+                (Some(signal), true) => sk.not(&signals[signal]),
+            })
+            .collect()
+    }
+
+    /// Synthesises the layer computing the same map as `map`, which must be affine.
+    fn from_affine(num_inputs: usize, map: impl Fn(&[bool]) -> Vec<bool>) -> Self {
+        let constants = map(&vec![false; num_inputs]);
+        let columns: Vec<Vec<bool>> = (0..num_inputs)
+            .map(|i| map(&(0..num_inputs).map(|j| i == j).collect::<Vec<_>>()))
+            .collect();
+
+        let targets: Vec<(BTreeSet<usize>, bool)> = constants
+            .iter()
+            .enumerate()
+            .map(|(output, &constant)| {
+                let set = (0..num_inputs)
+                    .filter(|&input| columns[input][output] != constant)
+                    .collect();
+                (set, constant)
+            })
+            .collect();
+
+        Self::synthesize(num_inputs, &targets)
+    }
+}
+
+/// The top and bottom layers of the inverse S-Box, around the middle of the forward S-Box.
+fn inverse_layers() -> (LinearLayer, LinearLayer) {
+    // z = A^-1(y ^ 0x63) is the input of the inversion
+    let top = LinearLayer::from_affine(8, |y| {
+        let z = inv_affine_linear(from_bits(y) ^ 0x63);
+        top_linear(&to_bits(z), &ClearBackend)
+    });
+
+    // The forward bottom layer gives A(Inv(z)) ^ 0x63, from which Inv(z) = A^-1(bottom ^ 0x63)
+    let bottom = LinearLayer::from_affine(MIDDLE_OUTPUTS, |m| {
+        let s = from_bits(&bottom_linear(m, &ClearBackend));
+        to_bits(inv_affine_linear(s ^ 0x63))
+    });
+
+    (top, bottom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counting::CountingBackend;
+    use crate::sbox::{INV_S_BOX_DATA, S_BOX_DATA, SBoxCircuit, SBoxPlans};
+
+    #[test]
+    fn test_boyar_peralta_clear() {
+        for x in 0..=255_u8 {
+            assert_eq!(
+                from_bits(&s_box(&to_bits(x), &ClearBackend)),
+                S_BOX_DATA[x as usize]
+            );
+            assert_eq!(
+                from_bits(&inv_s_box(&to_bits(x), &ClearBackend)),
+                INV_S_BOX_DATA[x as usize]
+            );
+        }
+    }
+
+    #[test]
+    fn test_boyar_peralta_gates() {
+        let backend = CountingBackend::default();
+        let bits: Vec<_> = to_bits(0x53).iter().map(|&x| backend.input(x)).collect();
+
+        let (_, counts) = backend.measure(|| s_box(&bits, &backend));
+        assert_eq!((counts.and, counts.xor, counts.not), (34, 94, 4));
+        assert_eq!(counts.bootstraps, 128);
+        assert_eq!(counts.depth, 16);
+
+        let backend = CountingBackend::default();
+        let bits: Vec<_> = to_bits(0x53).iter().map(|&x| backend.input(x)).collect();
+
+        // Same middle, with the layers composed with the inverse affine map
+        let (_, counts) = backend.measure(|| inv_s_box(&bits, &backend));
+        assert_eq!(counts.and, 34);
+        assert_eq!(counts.bootstraps, counts.and + counts.xor);
+    }
+
+    #[test]
+    fn test_boyar_peralta_plans_clear() {
+        let plans = SBoxPlans::compile(SBoxCircuit::BoyarPeralta);
+        assert_eq!(plans.s_box.gates().len(), 128 + 4);
+        assert_eq!(plans.s_box.num_stages(), 16);
+
+        let inputs: Vec<Vec<bool>> = (0..=255_u8)
+            .map(|x| to_bits(x).into_iter().rev().collect())
+            .collect();
+        for (plan, data) in [
+            (&plans.s_box, S_BOX_DATA),
+            (&plans.inv_s_box, INV_S_BOX_DATA),
+        ] {
+            let outputs = plan.evaluate_batch(&inputs, &ClearBackend);
+            for (x, output) in outputs.iter().enumerate() {
+                assert_eq!(from_bits(output), data[x]);
+            }
+        }
+    }
+}
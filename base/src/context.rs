@@ -0,0 +1,264 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::fmt;
+use std::sync::Arc;
+use tfhe::boolean::prelude::*;
+
+use crate::backend::Backend;
+use crate::sbox::{SBoxCircuit, SBoxPlans};
+
+/// FheAesContext is the evaluation context of AES for one server key.
+///
+/// It owns the server key, the thread pool on which the homomorphic operations run and the compiled S-Box plans, and is passed as the
+/// backend to `FHEByte`, `State`, `Key` and the modes in place of the bare `ServerKey`. As nothing is stored globally, several contexts with
+/// different server keys (e.g. one per client) can be used concurrently in the same process.
+///
+/// The operations of `FHEByte`, `State`, `Key` and the modes run their parallel loops through `Backend::install`, so they use the thread
+/// pool of the context whichever pool they are called from.
+pub struct FheAesContext {
+    server_key: ServerKey,
+    thread_pool: ThreadPool,
+    s_box_plans: Arc<SBoxPlans>,
+}
+
+impl FheAesContext {
+    /// Creates a context whose thread pool has one thread per logical core.
+    pub fn new(server_key: ServerKey) -> Self {
+        Self::with_num_threads(server_key, num_cpus::get())
+    }
+
+    pub fn with_num_threads(server_key: ServerKey, num_threads: usize) -> Self {
+        Self::with_s_box_plans(server_key, num_threads, SBoxPlans::shared().clone())
+    }
+
+    /// Creates a context evaluating the S-Box with the given circuit, with one thread per logical core.
+    pub fn with_s_box_circuit(server_key: ServerKey, circuit: SBoxCircuit) -> Self {
+        Self::with_s_box_plans(
+            server_key,
+            num_cpus::get(),
+            SBoxPlans::shared_for(circuit).clone(),
+        )
+    }
+
+    /// Creates a context evaluating the given S-Box plans, which are otherwise compiled once and shared by all the contexts of the process.
+    pub fn with_s_box_plans(
+        server_key: ServerKey,
+        num_threads: usize,
+        s_box_plans: Arc<SBoxPlans>,
+    ) -> Self {
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .expect("Failed to build the thread pool of the context");
+
+        FheAesContext {
+            server_key,
+            thread_pool,
+            s_box_plans,
+        }
+    }
+
+    pub fn server_key(&self) -> &ServerKey {
+        &self.server_key
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.thread_pool.current_num_threads()
+    }
+}
+
+impl fmt::Debug for FheAesContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FheAesContext")
+            .field("num_threads", &self.num_threads())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Backend for FheAesContext {
+    type Bit = Ciphertext;
+
+    fn trivial(&self, value: bool) -> Ciphertext {
+        Backend::trivial(&self.server_key, value)
+    }
+
+    fn not(&self, bit: &Ciphertext) -> Ciphertext {
+        Backend::not(&self.server_key, bit)
+    }
+
+    fn and(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        Backend::and(&self.server_key, lhs, rhs)
+    }
+
+    fn or(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        Backend::or(&self.server_key, lhs, rhs)
+    }
+
+    fn xor(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        Backend::xor(&self.server_key, lhs, rhs)
+    }
+
+    fn mux(&self, condition: &Ciphertext, then: &Ciphertext, otherwise: &Ciphertext) -> Ciphertext {
+        Backend::mux(&self.server_key, condition, then, otherwise)
+    }
+
+    fn not_assign(&self, bit: &mut Ciphertext) {
+        Backend::not_assign(&self.server_key, bit)
+    }
+
+    fn constant(&self, bit: &Ciphertext) -> Option<bool> {
+        Backend::constant(&self.server_key, bit)
+    }
+
+    fn and_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        Backend::and_assign(&self.server_key, lhs, rhs)
+    }
+
+    fn or_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        Backend::or_assign(&self.server_key, lhs, rhs)
+    }
+
+    fn xor_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        Backend::xor_assign(&self.server_key, lhs, rhs)
+    }
+
+    fn s_box_plans(&self) -> &Arc<SBoxPlans> {
+        &self.s_box_plans
+    }
+
+    fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        self.thread_pool.install(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FHEByte, Key, State};
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use tfhe::boolean::gen_keys;
+
+    /// A backend forwarding to a context, which records the number of threads of the pool every gate runs on
+    struct PoolProbe<'a> {
+        context: &'a FheAesContext,
+        num_threads: Mutex<HashSet<usize>>,
+    }
+
+    impl PoolProbe<'_> {
+        fn record(&self) {
+            self.num_threads
+                .lock()
+                .unwrap()
+                .insert(rayon::current_num_threads());
+        }
+    }
+
+    impl Backend for PoolProbe<'_> {
+        type Bit = Ciphertext;
+
+        fn trivial(&self, value: bool) -> Ciphertext {
+            self.context.trivial(value)
+        }
+
+        fn not(&self, bit: &Ciphertext) -> Ciphertext {
+            self.record();
+            self.context.not(bit)
+        }
+
+        fn and(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+            self.record();
+            self.context.and(lhs, rhs)
+        }
+
+        fn or(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+            self.record();
+            self.context.or(lhs, rhs)
+        }
+
+        fn xor(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+            self.record();
+            self.context.xor(lhs, rhs)
+        }
+
+        fn mux(
+            &self,
+            condition: &Ciphertext,
+            then: &Ciphertext,
+            otherwise: &Ciphertext,
+        ) -> Ciphertext {
+            self.record();
+            self.context.mux(condition, then, otherwise)
+        }
+
+        fn constant(&self, bit: &Ciphertext) -> Option<bool> {
+            self.context.constant(bit)
+        }
+
+        fn s_box_plans(&self) -> &Arc<SBoxPlans> {
+            self.context.s_box_plans()
+        }
+
+        fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+            self.context.install(op)
+        }
+    }
+
+    #[test]
+    fn test_concurrent_contexts() {
+        // Two clients with their own keys are served at the same time by the same process
+        let clients: Vec<_> = (0..2)
+            .map(|i| {
+                let (client_key, server_key) = gen_keys();
+                let context = FheAesContext::with_num_threads(server_key, 2);
+                let data: [u8; 16] = std::array::from_fn(|j| (16 * i + j) as u8);
+                (client_key, context, data)
+            })
+            .collect();
+
+        std::thread::scope(|scope| {
+            for (client_key, context, data) in clients.iter() {
+                scope.spawn(move || {
+                    assert_eq!(context.num_threads(), 2);
+
+                    let mut state = State::from_u8_enc(data, client_key);
+                    state.sub_bytes(context);
+
+                    let expected = data.map(|x| crate::sbox::S_BOX_DATA[x as usize]);
+                    assert_eq!(state.decrypt_to_u8(client_key), expected);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_operations_run_on_the_context_pool() {
+        let (client_key, server_key) = gen_keys();
+        let context = FheAesContext::with_num_threads(server_key, 1);
+        let probe = PoolProbe {
+            context: &context,
+            num_threads: Mutex::new(HashSet::new()),
+        };
+
+        // The caller runs on a pool of 3 threads, which the operations do not use
+        let caller_pool = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        caller_pool.install(|| {
+            let key = Key::from_u8_enc(&[0x2b; 16], &client_key);
+            let round_keys = key.generate_round_keys(&probe);
+
+            let mut state = State::from_u8_enc(&[0x32; 16], &client_key);
+            state.xor_key_enc(&round_keys[0], &probe);
+            state.xor_key_clear(&[0x01; 16], &probe);
+            state.xor_state(&State::from_u8_enc(&[0x45; 16], &client_key), &probe);
+            state.sub_bytes(&probe);
+            state.mix_columns(&probe);
+
+            let mut byte = FHEByte::from_u8_enc(&0x80, &client_key);
+            byte.xor_in_place(&FHEByte::from_u8_enc(&0x0f, &client_key), &probe);
+            byte.and_in_place(&FHEByte::from_u8_enc(&0xf3, &client_key), &probe);
+            byte.mul_x_gf2_in_place(&probe);
+            FHEByte::equals(&[byte.clone()], &[byte], &probe);
+        });
+
+        assert_eq!(*probe.num_threads.lock().unwrap(), HashSet::from([1]));
+    }
+}
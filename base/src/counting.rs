@@ -0,0 +1,577 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::backend::{Backend, ClearBackend, ClientBackend};
+use crate::folding::{fold_and, fold_mux, fold_or, fold_xor};
+use crate::key_schedule::KeySize;
+use crate::mux_order::{self, IDENTITY_ORDER, INV_S_BOX_ORDERS, S_BOX_ORDERS};
+use crate::sbox::{INV_S_BOX_DATA, S_BOX_DATA, SBoxCircuit, SBoxPlans};
+use crate::{FHEByte, Key, State};
+
+/// Counted is the bit of the `CountingBackend`, a bit of the inner backend annotated with its cost.
+///
+/// `depth` is the number of bootstraps on the longest path from the inputs to this bit, and `constant` holds the value of trivially encrypted bits,
+/// which tfhe evaluates without bootstrapping.
+#[derive(Clone, Debug)]
+pub struct Counted<T> {
+    value: T,
+    depth: u32,
+    constant: Option<bool>,
+}
+
+impl<T> Counted<T> {
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn is_trivial(&self) -> bool {
+        self.constant.is_some()
+    }
+}
+
+/// GateCounts is a snapshot of the gates evaluated by a `CountingBackend`.
+///
+/// `depth` is the critical path in bootstraps: for a snapshot it is the deepest bit produced so far, and for the difference of two snapshots
+/// (see `since`) it is how much the critical path grew in between.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GateCounts {
+    pub not: u64,
+    pub and: u64,
+    pub or: u64,
+    pub xor: u64,
+    pub mux: u64,
+    pub trivial: u64,
+    pub bootstraps: u64,
+    pub depth: u32,
+}
+
+impl GateCounts {
+    /// The number of and/or/xor/mux/not gates, trivial encryptions excluded
+    pub fn gates(&self) -> u64 {
+        self.not + self.and + self.or + self.xor + self.mux
+    }
+
+    /// The counts since the `earlier` snapshot. A snapshot taken before a `reset` can exceed the current counts, so the differences
+    /// saturate at 0 instead of underflowing.
+    pub fn since(&self, earlier: &GateCounts) -> GateCounts {
+        GateCounts {
+            not: self.not.saturating_sub(earlier.not),
+            and: self.and.saturating_sub(earlier.and),
+            or: self.or.saturating_sub(earlier.or),
+            xor: self.xor.saturating_sub(earlier.xor),
+            mux: self.mux.saturating_sub(earlier.mux),
+            trivial: self.trivial.saturating_sub(earlier.trivial),
+            bootstraps: self.bootstraps.saturating_sub(earlier.bootstraps),
+            depth: self.depth.saturating_sub(earlier.depth),
+        }
+    }
+}
+
+impl fmt::Display for GateCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>10} {:>6}",
+            self.gates(),
+            self.not,
+            self.and,
+            self.or,
+            self.xor,
+            self.mux,
+            self.trivial,
+            self.bootstraps,
+            self.depth
+        )
+    }
+}
+
+/// CountingBackend evaluates the gates with an inner backend while counting every gate, trivial encryption and bootstrap.
+///
+/// The bootstraps follow the boolean API of tfhe: `not` is free, a binary gate costs one bootstrap and a mux two. As with the `ServerKey`, the
+/// gates with a trivially encrypted operand are folded into copies, nots, constants or a single and/or (see the `folding` module), and only
+/// the gates they reduce to are counted. By default the inner backend is the `ClearBackend`, so the cost of the whole pipeline is obtained
+/// without running FHE at all.
+pub struct CountingBackend<B: Backend = ClearBackend> {
+    inner: B,
+    s_box_plans: Option<Arc<SBoxPlans>>,
+    not: AtomicU64,
+    and: AtomicU64,
+    or: AtomicU64,
+    xor: AtomicU64,
+    mux: AtomicU64,
+    trivial: AtomicU64,
+    bootstraps: AtomicU64,
+    depth: AtomicU32,
+}
+
+impl Default for CountingBackend<ClearBackend> {
+    fn default() -> Self {
+        Self::new(ClearBackend)
+    }
+}
+
+impl<B: Backend> CountingBackend<B> {
+    pub fn new(inner: B) -> Self {
+        CountingBackend {
+            inner,
+            s_box_plans: None,
+            not: AtomicU64::new(0),
+            and: AtomicU64::new(0),
+            or: AtomicU64::new(0),
+            xor: AtomicU64::new(0),
+            mux: AtomicU64::new(0),
+            trivial: AtomicU64::new(0),
+            bootstraps: AtomicU64::new(0),
+            depth: AtomicU32::new(0),
+        }
+    }
+
+    /// Evaluates the S-Box with the given plans instead of those of the inner backend, e.g. to compare the S-Box circuits.
+    pub fn with_s_box_plans(mut self, s_box_plans: Arc<SBoxPlans>) -> Self {
+        self.s_box_plans = Some(s_box_plans);
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn counts(&self) -> GateCounts {
+        GateCounts {
+            not: self.not.load(Ordering::Relaxed),
+            and: self.and.load(Ordering::Relaxed),
+            or: self.or.load(Ordering::Relaxed),
+            xor: self.xor.load(Ordering::Relaxed),
+            mux: self.mux.load(Ordering::Relaxed),
+            trivial: self.trivial.load(Ordering::Relaxed),
+            bootstraps: self.bootstraps.load(Ordering::Relaxed),
+            depth: self.depth.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in [
+            &self.not,
+            &self.and,
+            &self.or,
+            &self.xor,
+            &self.mux,
+            &self.trivial,
+            &self.bootstraps,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.depth.store(0, Ordering::Relaxed);
+    }
+
+    /// Runs `func` and returns its result along with the gates it evaluated.
+    ///
+    /// The depth is the growth of the critical path, which is the depth of the operation as long as its inputs are the deepest bits produced so far.
+    pub fn measure<R>(&self, func: impl FnOnce() -> R) -> (R, GateCounts) {
+        let before = self.counts();
+        let result = func();
+        (result, self.counts().since(&before))
+    }
+
+    /// Wraps an encrypted bit of the inner backend, as the input of a computation
+    pub fn input(&self, value: B::Bit) -> Counted<B::Bit> {
+        Counted {
+            value,
+            depth: 0,
+            constant: None,
+        }
+    }
+
+    fn output(&self, value: B::Bit, depth: u32, constant: Option<bool>) -> Counted<B::Bit> {
+        self.depth.fetch_max(depth, Ordering::Relaxed);
+        Counted {
+            value,
+            depth,
+            constant,
+        }
+    }
+
+    /// Records a gate on two encrypted bits, the gates with a known operand being folded beforehand
+    fn binary(
+        &self,
+        counter: &AtomicU64,
+        lhs: &Counted<B::Bit>,
+        rhs: &Counted<B::Bit>,
+        value: B::Bit,
+    ) -> Counted<B::Bit> {
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.bootstraps.fetch_add(1, Ordering::Relaxed);
+        self.output(value, lhs.depth.max(rhs.depth) + 1, None)
+    }
+}
+
+impl<B: Backend> Backend for CountingBackend<B>
+where
+    B::Bit: fmt::Debug,
+{
+    type Bit = Counted<B::Bit>;
+
+    fn trivial(&self, value: bool) -> Self::Bit {
+        self.trivial.fetch_add(1, Ordering::Relaxed);
+        self.output(self.inner.trivial(value), 0, Some(value))
+    }
+
+    fn not(&self, bit: &Self::Bit) -> Self::Bit {
+        self.not.fetch_add(1, Ordering::Relaxed);
+        self.output(
+            self.inner.not(&bit.value),
+            bit.depth,
+            bit.constant.map(|x| !x),
+        )
+    }
+
+    fn and(&self, lhs: &Self::Bit, rhs: &Self::Bit) -> Self::Bit {
+        fold_and(self, lhs, rhs).unwrap_or_else(|| {
+            let value = self.inner.and(&lhs.value, &rhs.value);
+            self.binary(&self.and, lhs, rhs, value)
+        })
+    }
+
+    fn or(&self, lhs: &Self::Bit, rhs: &Self::Bit) -> Self::Bit {
+        fold_or(self, lhs, rhs).unwrap_or_else(|| {
+            let value = self.inner.or(&lhs.value, &rhs.value);
+            self.binary(&self.or, lhs, rhs, value)
+        })
+    }
+
+    fn xor(&self, lhs: &Self::Bit, rhs: &Self::Bit) -> Self::Bit {
+        fold_xor(self, lhs, rhs).unwrap_or_else(|| {
+            let value = self.inner.xor(&lhs.value, &rhs.value);
+            self.binary(&self.xor, lhs, rhs, value)
+        })
+    }
+
+    fn mux(&self, condition: &Self::Bit, then: &Self::Bit, otherwise: &Self::Bit) -> Self::Bit {
+        fold_mux(self, condition, then, otherwise).unwrap_or_else(|| {
+            self.mux.fetch_add(1, Ordering::Relaxed);
+            let value = self
+                .inner
+                .mux(&condition.value, &then.value, &otherwise.value);
+
+            // Both bootstraps of a mux are independent, so they only add one level to the critical path
+            self.bootstraps.fetch_add(2, Ordering::Relaxed);
+            let depth = condition.depth.max(then.depth).max(otherwise.depth) + 1;
+            self.output(value, depth, None)
+        })
+    }
+
+    fn constant(&self, bit: &Self::Bit) -> Option<bool> {
+        bit.constant
+    }
+
+    fn s_box_plans(&self) -> &Arc<SBoxPlans> {
+        match &self.s_box_plans {
+            Some(s_box_plans) => s_box_plans,
+            None => self.inner.s_box_plans(),
+        }
+    }
+
+    fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        self.inner.install(op)
+    }
+}
+
+impl<B: Backend + ClientBackend<Bit = <B as Backend>::Bit>> ClientBackend for CountingBackend<B> {
+    type Bit = Counted<<B as Backend>::Bit>;
+
+    fn encrypt(&self, value: bool) -> Self::Bit {
+        self.input(self.inner.encrypt(value))
+    }
+
+    fn decrypt(&self, bit: &Self::Bit) -> bool {
+        self.inner.decrypt(&bit.value)
+    }
+}
+
+/// CostReport is a list of labelled gate counts, printed as a table.
+///
+/// The entries of a sequential report run one after the other (e.g. the rounds of a cipher), and the table then ends with their total.
+#[derive(Clone, Debug, Default)]
+pub struct CostReport {
+    pub title: String,
+    pub entries: Vec<(String, GateCounts)>,
+    pub sequential: bool,
+}
+
+impl CostReport {
+    pub fn new(title: &str) -> Self {
+        CostReport {
+            title: title.to_string(),
+            entries: Vec::new(),
+            sequential: false,
+        }
+    }
+
+    pub fn sequential(title: &str) -> Self {
+        CostReport {
+            sequential: true,
+            ..Self::new(title)
+        }
+    }
+
+    pub fn push(&mut self, label: &str, counts: GateCounts) {
+        self.entries.push((label.to_string(), counts));
+    }
+
+    pub fn get(&self, label: &str) -> Option<&GateCounts> {
+        self.entries
+            .iter()
+            .find(|(entry_label, _)| entry_label == label)
+            .map(|(_, counts)| counts)
+    }
+
+    /// The sum of all entries, whose depth is the sum of the depths as in a sequential report
+    pub fn total(&self) -> GateCounts {
+        self.entries
+            .iter()
+            .fold(GateCounts::default(), |total, (_, counts)| GateCounts {
+                not: total.not + counts.not,
+                and: total.and + counts.and,
+                or: total.or + counts.or,
+                xor: total.xor + counts.xor,
+                mux: total.mux + counts.mux,
+                trivial: total.trivial + counts.trivial,
+                bootstraps: total.bootstraps + counts.bootstraps,
+                depth: total.depth + counts.depth,
+            })
+    }
+}
+
+impl fmt::Display for CostReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.title)?;
+        writeln!(
+            f,
+            "{:<24} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>10} {:>6}",
+            "", "gates", "not", "and", "or", "xor", "mux", "trivial", "bootstraps", "depth"
+        )?;
+        for (label, counts) in self.entries.iter() {
+            writeln!(f, "{:<24} {}", label, counts)?;
+        }
+        if self.sequential {
+            writeln!(f, "{:<24} {}", "total", self.total())?;
+        }
+        Ok(())
+    }
+}
+
+/// Measures every AES operation once, on fresh encrypted inputs so that the depths are the depths of the operations themselves.
+pub fn operation_report(key_size: KeySize) -> CostReport {
+    let mut report = CostReport::new(&format!("{:?} operations", key_size));
+    let state_data: [u8; 16] = std::array::from_fn(|i| (i as u8).wrapping_mul(0x3b));
+    let key_data: Vec<u8> = (0..key_size.bytes() as u8).collect();
+
+    let measure_state =
+        |report: &mut CostReport,
+         label: &str,
+         op: &dyn Fn(&mut State<Counted<bool>>, &CountingBackend)| {
+            let backend = CountingBackend::default();
+            let mut state = State::from_u8_enc(&state_data, &backend);
+            let ((), counts) = backend.measure(|| op(&mut state, &backend));
+            report.push(label, counts);
+        };
+
+    let backend = CountingBackend::default();
+    let byte = FHEByte::from_u8_enc(&0x53, &backend);
+    report.push("SubByte", backend.measure(|| byte.sub_byte(&backend)).1);
+
+    measure_state(&mut report, "SubBytes", &|state, backend| {
+        state.sub_bytes(backend)
+    });
+    measure_state(&mut report, "InvSubBytes", &|state, backend| {
+        state.inv_sub_bytes(backend)
+    });
+    measure_state(&mut report, "ShiftRows", &|state, _| state.shift_rows());
+    measure_state(&mut report, "MixColumns", &|state, backend| {
+        state.mix_columns(backend)
+    });
+    measure_state(&mut report, "InvMixColumns", &|state, backend| {
+        state.inv_mix_columns(backend)
+    });
+    measure_state(&mut report, "AddRoundKey", &|state, backend| {
+        let key = State::from_u8_enc(&[0x2b; 16], backend);
+        state.xor_state(&key, backend)
+    });
+
+    if key_size == KeySize::Aes128 {
+        let backend = CountingBackend::default();
+        let mut key = Key::from_u8_enc(&key_data, &backend);
+        let ((), counts) = backend.measure(|| key.generate_next_key_in_place(&0x01, &backend));
+        report.push("NextRoundKey", counts);
+    }
+
+    let backend = CountingBackend::default();
+    let key = Key::from_u8_enc(&key_data, &backend);
+    report.push(
+        "KeyExpansion",
+        backend.measure(|| key.generate_round_keys(&backend)).1,
+    );
+
+    report
+}
+
+/// Measures the S-Box and the Inv S-Box of every `SBoxCircuit`, side by side.
+pub fn s_box_report() -> CostReport {
+    let mut report = CostReport::new("S-Box circuits");
+
+    for circuit in SBoxCircuit::ALL {
+        let s_box_plans = SBoxPlans::shared_for(circuit);
+
+        // A backend per measure, so that the depths are those of the S-Boxes themselves
+        for (label, inverse) in [("SubByte", false), ("InvSubByte", true)] {
+            let backend = CountingBackend::default().with_s_box_plans(s_box_plans.clone());
+            let byte = FHEByte::from_u8_enc(&0x53, &backend);
+            let (_, counts) = backend.measure(|| {
+                if inverse {
+                    byte.inv_sub_byte(&backend)
+                } else {
+                    byte.sub_byte(&backend)
+                }
+            });
+            report.push(&format!("{} {}", label, circuit), counts);
+        }
+    }
+
+    report
+}
+
+/// Measures the mux tree plans of the S-Box and the Inv S-Box with the selector order of `BooleanExpr::reduce_mux` for every output bit
+/// and with the orders searched by `mux_order`.
+pub fn mux_order_report() -> CostReport {
+    let mut report = CostReport::new("Mux tree selector orders");
+
+    for (label, data, orders) in [
+        ("SubByte", S_BOX_DATA, S_BOX_ORDERS),
+        ("InvSubByte", INV_S_BOX_DATA, INV_S_BOX_ORDERS),
+    ] {
+        for (name, orders) in [("identity", [IDENTITY_ORDER; 8]), ("searched", orders)] {
+            let plan = mux_order::compile(data, &orders);
+            let backend = CountingBackend::default();
+            let bits: Vec<_> = (0..8)
+                .map(|i| backend.encrypt(0x53 & (1 << i) != 0))
+                .collect();
+            let (_, counts) = backend.measure(|| plan.evaluate(&bits, &backend));
+            report.push(&format!("{} {}", label, name), counts);
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counting_gates() {
+        let backend = CountingBackend::default();
+        let x = backend.encrypt(true);
+        let y = backend.encrypt(false);
+        let one = backend.trivial(true);
+
+        let z = backend.xor(&x, &y);
+        assert!(backend.decrypt(&z));
+        assert_eq!(z.depth(), 1);
+
+        // Gates with a trivial operand are folded and not gates do not bootstrap
+        let w = backend.not(&backend.and(&z, &one));
+        assert!(!backend.decrypt(&w));
+        assert_eq!(w.depth(), 1);
+
+        let m = backend.mux(&w, &x, &z);
+        assert!(backend.decrypt(&m));
+        assert_eq!(m.depth(), 2);
+
+        let counts = backend.counts();
+        assert_eq!(counts.gates(), 3);
+        assert_eq!(
+            (counts.xor, counts.and, counts.not, counts.mux),
+            (1, 0, 1, 1)
+        );
+        assert_eq!(counts.trivial, 1);
+        assert_eq!(counts.bootstraps, 3);
+        assert_eq!(counts.depth, 2);
+
+        backend.reset();
+        assert_eq!(backend.counts(), GateCounts::default());
+
+        // A snapshot taken before the reset does not underflow
+        backend.xor(&x, &y);
+        let since = backend.counts().since(&counts);
+        assert_eq!((since.xor, since.bootstraps, since.depth), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_operation_report() {
+        let report = operation_report(KeySize::Aes128);
+        println!("{}", report);
+
+        let sub_byte = report.get("SubByte").unwrap();
+        let sub_bytes = report.get("SubBytes").unwrap();
+        assert_eq!(sub_bytes.bootstraps, 16 * sub_byte.bootstraps);
+        assert_eq!(sub_bytes.depth, sub_byte.depth);
+
+        assert_eq!(report.get("ShiftRows").unwrap().gates(), 0);
+
+        // The linear layers are straight-line xor circuits on every column
+        let mix_columns = report.get("MixColumns").unwrap();
+        assert_eq!(mix_columns.xor, mix_columns.gates());
+        assert_eq!(mix_columns.bootstraps, 4 * 95);
+        assert_eq!(report.get("InvMixColumns").unwrap().bootstraps, 4 * 129);
+        assert_eq!(report.get("AddRoundKey").unwrap().xor, 128);
+        assert_eq!(report.get("AddRoundKey").unwrap().depth, 1);
+
+        let next_key = report.get("NextRoundKey").unwrap();
+        assert_eq!(
+            report.get("KeyExpansion").unwrap().bootstraps,
+            10 * next_key.bootstraps
+        );
+    }
+
+    #[test]
+    fn test_s_box_report() {
+        let report = s_box_report();
+        println!("{}", report);
+
+        let mux_tree = report.get("SubByte mux-tree").unwrap();
+        let boyar_peralta = report.get("SubByte boyar-peralta").unwrap();
+        assert_eq!(boyar_peralta.bootstraps, 128);
+        assert_eq!(boyar_peralta.depth, 16);
+        assert!(boyar_peralta.bootstraps < mux_tree.bootstraps);
+
+        let inv_mux_tree = report.get("InvSubByte mux-tree").unwrap();
+        let inv_boyar_peralta = report.get("InvSubByte boyar-peralta").unwrap();
+        assert!(inv_boyar_peralta.bootstraps < inv_mux_tree.bootstraps);
+    }
+
+    #[test]
+    fn test_mux_order_report() {
+        let report = mux_order_report();
+        println!("{}", report);
+
+        // The shared plans are compiled with the searched orders
+        let s_box = s_box_report();
+        for label in ["SubByte", "InvSubByte"] {
+            let identity = report.get(&format!("{} identity", label)).unwrap();
+            let searched = report.get(&format!("{} searched", label)).unwrap();
+            assert!(searched.gates() < identity.gates());
+            assert_eq!(
+                searched.bootstraps,
+                s_box
+                    .get(&format!("{} mux-tree", label))
+                    .unwrap()
+                    .bootstraps
+            );
+        }
+    }
+}
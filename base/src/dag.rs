@@ -0,0 +1,588 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::backend::Backend;
+use crate::folding::{fold_and, fold_mux, fold_or, fold_xor};
+use crate::plan::Gate;
+use crate::sbox::SBoxPlans;
+
+/// A node of a `GateDag`, whose wire is its index in the node list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Node {
+    /// The i-th input of the DAG
+    Input(usize),
+    Constant(bool),
+    /// A gate whose fields are the wires of its inputs, all of them earlier in the node list
+    Gate(Gate),
+}
+
+/// GateDag is a whole computation lowered to a single DAG of gates, e.g. the key schedule, the rounds and the chaining of a mode.
+///
+/// The primitives evaluate their gates in nested parallel loops (the bits of a byte, the bytes of a state, the states of a batch), with a
+/// barrier at the end of every loop. A DAG is instead run by a list scheduler over all the threads of the backend: a gate is ready as soon as
+/// its inputs are, and the ready gate on the longest remaining path runs first, so that independent gates of different rounds
+/// and blocks overlap. Every value is dropped after its last reader.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GateDag {
+    nodes: Vec<Node>,
+    num_inputs: usize,
+    outputs: Vec<usize>,
+}
+
+impl GateDag {
+    /// Lowers a circuit written against the `Backend` trait, by running it once on a `DagBuilder` with the shared S-Box plans.
+    ///
+    /// The circuit receives the wires of the `num_inputs` inputs and returns the wires of its outputs.
+    pub fn trace(
+        num_inputs: usize,
+        circuit: impl FnOnce(&DagBuilder, &[usize]) -> Vec<usize>,
+    ) -> Self {
+        DagBuilder::default().trace(num_inputs, circuit)
+    }
+
+    /// Lowers a circuit on the given bits with the S-Box plans of the backend and evaluates it, as `Backend::run_dag` does by default.
+    ///
+    /// The bits known by the backend, such as trivial encryptions, become constants of the DAG so that the gates reading them are still
+    /// folded, and the others become its inputs. The circuit receives the wire of every bit and returns the wires of its outputs.
+    pub fn run<B: Backend>(
+        bits: &[B::Bit],
+        server_key: &B,
+        circuit: impl FnOnce(&DagBuilder, &[usize]) -> Vec<usize>,
+    ) -> Vec<B::Bit> {
+        let builder = DagBuilder::new(server_key.s_box_plans().clone());
+        let mut inputs = Vec::new();
+        let wires: Vec<usize> = bits
+            .iter()
+            .map(|bit| match server_key.constant(bit) {
+                Some(value) => builder.trivial(value),
+                None => {
+                    inputs.push(bit.clone());
+                    builder.inputs(1)[0]
+                }
+            })
+            .collect();
+
+        let outputs = circuit(&builder, &wires);
+        builder.build(&outputs).evaluate(&inputs, server_key)
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.num_inputs
+    }
+
+    /// The wires of the outputs
+    pub fn outputs(&self) -> &[usize] {
+        &self.outputs
+    }
+
+    fn gates(&self) -> impl Iterator<Item = &Gate> {
+        self.nodes.iter().filter_map(|node| match node {
+            Node::Gate(gate) => Some(gate),
+            _ => None,
+        })
+    }
+
+    pub fn num_gates(&self) -> usize {
+        self.gates().count()
+    }
+
+    pub fn bootstraps(&self) -> u64 {
+        self.gates().map(|gate| gate.bootstraps() as u64).sum()
+    }
+
+    /// The wires reading every wire
+    fn readers(&self) -> Vec<Vec<usize>> {
+        let mut readers = vec![Vec::new(); self.nodes.len()];
+        for (wire, node) in self.nodes.iter().enumerate() {
+            if let Node::Gate(gate) = node {
+                for input in gate.inputs() {
+                    readers[input].push(wire);
+                }
+            }
+        }
+        readers
+    }
+
+    /// The depth of the longest path from every wire to an output, the wire included, by which the scheduler orders the ready gates.
+    ///
+    /// As for the `CountingBackend`, every gate but not adds one to the depth, the two bootstraps of a mux being independent.
+    pub fn critical_paths(&self) -> Vec<u32> {
+        let readers = self.readers();
+        let mut critical_paths = vec![0; self.nodes.len()];
+        for wire in (0..self.nodes.len()).rev() {
+            let own = match self.nodes[wire] {
+                Node::Gate(gate) => !gate.is_free() as u32,
+                _ => 0,
+            };
+            let after = readers[wire]
+                .iter()
+                .map(|&reader| critical_paths[reader])
+                .max()
+                .unwrap_or(0);
+            critical_paths[wire] = own + after;
+        }
+        critical_paths
+    }
+
+    /// The critical path of the whole DAG, as the depth of the `CountingBackend`
+    pub fn depth(&self) -> u32 {
+        self.critical_paths().into_iter().max().unwrap_or(0)
+    }
+
+    /// Evaluates the DAG on its inputs and returns the outputs in the order they were traced.
+    ///
+    /// Every gate made ready by the value of its last missing input spawns a task on the thread pool of the backend. The ready gates are
+    /// shared, ordered by critical path and then by wire, and a task runs the first of them, which is not necessarily the gate that spawned
+    /// it. No task ever waits: there are as many tasks as ready gates, and the number of missing inputs of every gate is an atomic counter.
+    pub fn evaluate<B: Backend>(&self, inputs: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+        assert!(
+            inputs.len() == self.num_inputs,
+            "The DAG has {} inputs !",
+            self.num_inputs
+        );
+
+        let readers = self.readers();
+        let mut remaining_readers: Vec<usize> = readers.iter().map(Vec::len).collect();
+        for &output in self.outputs.iter() {
+            // The outputs are never dropped
+            remaining_readers[output] += 1;
+        }
+
+        let mut available = Vec::new();
+        let mut values = Vec::with_capacity(self.nodes.len());
+        let mut pending = Vec::with_capacity(self.nodes.len());
+        for (wire, node) in self.nodes.iter().enumerate() {
+            let (value, num_inputs) = match *node {
+                Node::Input(i) => (Some(Arc::new(inputs[i].clone())), 0),
+                Node::Constant(value) => (Some(Arc::new(server_key.trivial(value))), 0),
+                Node::Gate(gate) => (None, gate.inputs().len()),
+            };
+            if value.is_some() {
+                available.push(wire);
+            }
+            values.push(Mutex::new(value));
+            pending.push(AtomicUsize::new(num_inputs));
+        }
+
+        let scheduler = Scheduler {
+            dag: self,
+            server_key,
+            critical_paths: self.critical_paths(),
+            readers,
+            ready: Mutex::new(BinaryHeap::new()),
+            values,
+            pending,
+            remaining_readers: remaining_readers
+                .into_iter()
+                .map(AtomicUsize::new)
+                .collect(),
+        };
+
+        server_key.install(|| {
+            rayon::scope(|scope| {
+                for wire in available {
+                    scheduler.release(wire, scope);
+                }
+            })
+        });
+
+        let values = scheduler.values;
+        self.outputs
+            .iter()
+            .map(|&wire| B::Bit::clone(values[wire].lock().unwrap().as_ref().unwrap()))
+            .collect()
+    }
+}
+
+/// The state shared by the tasks of `GateDag::evaluate`
+struct Scheduler<'a, B: Backend> {
+    dag: &'a GateDag,
+    server_key: &'a B,
+    readers: Vec<Vec<usize>>,
+    critical_paths: Vec<u32>,
+    /// The gates whose inputs are all available, by critical path and then by wire
+    ready: Mutex<BinaryHeap<(u32, Reverse<usize>)>>,
+    values: Vec<Mutex<Option<Arc<B::Bit>>>>,
+    /// The number of inputs of every gate which are not available yet
+    pending: Vec<AtomicUsize>,
+    /// The number of gates which have yet to read every value, plus one for the outputs
+    remaining_readers: Vec<AtomicUsize>,
+}
+
+impl<B: Backend> Scheduler<'_, B> {
+    /// Marks the value of `wire` as available, and spawns a task for every gate it made ready.
+    fn release<'s>(&'s self, wire: usize, scope: &rayon::Scope<'s>) {
+        for &reader in self.readers[wire].iter() {
+            if self.pending[reader].fetch_sub(1, Ordering::AcqRel) == 1 {
+                let ready = (self.critical_paths[reader], Reverse(reader));
+                self.ready.lock().unwrap().push(ready);
+                scope.spawn(|scope| self.run_next(scope));
+            }
+        }
+    }
+
+    /// Runs the ready gate on the longest critical path, then releases its value.
+    fn run_next<'s>(&'s self, scope: &rayon::Scope<'s>) {
+        // Every task is spawned along with a ready gate, so there is always one left
+        let (_, Reverse(wire)) = self.ready.lock().unwrap().pop().unwrap();
+        let Node::Gate(gate) = self.dag.nodes[wire] else {
+            unreachable!("Only the gates are scheduled");
+        };
+
+        let inputs: Vec<Arc<B::Bit>> = gate
+            .inputs()
+            .iter()
+            .map(|&input| self.values[input].lock().unwrap().clone().unwrap())
+            .collect();
+        let server_key = self.server_key;
+        let value = match gate {
+            Gate::And(..) => server_key.and(&inputs[0], &inputs[1]),
+            Gate::Or(..) => server_key.or(&inputs[0], &inputs[1]),
+            Gate::Xor(..) => server_key.xor(&inputs[0], &inputs[1]),
+            Gate::Mux(..) => server_key.mux(&inputs[0], &inputs[1], &inputs[2]),
+            Gate::Not(..) => server_key.not(&inputs[0]),
+        };
+        drop(inputs);
+
+        *self.values[wire].lock().unwrap() = Some(Arc::new(value));
+        for input in gate.inputs() {
+            if self.remaining_readers[input].fetch_sub(1, Ordering::AcqRel) == 1 {
+                *self.values[input].lock().unwrap() = None;
+            }
+        }
+        self.release(wire, scope);
+    }
+}
+
+/// DagBuilder is the backend which lowers a circuit to a `GateDag`, by recording its gates instead of evaluating them.
+///
+/// Its bits are wires: the first two wires are the constants true and false, the wires of the inputs are created by `inputs`, and every other
+/// wire is the output of a recorded gate. As with the `ServerKey`, the gates reading a constant are folded, and identical gates are recorded
+/// once. The gates which do not lead to an output are pruned by `build`.
+#[derive(Debug)]
+pub struct DagBuilder {
+    recorded: Mutex<RecordedNodes>,
+    s_box_plans: Arc<SBoxPlans>,
+}
+
+#[derive(Debug)]
+struct RecordedNodes {
+    nodes: Vec<Node>,
+    num_inputs: usize,
+    wires: HashMap<Gate, usize>,
+}
+
+impl Default for DagBuilder {
+    fn default() -> Self {
+        Self::new(SBoxPlans::shared().clone())
+    }
+}
+
+impl DagBuilder {
+    /// Creates a builder lowering the S-Box with the given plans.
+    pub fn new(s_box_plans: Arc<SBoxPlans>) -> Self {
+        DagBuilder {
+            recorded: Mutex::new(RecordedNodes {
+                nodes: vec![Node::Constant(true), Node::Constant(false)],
+                num_inputs: 0,
+                wires: HashMap::new(),
+            }),
+            s_box_plans,
+        }
+    }
+
+    /// Creates the wires of `n` new inputs.
+    pub fn inputs(&self, n: usize) -> Vec<usize> {
+        let mut recorded = self.recorded.lock().unwrap();
+        (0..n)
+            .map(|_| {
+                let wire = recorded.nodes.len();
+                let input = recorded.num_inputs;
+                recorded.nodes.push(Node::Input(input));
+                recorded.num_inputs += 1;
+                wire
+            })
+            .collect()
+    }
+
+    /// Lowers the circuit, which receives the wires of `num_inputs` new inputs and returns the wires of its outputs.
+    pub fn trace(
+        self,
+        num_inputs: usize,
+        circuit: impl FnOnce(&DagBuilder, &[usize]) -> Vec<usize>,
+    ) -> GateDag {
+        let inputs = self.inputs(num_inputs);
+        let outputs = circuit(&self, &inputs);
+        self.build(&outputs)
+    }
+
+    fn record(&self, gate: Gate) -> usize {
+        let mut recorded = self.recorded.lock().unwrap();
+        if let Some(&wire) = recorded.wires.get(&gate) {
+            return wire;
+        }
+
+        let wire = recorded.nodes.len();
+        recorded.nodes.push(Node::Gate(gate));
+        recorded.wires.insert(gate, wire);
+        wire
+    }
+
+    /// Prunes the nodes which do not lead to an output, the inputs excepted, and renumbers the others in the order they were recorded.
+    pub fn build(self, outputs: &[usize]) -> GateDag {
+        let recorded = self.recorded.into_inner().unwrap();
+
+        let mut used = vec![false; recorded.nodes.len()];
+        for &output in outputs.iter() {
+            used[output] = true;
+        }
+        for wire in (0..recorded.nodes.len()).rev() {
+            match recorded.nodes[wire] {
+                Node::Input(_) => used[wire] = true,
+                Node::Gate(gate) if used[wire] => {
+                    for input in gate.inputs() {
+                        used[input] = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut wires = vec![usize::MAX; recorded.nodes.len()];
+        let mut nodes = Vec::new();
+        for (wire, node) in recorded.nodes.iter().enumerate() {
+            if !used[wire] {
+                continue;
+            }
+            wires[wire] = nodes.len();
+            nodes.push(match *node {
+                Node::Gate(gate) => Node::Gate(match gate {
+                    Gate::And(lhs, rhs) => Gate::And(wires[lhs], wires[rhs]),
+                    Gate::Or(lhs, rhs) => Gate::Or(wires[lhs], wires[rhs]),
+                    Gate::Xor(lhs, rhs) => Gate::Xor(wires[lhs], wires[rhs]),
+                    Gate::Mux(condition, then, otherwise) => {
+                        Gate::Mux(wires[condition], wires[then], wires[otherwise])
+                    }
+                    Gate::Not(input) => Gate::Not(wires[input]),
+                }),
+                node => node,
+            });
+        }
+
+        GateDag {
+            nodes,
+            num_inputs: recorded.num_inputs,
+            outputs: outputs.iter().map(|&wire| wires[wire]).collect(),
+        }
+    }
+}
+
+impl Backend for DagBuilder {
+    type Bit = usize;
+
+    fn trivial(&self, value: bool) -> usize {
+        if value { 0 } else { 1 }
+    }
+
+    fn not(&self, bit: &usize) -> usize {
+        if let Some(value) = self.constant(bit) {
+            return self.trivial(!value);
+        }
+        let recorded = self.recorded.lock().unwrap();
+        if let Node::Gate(Gate::Not(input)) = recorded.nodes[*bit] {
+            return input;
+        }
+        drop(recorded);
+        self.record(Gate::Not(*bit))
+    }
+
+    fn and(&self, lhs: &usize, rhs: &usize) -> usize {
+        fold_and(self, lhs, rhs)
+            .unwrap_or_else(|| self.record(Gate::And(*lhs.min(rhs), *lhs.max(rhs))))
+    }
+
+    fn or(&self, lhs: &usize, rhs: &usize) -> usize {
+        fold_or(self, lhs, rhs)
+            .unwrap_or_else(|| self.record(Gate::Or(*lhs.min(rhs), *lhs.max(rhs))))
+    }
+
+    fn xor(&self, lhs: &usize, rhs: &usize) -> usize {
+        fold_xor(self, lhs, rhs)
+            .unwrap_or_else(|| self.record(Gate::Xor(*lhs.min(rhs), *lhs.max(rhs))))
+    }
+
+    fn mux(&self, condition: &usize, then: &usize, otherwise: &usize) -> usize {
+        fold_mux(self, condition, then, otherwise)
+            .unwrap_or_else(|| self.record(Gate::Mux(*condition, *then, *otherwise)))
+    }
+
+    fn constant(&self, bit: &usize) -> Option<bool> {
+        match *bit {
+            0 => Some(true),
+            1 => Some(false),
+            _ => None,
+        }
+    }
+
+    fn s_box_plans(&self) -> &Arc<SBoxPlans> {
+        &self.s_box_plans
+    }
+
+    /// The gates of the circuit are recorded in the DAG being lowered, so that a circuit traced as a whole stays a single DAG.
+    fn run_dag(
+        &self,
+        bits: &[usize],
+        circuit: impl FnOnce(&DagBuilder, &[usize]) -> Vec<usize>,
+    ) -> Vec<usize> {
+        circuit(self, bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{ClearBackend, ClientBackend};
+    use crate::counting::CountingBackend;
+    use crate::key_schedule::key_expansion_clear;
+    use crate::{Key, State};
+
+    fn to_bits(data: &[u8]) -> Vec<bool> {
+        data.iter()
+            .flat_map(|x| (0..8).rev().map(move |i| x & (1 << i) != 0))
+            .collect()
+    }
+
+    #[test]
+    fn test_bits_round_trip() {
+        let data: [u8; 16] = std::array::from_fn(|i| (17 * i + 3) as u8);
+        let state = State::from_u8_enc(&data, &ClearBackend);
+        assert_eq!(state.to_bits(), to_bits(&data));
+        assert_eq!(
+            State::from_bits(&to_bits(&data)).decrypt_to_u8(&ClearBackend),
+            data
+        );
+
+        for key_bytes in [16, 24, 32] {
+            let data: Vec<u8> = (0..key_bytes as u8).map(|i| i.wrapping_mul(29)).collect();
+            let key = Key::from_u8_enc(&data, &ClearBackend);
+            assert_eq!(key.to_bits(), to_bits(&data));
+            assert_eq!(
+                Key::from_bits(&to_bits(&data)).decrypt_to_u8(&ClearBackend),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_dag_key_schedule_and_rounds() {
+        let key_data: Vec<u8> = (0..16).collect();
+        let block: [u8; 16] = std::array::from_fn(|i| (i * 0x11) as u8);
+
+        // The first round of AES-128, with the key schedule evaluated online
+        let dag = GateDag::trace(256, |builder, inputs| {
+            let key = Key::from_bits(&inputs[..128]);
+            let round_keys = key.generate_round_keys(builder);
+            let mut state = State::from_bits(&inputs[128..]);
+            state.xor_key_enc(&round_keys[0], builder);
+            state.sub_bytes(builder);
+            state.shift_rows();
+            state.mix_columns(builder);
+            state.xor_key_enc(&round_keys[1], builder);
+            state.to_bits()
+        });
+
+        let round_keys = key_expansion_clear(&key_data);
+        let mut expected = State::from_u8_enc(&block, &ClearBackend);
+        expected.xor_key_clear(&round_keys[0], &ClearBackend);
+        expected.sub_bytes(&ClearBackend);
+        expected.shift_rows();
+        expected.mix_columns(&ClearBackend);
+        expected.xor_key_clear(&round_keys[1], &ClearBackend);
+
+        let inputs = [to_bits(&key_data), to_bits(&block)].concat();
+        assert_eq!(dag.evaluate(&inputs, &ClearBackend), expected.to_bits());
+
+        // The unused round keys are pruned, and the costs are those of the gates evaluated one by one
+        let backend = CountingBackend::default();
+        let inputs: Vec<_> = inputs.iter().map(|&bit| backend.encrypt(bit)).collect();
+        let (outputs, counts) = backend.measure(|| dag.evaluate(&inputs, &backend));
+        assert_eq!(
+            outputs
+                .iter()
+                .map(|bit| backend.decrypt(bit))
+                .collect::<Vec<_>>(),
+            expected.to_bits()
+        );
+        assert_eq!(counts.gates(), dag.num_gates() as u64);
+        assert_eq!(counts.bootstraps, dag.bootstraps());
+        assert_eq!(counts.depth, dag.depth());
+    }
+
+    #[test]
+    fn test_dag_folding_and_dedup() {
+        let dag = GateDag::trace(2, |builder, inputs| {
+            let (x, y) = (inputs[0], inputs[1]);
+            let t = builder.trivial(true);
+            vec![
+                builder.and(&x, &y),
+                builder.and(&y, &x),
+                builder.xor(&x, &t),
+                builder.not(&builder.not(&y)),
+                builder.mux(&t, &x, &y),
+            ]
+        });
+
+        assert_eq!(dag.num_gates(), 2);
+        assert_eq!(dag.bootstraps(), 1);
+        assert_eq!(dag.outputs()[0], dag.outputs()[1]);
+        for x in [false, true] {
+            for y in [false, true] {
+                assert_eq!(dag.evaluate(&[x, y], &ClearBackend), vec![
+                    x & y,
+                    x & y,
+                    !x,
+                    y,
+                    x
+                ]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_dag() {
+        let backend = CountingBackend::default();
+        let bits = [
+            backend.encrypt(true),
+            backend.trivial(true),
+            backend.encrypt(false),
+        ];
+
+        // The trivial bit is a constant of the DAG, and a nested run records its gates in the same DAG
+        let (outputs, counts) = backend.measure(|| {
+            backend.run_dag(&bits, |builder, wires| {
+                let (x, t, y) = (wires[0], wires[1], wires[2]);
+                let x_and_t = builder.and(&x, &t);
+                let nested = builder.run_dag(&[x_and_t, y], |builder, wires| {
+                    vec![builder.xor(&wires[0], &wires[1])]
+                });
+                vec![x_and_t, nested[0], builder.or(&y, &t)]
+            })
+        });
+
+        assert_eq!(
+            outputs
+                .iter()
+                .map(|bit| backend.decrypt(bit))
+                .collect::<Vec<_>>(),
+            vec![true, true, true]
+        );
+        assert_eq!(backend.constant(&outputs[2]), Some(true));
+        assert_eq!(counts.bootstraps, 1);
+    }
+}
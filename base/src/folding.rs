@@ -0,0 +1,101 @@
+use crate::backend::Backend;
+
+/// Constant folding of the gates with an operand known in the clear, as reported by `Backend::constant`.
+///
+/// A gate with a known operand is a copy, a not or a constant, none of which bootstraps: `and(x, true)` is `x`, `xor(x, true)` is `not(x)`,
+/// and a mux with a known condition selects its branch. A mux with an encrypted condition and a known branch is a single and/or gate, and
+/// one with two known branches is a copy, a not or a constant of the condition. These functions return `None` when the gate has to be
+/// evaluated, so that the backends only fall back to their own gates for fully encrypted operands.
+pub fn fold_and<B: Backend + ?Sized>(backend: &B, lhs: &B::Bit, rhs: &B::Bit) -> Option<B::Bit> {
+    match (backend.constant(lhs), backend.constant(rhs)) {
+        (Some(false), _) | (_, Some(false)) => Some(backend.trivial(false)),
+        (Some(true), _) => Some(rhs.clone()),
+        (_, Some(true)) => Some(lhs.clone()),
+        (None, None) => None,
+    }
+}
+
+pub fn fold_or<B: Backend + ?Sized>(backend: &B, lhs: &B::Bit, rhs: &B::Bit) -> Option<B::Bit> {
+    match (backend.constant(lhs), backend.constant(rhs)) {
+        (Some(true), _) | (_, Some(true)) => Some(backend.trivial(true)),
+        (Some(false), _) => Some(rhs.clone()),
+        (_, Some(false)) => Some(lhs.clone()),
+        (None, None) => None,
+    }
+}
+
+pub fn fold_xor<B: Backend + ?Sized>(backend: &B, lhs: &B::Bit, rhs: &B::Bit) -> Option<B::Bit> {
+    match (backend.constant(lhs), backend.constant(rhs)) {
+        (Some(x), Some(y)) => Some(backend.trivial(x ^ y)),
+        (Some(false), None) => Some(rhs.clone()),
+        (Some(true), None) => Some(backend.not(rhs)),
+        (None, Some(false)) => Some(lhs.clone()),
+        (None, Some(true)) => Some(backend.not(lhs)),
+        (None, None) => None,
+    }
+}
+
+pub fn fold_mux<B: Backend + ?Sized>(
+    backend: &B,
+    condition: &B::Bit,
+    then: &B::Bit,
+    otherwise: &B::Bit,
+) -> Option<B::Bit> {
+    match (
+        backend.constant(condition),
+        backend.constant(then),
+        backend.constant(otherwise),
+    ) {
+        (Some(true), _, _) => Some(then.clone()),
+        (Some(false), _, _) => Some(otherwise.clone()),
+        (None, Some(x), Some(y)) if x == y => Some(backend.trivial(x)),
+        (None, Some(true), Some(false)) => Some(condition.clone()),
+        (None, Some(false), Some(true)) => Some(backend.not(condition)),
+        (None, Some(true), None) => Some(backend.or(condition, otherwise)),
+        (None, Some(false), None) => Some(backend.and(&backend.not(condition), otherwise)),
+        (None, None, Some(true)) => Some(backend.or(&backend.not(condition), then)),
+        (None, None, Some(false)) => Some(backend.and(condition, then)),
+        (None, None, None) => None,
+        (None, Some(_), Some(_)) => unreachable!("Both branches are known"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ClientBackend;
+    use crate::counting::CountingBackend;
+
+    #[test]
+    fn test_folding() {
+        let backend = CountingBackend::default();
+        let bits = [
+            backend.trivial(false),
+            backend.trivial(true),
+            backend.encrypt(false),
+            backend.encrypt(true),
+        ];
+
+        for x in bits.iter() {
+            for y in bits.iter() {
+                let (x_value, y_value) = (backend.decrypt(x), backend.decrypt(y));
+                assert_eq!(backend.decrypt(&backend.and(x, y)), x_value & y_value);
+                assert_eq!(backend.decrypt(&backend.or(x, y)), x_value | y_value);
+                assert_eq!(backend.decrypt(&backend.xor(x, y)), x_value ^ y_value);
+
+                for z in bits.iter() {
+                    let expected = if x_value { y_value } else { backend.decrypt(z) };
+                    assert_eq!(backend.decrypt(&backend.mux(x, y, z)), expected);
+                }
+            }
+        }
+
+        // Only the gates of encrypted operands bootstrap
+        backend.reset();
+        let (_, counts) = backend.measure(|| backend.xor(&bits[1], &bits[2]));
+        assert_eq!((counts.xor, counts.not, counts.bootstraps), (0, 1, 0));
+
+        let (_, counts) = backend.measure(|| backend.mux(&bits[2], &bits[3], &bits[0]));
+        assert_eq!((counts.mux, counts.and, counts.bootstraps), (0, 1, 1));
+    }
+}
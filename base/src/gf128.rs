@@ -0,0 +1,325 @@
+use std::sync::LazyLock;
+
+use rayon::prelude::*;
+
+use crate::backend::Backend;
+
+/// The reduction polynomial without its `x^128` term, in the clear representation
+const R: u128 = 0xe1 << 120;
+
+/// The number of powers computed in the same batch by `powers`, which bounds the `128 * 128` ands of the products held at once
+const POWERS_PER_BATCH: usize = 16;
+
+/// `x^k` modulo the reduction polynomial, for every degree of an unreduced product
+static REDUCTIONS: LazyLock<[u128; 255]> = LazyLock::new(|| {
+    let mut reductions = [1 << 127; 255];
+    for k in 1..255 {
+        reductions[k] = mul_x(reductions[k - 1]);
+    }
+    reductions
+});
+
+fn mul_x(x: u128) -> u128 {
+    if x & 1 == 0 { x >> 1 } else { (x >> 1) ^ R }
+}
+
+fn coefficient(x: u128, i: usize) -> bool {
+    (x >> (127 - i)) & 1 != 0
+}
+
+/// Multiplies two clear elements, with the right shift algorithm of the specification.
+pub fn mul_clear(x: u128, y: u128) -> u128 {
+    let mut v = y;
+    let mut z = 0;
+    for i in 0..128 {
+        if coefficient(x, i) {
+            z ^= v;
+        }
+        v = mul_x(v);
+    }
+    z
+}
+
+/// Xors the bits of every group, level by level, each level being a single parallel batch for all the groups. An empty group is `None`.
+fn xor_sums<B: Backend>(mut groups: Vec<Vec<B::Bit>>, server_key: &B) -> Vec<Option<B::Bit>> {
+    while groups.iter().any(|group| group.len() > 1) {
+        groups = groups
+            .par_iter()
+            .map(|group| {
+                group
+                    .par_chunks(2)
+                    .map(|pair| match pair {
+                        [lhs, rhs] => server_key.xor(lhs, rhs),
+                        [bit] => bit.clone(),
+                        _ => unreachable!(),
+                    })
+                    .collect()
+            })
+            .collect();
+    }
+    groups
+        .into_iter()
+        .map(|group| group.into_iter().next())
+        .collect()
+}
+
+/// Reduces unreduced products, given as the 255 coefficients of each of them, `None` being a zero coefficient.
+fn reduce<B: Backend>(coefficients: &[Option<B::Bit>], server_key: &B) -> Vec<Vec<B::Bit>> {
+    let groups: Vec<Vec<B::Bit>> = coefficients
+        .chunks(255)
+        .flat_map(|coefficients| {
+            (0..128).map(|m| {
+                coefficients
+                    .iter()
+                    .zip(REDUCTIONS.iter())
+                    .filter(|(_, reduction)| coefficient(**reduction, m))
+                    .filter_map(|(bit, _)| bit.clone())
+                    .collect()
+            })
+        })
+        .collect();
+
+    let zero = server_key.trivial(false);
+    xor_sums(groups, server_key)
+        .into_iter()
+        .map(|bit| bit.unwrap_or_else(|| zero.clone()))
+        .collect::<Vec<_>>()
+        .chunks(128)
+        .map(<[_]>::to_vec)
+        .collect()
+}
+
+/// Two encrypted elements to multiply
+pub type Pair<'a, T> = (&'a [T], &'a [T]);
+
+fn assert_element<T>(x: &[T]) {
+    assert!(x.len() == 128, "An element of GF(2^128) has 128 bits !");
+}
+
+/// Adds encrypted elements, with a balanced xor tree per bit.
+pub fn sum<B: Backend>(elements: &[Vec<B::Bit>], server_key: &B) -> Vec<B::Bit> {
+    elements.iter().for_each(|x| assert_element(x));
+    let groups: Vec<Vec<B::Bit>> = (0..128)
+        .map(|bit| elements.iter().map(|x| x[bit].clone()).collect())
+        .collect();
+    xor_sums(groups, server_key)
+        .into_iter()
+        .map(|bit| bit.unwrap_or_else(|| server_key.trivial(false)))
+        .collect()
+}
+
+/// Multiplies pairs of encrypted elements of GF(2^128), the field of GHASH in GCM (NIST SP 800-38D), all in the same parallel batches.
+///
+/// An element is 128 bits, the bit `i` being the coefficient of `x^i`, so that a block of GCM is an element in the order of
+/// `State::to_bits`: the most significant bit of its first byte is the coefficient of `x^0`. The clear elements are the blocks read as
+/// big endian `u128`, the coefficient of `x^i` being the bit `127 - i`, as in the specification.
+///
+/// A product is the 128 x 128 ands of the coefficients, summed by degree with balanced xor trees, then reduced modulo
+/// `x^128 + x^7 + x^2 + x + 1`, which is linear: every bit of the result is the xor of the coefficients whose power of `x` reduces to a
+/// polynomial holding that bit. The product of an encrypted element by a clear one, and the square of an encrypted element, are linear
+/// too, so they are xors only.
+pub fn mul_batch<B: Backend>(pairs: &[Pair<B::Bit>], server_key: &B) -> Vec<Vec<B::Bit>> {
+    pairs.iter().for_each(|(x, y)| {
+        assert_element(x);
+        assert_element(y);
+    });
+
+    // The and of the coefficients `i` and `j` is at `i * 128 + j`
+    let ands: Vec<Vec<B::Bit>> = pairs
+        .par_iter()
+        .map(|(x, y)| {
+            (0..128 * 128)
+                .into_par_iter()
+                .map(|n| server_key.and(&x[n / 128], &y[n % 128]))
+                .collect()
+        })
+        .collect();
+
+    // The ands are moved to the group of their degree `i + j`, in the order of `i`
+    let groups: Vec<Vec<B::Bit>> = ands
+        .into_iter()
+        .flat_map(|ands| {
+            let mut groups: Vec<Vec<B::Bit>> = (0..255_usize)
+                .map(|k| Vec::with_capacity(128 - k.abs_diff(127)))
+                .collect();
+            for (n, bit) in ands.into_iter().enumerate() {
+                groups[n / 128 + n % 128].push(bit);
+            }
+            groups
+        })
+        .collect();
+    reduce(&xor_sums(groups, server_key), server_key)
+}
+
+pub fn mul<B: Backend>(x: &[B::Bit], y: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+    mul_batch(&[(x, y)], server_key).pop().unwrap()
+}
+
+/// Multiplies encrypted elements by clear ones, e.g. the blocks of a public ciphertext by an encrypted hash key, with xors only.
+pub fn mul_clear_batch<B: Backend>(
+    pairs: &[(u128, &[B::Bit])],
+    server_key: &B,
+) -> Vec<Vec<B::Bit>> {
+    let groups: Vec<Vec<B::Bit>> = pairs
+        .iter()
+        .flat_map(|&(x, y)| {
+            assert_element(y);
+            (0..255_usize).map(move |k| {
+                (k.saturating_sub(127)..=k.min(127))
+                    .filter(|&i| coefficient(x, i))
+                    .map(|i| y[k - i].clone())
+                    .collect()
+            })
+        })
+        .collect();
+    reduce(&xor_sums(groups, server_key), server_key)
+}
+
+/// Squares encrypted elements with xors only, the square of a sum being the sum of the squares in characteristic 2.
+pub fn square_batch<B: Backend>(elements: &[&[B::Bit]], server_key: &B) -> Vec<Vec<B::Bit>> {
+    let coefficients: Vec<Option<B::Bit>> = elements
+        .iter()
+        .flat_map(|x| {
+            assert_element(x);
+            (0..255).map(move |k| (k % 2 == 0).then(|| x[k / 2].clone()))
+        })
+        .collect();
+    reduce(&coefficients, server_key)
+}
+
+/// The powers `h^1` to `h^n` of an encrypted element.
+///
+/// The powers are computed by ranges of doubling exponents, `h^2k` as the square of `h^k` and `h^(2k+1)` as the product of `h^k` and
+/// `h^(k+1)`, so that the depth grows with `log2(n)` multiplications. A range is computed in batches of `POWERS_PER_BATCH` powers, so that
+/// the ands of the products in flight do not grow with `n`.
+pub fn powers<B: Backend>(h: &[B::Bit], n: usize, server_key: &B) -> Vec<Vec<B::Bit>> {
+    assert_element(h);
+    // `powers[k]` is `h^(k+1)`
+    let mut powers = vec![h.to_vec()];
+    while powers.len() < n {
+        let range: Vec<usize> = (powers.len() + 1..=(2 * powers.len()).min(n)).collect();
+        for batch in range.chunks(POWERS_PER_BATCH) {
+            let next = next_powers(&powers, batch, server_key);
+            powers.extend(next);
+        }
+    }
+    powers
+}
+
+/// The powers of the exponents, from the powers of at least half of the largest of them.
+fn next_powers<B: Backend>(
+    powers: &[Vec<B::Bit>],
+    exponents: &[usize],
+    server_key: &B,
+) -> Vec<Vec<B::Bit>> {
+    let power = |k: usize| powers[k - 1].as_slice();
+
+    let (even, odd): (Vec<usize>, Vec<usize>) = exponents.iter().partition(|&&k| k % 2 == 0);
+    let squares: Vec<&[B::Bit]> = even.iter().map(|&k| power(k / 2)).collect();
+    let products: Vec<Pair<B::Bit>> = odd
+        .iter()
+        .map(|&k| (power(k / 2), power(k / 2 + 1)))
+        .collect();
+    let (squares, products) = rayon::join(
+        || square_batch(&squares, server_key),
+        || mul_batch(&products, server_key),
+    );
+
+    let (mut squares, mut products) = (squares.into_iter(), products.into_iter());
+    exponents
+        .iter()
+        .map(|&k| {
+            if k % 2 == 0 {
+                squares.next().unwrap()
+            } else {
+                products.next().unwrap()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ClearBackend;
+    use crate::counting::CountingBackend;
+
+    fn to_bits(x: u128) -> Vec<bool> {
+        (0..128).map(|i| coefficient(x, i)).collect()
+    }
+
+    fn from_bits(bits: &[bool]) -> u128 {
+        bits.iter()
+            .enumerate()
+            .fold(0, |acc, (i, &bit)| acc | ((bit as u128) << (127 - i)))
+    }
+
+    #[test]
+    fn test_mul_clear() {
+        // H and the GHASH of the test case 2 of the GCM specification, with the key and the block 0
+        let h = 0x66e94bd4_ef8a2c3b_884cfa59_ca342b2e;
+        let x = 0x0388dace_60b6a392_f328c2b9_71b2fe78;
+        assert_eq!(mul_clear(x, h), 0x5e2ec746_91706288_2c85b068_5353deb7);
+
+        // x^0 is the neutral element
+        assert_eq!(mul_clear(1 << 127, h), h);
+        assert_eq!(mul_clear(h, x), mul_clear(x, h));
+    }
+
+    #[test]
+    fn test_mul_encrypted() {
+        let backend = ClearBackend;
+        let values = [
+            0x66e94bd4_ef8a2c3b_884cfa59_ca342b2e,
+            0x0388dace_60b6a392_f328c2b9_71b2fe78,
+            0xfeffe992_8665731c_6d6a8f94_67308308,
+            1,
+            u128::MAX,
+        ];
+
+        for &x in &values {
+            for &y in &values {
+                let product = mul(&to_bits(x), &to_bits(y), &backend);
+                assert_eq!(from_bits(&product), mul_clear(x, y));
+
+                let product = mul_clear_batch(&[(x, to_bits(y).as_slice())], &backend);
+                assert_eq!(from_bits(&product[0]), mul_clear(x, y));
+            }
+            let square = square_batch(&[to_bits(x).as_slice()], &backend);
+            assert_eq!(from_bits(&square[0]), mul_clear(x, x));
+        }
+
+        let h = values[2];
+        // The range of the exponents 17 to 32 is a full batch, and the next one is cut at 40
+        let powers = powers(&to_bits(h), 40, &backend);
+        assert_eq!(powers.len(), 40);
+        let mut expected = h;
+        for power in powers {
+            assert_eq!(from_bits(&power), expected);
+            expected = mul_clear(expected, h);
+        }
+
+        let elements: Vec<_> = values.iter().map(|&x| to_bits(x)).collect();
+        let expected = values.iter().fold(0, |acc, x| acc ^ x);
+        assert_eq!(from_bits(&sum(&elements, &backend)), expected);
+    }
+
+    #[test]
+    fn test_mul_cost() {
+        let backend = CountingBackend::default();
+        let x: Vec<_> = (0..128).map(|_| backend.input(false)).collect();
+        let y: Vec<_> = (0..128).map(|_| backend.input(true)).collect();
+
+        let (_, counts) = backend.measure(|| mul(&x, &y, &backend));
+        assert_eq!(counts.and, 128 * 128);
+        // An and, the xor trees of the coefficients of degree 127, then of the reduction
+        assert!(counts.depth <= 1 + 7 + 3);
+
+        // The square and the product by a clear element have no and
+        backend.reset();
+        let (_, counts) = backend.measure(|| square_batch(&[x.as_slice()], &backend));
+        assert_eq!(counts.and, 0);
+        let (_, counts) = backend.measure(|| mul_clear_batch(&[(R, y.as_slice())], &backend));
+        assert_eq!(counts.and, 0);
+    }
+}
@@ -1,173 +1,273 @@
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
 use tfhe::boolean::prelude::*;
 
+use crate::backend::{Backend, ClientBackend};
 use crate::primitive::*;
 use crate::sbox::*;
 
 const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1B, 0x36];
 
-/// This represents a key in AES-128. The key is represented in a transposed manner, and all algorithms are implemented as such.
-/// For more details for each algorithm, refer to the [Efficient Implementation of AES in 32 bit systems](https://link.springer.com/content/pdf/10.1007/3-540-36400-5_13.pdf) paper.
-
-#[derive(Clone, Debug)]
-pub struct Key {
-    pub data: [FHEByte; 16],
+/// The three key lengths of AES as specified in FIPS-197, which fix the number of rounds of the cipher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeySize {
+    Aes128,
+    Aes192,
+    Aes256,
 }
 
-impl Key {
-    pub fn from_u128_enc(value: u128, client_key: &ClientKey) -> Self {
-        let mut data: [FHEByte; 16] = (0..16)
-            .rev()
-            .map(|i| FHEByte::from_u8_enc(&{ ((value >> 8 * i) & 0xFF) as u8 }, client_key))
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+impl KeySize {
+    pub fn from_bytes(bytes: usize) -> Self {
+        match bytes {
+            16 => KeySize::Aes128,
+            24 => KeySize::Aes192,
+            32 => KeySize::Aes256,
+            _ => panic!("AES keys are 16, 24 or 32 bytes long, got {} bytes !", bytes),
+        }
+    }
 
-        // TRANSPOSE INPUT DATA
-        data.swap(1, 4);
-        data.swap(2, 8);
-        data.swap(3, 12);
+    /// The key size whose key expansion produces the given number of round keys (Nr + 1)
+    pub fn from_round_keys(round_keys: usize) -> Self {
+        match round_keys {
+            11 => KeySize::Aes128,
+            13 => KeySize::Aes192,
+            15 => KeySize::Aes256,
+            _ => panic!("AES uses 11, 13 or 15 round keys, got {} !", round_keys),
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        4 * self.words()
+    }
 
-        data.swap(6, 9);
-        data.swap(7, 13);
+    /// The number of 32-bit words (Nk) in the cipher key
+    pub fn words(&self) -> usize {
+        match self {
+            KeySize::Aes128 => 4,
+            KeySize::Aes192 => 6,
+            KeySize::Aes256 => 8,
+        }
+    }
 
-        data.swap(11, 14);
+    /// The number of rounds (Nr) of the cipher
+    pub fn rounds(&self) -> usize {
+        self.words() + 6
+    }
 
-        Self { data }
+    pub fn round_keys(&self) -> usize {
+        self.rounds() + 1
     }
+}
 
-    pub fn from_u128_clear(value: u128, server_key: &ServerKey) -> Self {
-        let mut data: [FHEByte; 16] = (0..16)
-            .rev()
-            .map(|i| FHEByte::from_u8_clear(&{ ((value >> 8 * i) & 0xFF) as u8 }, server_key))
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+/// Transposes a key given as a sequence of 4-byte words (as in FIPS-197) into a matrix of 4 rows and `columns` columns stored row by row.
+fn transpose<T: Clone>(data: &[T], columns: usize) -> Vec<T> {
+    (0..4)
+        .flat_map(|row| (0..columns).map(move |column| data[4 * column + row].clone()))
+        .collect()
+}
 
-        // TRANSPOSE INPUT DATA
-        data.swap(1, 4);
-        data.swap(2, 8);
-        data.swap(3, 12);
+/// Inverse of `transpose`, turning the rows back into a sequence of 4-byte words.
+fn untranspose<T: Clone>(data: &[T], columns: usize) -> Vec<T> {
+    (0..columns)
+        .flat_map(|column| (0..4).map(move |row| data[row * columns + column].clone()))
+        .collect()
+}
 
-        data.swap(6, 9);
-        data.swap(7, 13);
+/// This represents a key in AES-128, AES-192 or AES-256. The key is represented in a transposed manner, and all algorithms are implemented as such.
+/// A cipher key of Nk words is stored as 4 rows of Nk bytes, while the round keys generated from it are always 128-bit keys.
+/// For more details for each algorithm, refer to the [Efficient Implementation of AES in 32 bit systems](https://link.springer.com/content/pdf/10.1007/3-540-36400-5_13.pdf) paper.
 
-        data.swap(11, 14);
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Key<T = Ciphertext> {
+    pub data: Vec<FHEByte<T>>,
+}
+
+impl<T: Clone + Send + Sync + Debug> Key<T> {
+    pub fn from_u128_enc<C: ClientBackend<Bit = T>>(value: u128, client_key: &C) -> Self {
+        Self::from_u8_enc(&value.to_be_bytes(), client_key)
+    }
 
-        Self { data }
+    pub fn from_u128_clear<B: Backend<Bit = T>>(value: u128, server_key: &B) -> Self {
+        Self::from_u8_clear(&value.to_be_bytes(), server_key)
     }
 
-    pub fn from_u8_enc(data: &[u8; 16], client_key: &ClientKey) -> Self {
-        let mut data = data.map(|value| FHEByte::from_u8_enc(&value, client_key));
+    pub fn from_u8_enc<C: ClientBackend<Bit = T>>(data: &[u8], client_key: &C) -> Self {
+        let key_size = KeySize::from_bytes(data.len());
+        let data: Vec<_> = data
+            .iter()
+            .map(|value| FHEByte::from_u8_enc(value, client_key))
+            .collect();
 
         // TRANSPOSE INPUT DATA
-        data.swap(1, 4);
-        data.swap(2, 8);
-        data.swap(3, 12);
+        Self {
+            data: transpose(&data, key_size.words()),
+        }
+    }
 
-        data.swap(6, 9);
-        data.swap(7, 13);
+    pub fn from_u8_clear<B: Backend<Bit = T>>(data: &[u8], server_key: &B) -> Self {
+        let key_size = KeySize::from_bytes(data.len());
+        let data: Vec<_> = data
+            .iter()
+            .map(|value| FHEByte::from_u8_clear(value, server_key))
+            .collect();
 
-        data.swap(11, 14);
-        Self { data }
+        // TRANSPOSE INPUT DATA
+        Self {
+            data: transpose(&data, key_size.words()),
+        }
     }
 
-    pub fn from_u8_clear(data: &[u8; 16], server_key: &ServerKey) -> Self {
-        let mut data = data.map(|value| FHEByte::from_u8_clear(&value, server_key));
+    /// Creates a key from the bits of its 16, 24 or 32 bytes, in the order of `from_u8_enc` and each most significant bit first.
+    pub fn from_bits(bits: &[T]) -> Self {
+        assert!(
+            bits.len() % 8 == 0,
+            "A key is made of whole bytes, got {} bits !",
+            bits.len()
+        );
+        let key_size = KeySize::from_bytes(bits.len() / 8);
+        let data: Vec<_> = bits.chunks(8).map(FHEByte::from_bits).collect();
 
         // TRANSPOSE INPUT DATA
-        data.swap(1, 4);
-        data.swap(2, 8);
-        data.swap(3, 12);
+        Self {
+            data: transpose(&data, key_size.words()),
+        }
+    }
 
-        data.swap(6, 9);
-        data.swap(7, 13);
+    /// The bits of the key, in the order of `from_bits`
+    pub fn to_bits(&self) -> Vec<T> {
+        untranspose(&self.data, self.key_size().words())
+            .iter()
+            .flat_map(|byte| byte.bits().cloned())
+            .collect()
+    }
 
-        data.swap(11, 14);
-        Self { data }
+    pub(crate) fn is_well_formed(&self) -> bool {
+        [16, 24, 32].contains(&self.data.len())
+            && self.data.iter().all(|byte| byte.is_well_formed())
     }
 
-    pub fn generate_next_key_in_place(&mut self, rcon: &u8, server_key: &ServerKey) {
-        let mut temp: Vec<_> = [7, 11, 15, 3]
-            .into_par_iter()
-            .map(|i| self.data[i].sub_byte(server_key))
-            .collect();
-        temp[0].xor_in_place(&FHEByte::from_u8_clear(rcon, server_key), server_key);
-
-        self.data
-            .par_iter_mut()
-            .enumerate()
-            .filter(|(i, _)| i % 4 == 0)
-            .for_each_with(server_key, |server_key, (i, x)| {
-                x.xor_in_place(&temp[i / 4], server_key)
-            });
-
-        self.data
-            .par_chunks_mut(2)
-            .enumerate()
-            .filter(|(i, _)| i % 2 == 0)
-            .for_each_with(server_key, |server_key, (_, x)| {
-                let (a, b) = x.split_at_mut(1);
-                b[0].xor_in_place(&a[0], server_key)
-            });
-
-        self.data[1..]
-            .par_chunks_mut(2)
-            .enumerate()
-            .filter(|(i, _)| i % 2 == 0)
-            .for_each_with(server_key, |server_key, (_, x)| {
-                let (a, b) = x.split_at_mut(1);
-                b[0].xor_in_place(&a[0], server_key)
-            });
-
-        self.data
-            .par_chunks_mut(2)
-            .enumerate()
-            .filter(|(i, _)| i % 2 == 1)
-            .for_each_with(server_key, |server_key, (_, x)| {
-                let (a, b) = x.split_at_mut(1);
-                b[0].xor_in_place(&a[0], server_key)
-            });
+    pub fn key_size(&self) -> KeySize {
+        KeySize::from_bytes(self.data.len())
+    }
+
+    /// This method computes the next round key of an AES-128 key in place, using the transposed representation.
+    pub fn generate_next_key_in_place<B: Backend<Bit = T>>(&mut self, rcon: &u8, server_key: &B) {
+        assert!(
+            self.key_size() == KeySize::Aes128,
+            "The in place key schedule is only defined for AES-128 keys !"
+        );
+
+        server_key.install(|| {
+            let last_column = [7, 11, 15, 3].map(|i| self.data[i].clone());
+            let mut temp = FHEByte::sub_byte_batch(&last_column, server_key);
+            temp[0].xor_in_place(&FHEByte::from_u8_clear(rcon, server_key), server_key);
+
+            self.data
+                .par_iter_mut()
+                .enumerate()
+                .filter(|(i, _)| i % 4 == 0)
+                .for_each_with(server_key, |server_key, (i, x)| {
+                    x.xor_in_place(&temp[i / 4], server_key)
+                });
+
+            self.data
+                .par_chunks_mut(2)
+                .enumerate()
+                .filter(|(i, _)| i % 2 == 0)
+                .for_each_with(server_key, |server_key, (_, x)| {
+                    let (a, b) = x.split_at_mut(1);
+                    b[0].xor_in_place(&a[0], server_key)
+                });
+
+            self.data[1..]
+                .par_chunks_mut(2)
+                .enumerate()
+                .filter(|(i, _)| i % 2 == 0)
+                .for_each_with(server_key, |server_key, (_, x)| {
+                    let (a, b) = x.split_at_mut(1);
+                    b[0].xor_in_place(&a[0], server_key)
+                });
+
+            self.data
+                .par_chunks_mut(2)
+                .enumerate()
+                .filter(|(i, _)| i % 2 == 1)
+                .for_each_with(server_key, |server_key, (_, x)| {
+                    let (a, b) = x.split_at_mut(1);
+                    b[0].xor_in_place(&a[0], server_key)
+                });
+        })
     }
 
-    pub fn decrypt_to_u8(&self, client_key: &ClientKey) -> [u8; 16] {
-        let mut decrypted_data: [u8; 16] = self
+    pub fn decrypt_to_u8<C: ClientBackend<Bit = T>>(&self, client_key: &C) -> Vec<u8> {
+        let decrypted_data: Vec<u8> = self
             .data
             .iter()
-            .map(|x| {
-                (&x.decrypt(client_key))
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(i, &x)| x.then(|| 2_u8.pow(8 - (i + 1) as u32)))
-                    .sum()
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+            .map(|x| x.decrypt_to_u8(client_key))
+            .collect();
 
         // TRANSPOSE OUTPUT DATA
-        decrypted_data.swap(1, 4);
-        decrypted_data.swap(2, 8);
-        decrypted_data.swap(3, 12);
-
-        decrypted_data.swap(6, 9);
-        decrypted_data.swap(7, 13);
-
-        decrypted_data.swap(11, 14);
+        untranspose(&decrypted_data, self.key_size().words())
+    }
 
-        decrypted_data
+    /// This method performs the key expansion for the given key in the FHE context, and returns all round keys (11, 13 or 15 depending on the key size).
+    pub fn generate_round_keys<B: Backend<Bit = T>>(&self, server_key: &B) -> Vec<Key<T>> {
+        server_key.install(|| match self.key_size() {
+            KeySize::Aes128 => {
+                let mut keys = vec![self.clone()];
+                for i in 0..10 {
+                    let mut key = keys[i].clone();
+                    key.generate_next_key_in_place(&RCON[i], &server_key);
+                    keys.push(key);
+                }
+
+                keys
+            }
+            key_size => self.generate_round_keys_words(key_size, server_key),
+        })
     }
 
-    /// This method performs the key expansion for the given key in the FHE context, and returns all keys as an array of 11 keys.
-    pub fn generate_round_keys(&self, server_key: &ServerKey) -> [Key; 11] {
-        let mut keys = vec![self.clone()];
-        for i in 0..10 {
-            let mut key = keys[i].clone();
-            key.generate_next_key_in_place(&RCON[i], &server_key);
-            keys.push(key);
+    /// Word by word key expansion of FIPS-197, used for AES-192 and AES-256 where a round key does not line up with a cipher key.
+    fn generate_round_keys_words<B: Backend<Bit = T>>(
+        &self,
+        key_size: KeySize,
+        server_key: &B,
+    ) -> Vec<Key<T>> {
+        let nk = key_size.words();
+        let mut words: Vec<Vec<FHEByte<T>>> = untranspose(&self.data, nk)
+            .chunks(4)
+            .map(|word| word.to_vec())
+            .collect();
+
+        for i in nk..4 * key_size.round_keys() {
+            let mut temp = words[i - 1].clone();
+
+            if i % nk == 0 {
+                temp.rotate_left(1);
+                temp = FHEByte::sub_byte_batch(&temp, server_key);
+                temp[0].xor_in_place(
+                    &FHEByte::from_u8_clear(&RCON[i / nk - 1], server_key),
+                    server_key,
+                );
+            } else if nk > 6 && i % nk == 4 {
+                temp = FHEByte::sub_byte_batch(&temp, server_key);
+            }
+
+            temp.par_iter_mut()
+                .zip(words[i - nk].par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| {
+                    x.xor_in_place(y, server_key)
+                });
+            words.push(temp);
         }
 
-        keys.try_into().expect("There should be 11 Keys")
+        words
+            .chunks(4)
+            .map(|round_words| Key {
+                data: transpose(&round_words.concat(), 4),
+            })
+            .collect()
     }
 }
 
@@ -179,34 +279,34 @@ fn rot_word(word: &[u8; 4]) -> [u8; 4] {
     [word[1], word[2], word[3], word[0]]
 }
 
-/// This method performs the key expansion for the given key in the clear, and returns all keys as an array of 11 keys.
-pub fn key_expansion_clear(key: &[u8; 16]) -> [[u8; 16]; 11] {
-    let mut round_keys = [[0u8; 16]; 11];
+/// This method performs the key expansion for the given 16, 24 or 32 byte key in the clear, and returns all round keys (11, 13 or 15 depending on the key size).
+pub fn key_expansion_clear(key: &[u8]) -> Vec<[u8; 16]> {
+    let key_size = KeySize::from_bytes(key.len());
+    let nk = key_size.words();
 
-    // Round 0 Key (original key)
-    round_keys[0] = *key;
+    let mut words: Vec<[u8; 4]> = key
+        .chunks(4)
+        .map(|word| word.try_into().unwrap())
+        .collect();
 
-    for round in 1..=10 {
-        let mut temp = [
-            round_keys[round - 1][12],
-            round_keys[round - 1][13],
-            round_keys[round - 1][14],
-            round_keys[round - 1][15],
-        ];
-
-        temp = sub_word(&rot_word(&temp));
-        temp[0] ^= RCON[round - 1];
+    for i in nk..4 * key_size.round_keys() {
+        let mut temp = words[i - 1];
 
-        for i in 0..4 {
-            round_keys[round][i] = round_keys[round - 1][i] ^ temp[i];
+        if i % nk == 0 {
+            temp = sub_word(&rot_word(&temp));
+            temp[0] ^= RCON[i / nk - 1];
+        } else if nk > 6 && i % nk == 4 {
+            temp = sub_word(&temp);
         }
 
-        for i in 4..16 {
-            round_keys[round][i] = round_keys[round - 1][i] ^ round_keys[round][i - 4];
-        }
+        let prev = words[i - nk];
+        words.push([0, 1, 2, 3].map(|j| prev[j] ^ temp[j]));
     }
 
-    round_keys
+    words
+        .chunks(4)
+        .map(|round_words| round_words.concat().try_into().unwrap())
+        .collect()
 }
 
 #[cfg(test)]
@@ -215,6 +315,7 @@ mod tests {
     use std::time::Instant;
 
     use super::*;
+    use crate::backend::ClearBackend;
     use tfhe::boolean::gen_keys;
 
     #[test]
@@ -245,6 +346,86 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_key_expansion_192() {
+        // This test follows the key expansion example of Appendix A.2 of the FIPS-197 standard
+        let key = [
+            0x8e, 0x73, 0xb0, 0xf7, 0xda, 0x0e, 0x64, 0x52, 0xc8, 0x10, 0xf3, 0x2b, 0x80, 0x90,
+            0x79, 0xe5, 0x62, 0xf8, 0xea, 0xd2, 0x52, 0x2c, 0x6b, 0x7b,
+        ];
+        let round_keys = key_expansion_clear(&key);
+
+        assert_eq!(round_keys.len(), 13);
+        assert_eq!(round_keys[0][..], key[..16]);
+        assert_eq!(
+            round_keys[12],
+            [
+                0xe9, 0x8b, 0xa0, 0x6f, 0x44, 0x8c, 0x77, 0x3c, 0x8e, 0xcc, 0x72, 0x04, 0x01, 0x00,
+                0x22, 0x02
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_expansion_256() {
+        // This test follows the key expansion example of Appendix A.3 of the FIPS-197 standard
+        let key = [
+            0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d,
+            0x77, 0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3,
+            0x09, 0x14, 0xdf, 0xf4,
+        ];
+        let round_keys = key_expansion_clear(&key);
+
+        assert_eq!(round_keys.len(), 15);
+        assert_eq!(round_keys[1][..], key[16..]);
+        assert_eq!(
+            round_keys[14],
+            [
+                0xfe, 0x48, 0x90, 0xd1, 0xe6, 0x18, 0x8d, 0x0b, 0x04, 0x6d, 0xf3, 0x44, 0x70, 0x6c,
+                0x63, 0x1e
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_schedule_256() {
+        let (client_key, server_key) = gen_keys();
+        let key_data: Vec<u8> = (0..32).collect();
+        let key = Key::from_u8_enc(&key_data, &client_key);
+
+        let keys = key.generate_round_keys(&server_key);
+        let clear_keys = key_expansion_clear(&key_data);
+
+        assert_eq!(keys.len(), 15);
+        for (key, clear_key) in keys.iter().zip(clear_keys.iter()) {
+            assert_eq!(key.decrypt_to_u8(&client_key), clear_key);
+        }
+    }
+
+    #[test]
+    fn test_key_schedule_clear() {
+        let backend = ClearBackend;
+
+        for key_bytes in [16, 24, 32] {
+            let key_data: Vec<u8> = (0..key_bytes).map(|x| (x * 0x1d + 0x2b) as u8).collect();
+            let key = Key::from_u8_enc(&key_data, &backend);
+
+            let keys = key.generate_round_keys(&backend);
+            let clear_keys = key_expansion_clear(&key_data);
+
+            assert_eq!(keys.len(), clear_keys.len());
+            for (key, clear_key) in keys.iter().zip(clear_keys.iter()) {
+                assert_eq!(key.decrypt_to_u8(&backend), clear_key);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_key_from_partial_byte() {
+        Key::from_bits(&[false; 129]);
+    }
+
     #[test]
     fn test_conversion_u8_u128() {
         let (client_key, _) = gen_keys();
@@ -1,12 +1,28 @@
 #![feature(iter_array_chunks)]
 #![feature(array_chunks)]
 
+pub mod backend;
 pub mod boolean_tree;
+pub mod boyar_peralta;
+pub mod context;
+pub mod counting;
+pub mod dag;
+pub mod folding;
+pub mod gf128;
 pub mod key_schedule;
+pub mod lut;
+pub mod mix_columns;
+pub mod mux_order;
+pub mod paar;
+pub mod plan;
 pub mod primitive;
 pub mod sbox;
+pub mod serialization;
 pub mod state;
 
+pub use backend::{Backend, ClearBackend, ClientBackend};
+pub use context::FheAesContext;
+pub use counting::CountingBackend;
 pub use key_schedule::Key;
 pub use primitive::FHEByte;
 pub use state::State;
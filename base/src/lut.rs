@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::backend::Backend;
+use crate::boolean_tree::{ExprArena, ExprId};
+use crate::plan::EvaluationPlan;
+
+/// LookupTable is a clear table of `2^input_bits` entries of `output_bits` bits, evaluated on encrypted bits as the S-Box is.
+///
+/// Every output bit is the reduced mux tree of its column of the table, and the 8 or fewer trees are compiled into a single
+/// `EvaluationPlan`, sharing their common sub-expressions. Compiling is the expensive part, so `compile` returns a `CompiledTable`, which
+/// is held by the caller and evaluates the table as many times as needed, as `FheAesContext` holds the S-Box plans.
+///
+/// The inputs are at most 8 bits, the operands of the mux trees, and the outputs at most 64 bits, the entries of the table.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LookupTable {
+    input_bits: u8,
+    output_bits: u8,
+    entries: Vec<u64>,
+}
+
+impl LookupTable {
+    pub fn new(input_bits: u8, output_bits: u8, entries: Vec<u64>) -> Self {
+        assert!(
+            (1..=8).contains(&input_bits),
+            "A lookup table has 1 to 8 input bits !"
+        );
+        assert!(
+            (1..=64).contains(&output_bits),
+            "A lookup table has 1 to 64 output bits !"
+        );
+        assert!(
+            entries.len() == 1 << input_bits,
+            "A table of {} input bits has {} entries !",
+            input_bits,
+            1 << input_bits
+        );
+        assert!(
+            entries
+                .iter()
+                .all(|&entry| output_bits == 64 || entry >> output_bits == 0),
+            "The entries of the table do not fit in {} bits !",
+            output_bits
+        );
+
+        LookupTable {
+            input_bits,
+            output_bits,
+            entries,
+        }
+    }
+
+    /// A table of `input_bits` bits to a single bit, e.g. a character class.
+    pub fn from_predicate(input_bits: u8, predicate: impl Fn(u64) -> bool) -> Self {
+        let entries = (0..1 << input_bits).map(|x| predicate(x) as u64).collect();
+        Self::new(input_bits, 1, entries)
+    }
+
+    pub fn input_bits(&self) -> u8 {
+        self.input_bits
+    }
+
+    pub fn output_bits(&self) -> u8 {
+        self.output_bits
+    }
+
+    pub fn get(&self, x: usize) -> u64 {
+        self.entries[x]
+    }
+
+    /// Compiles the reduced mux trees of the output bits, most significant first, into the plan of the `CompiledTable`.
+    ///
+    /// As in `sbox::bit_x_s_box`, the truth tables are reversed so that the first level of the trees selects the odd entries on Bit0.
+    pub fn compile(&self) -> CompiledTable {
+        let mut arena = ExprArena::new();
+        let outputs: Vec<ExprId> = (0..self.output_bits)
+            .rev()
+            .map(|position| {
+                let items: Vec<ExprId> = self
+                    .entries
+                    .iter()
+                    .rev()
+                    .map(|&entry| arena.operand(((entry >> position) & 1 != 0).into()))
+                    .collect();
+                arena.reduce_mux(&items)
+            })
+            .collect();
+        CompiledTable {
+            table: self.clone(),
+            plan: Arc::new(EvaluationPlan::compile_arena(&arena, &outputs)),
+        }
+    }
+}
+
+/// CompiledTable is a `LookupTable` along with its plan from `LookupTable::compile`, so that a table is always evaluated with its own plan.
+///
+/// Cloning it shares the plan.
+#[derive(Clone, Debug)]
+pub struct CompiledTable {
+    table: LookupTable,
+    plan: Arc<EvaluationPlan>,
+}
+
+impl CompiledTable {
+    pub fn table(&self) -> &LookupTable {
+        &self.table
+    }
+
+    pub fn plan(&self) -> &EvaluationPlan {
+        &self.plan
+    }
+
+    /// Evaluates the table on `input_bits` bits, most significant first, and returns the `output_bits` bits of the entry, most significant
+    /// first.
+    pub fn evaluate<B: Backend>(&self, bits: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+        self.evaluate_batch(&[bits.to_vec()], server_key)
+            .pop()
+            .unwrap()
+    }
+
+    /// Evaluates the table on many inputs at once, every stage of the plan being a single parallel batch as in `FHEByte::sub_byte_batch`.
+    pub fn evaluate_batch<B: Backend>(
+        &self,
+        inputs: &[Vec<B::Bit>],
+        server_key: &B,
+    ) -> Vec<Vec<B::Bit>> {
+        let input_bits = self.table.input_bits as usize;
+
+        // The plan indexes the bits from the least significant one, and reads 8 of them whatever the table: the bits above the inputs
+        // are never selected, so they are trivial
+        let padding = server_key.trivial(false);
+        let inputs: Vec<Vec<B::Bit>> = inputs
+            .iter()
+            .map(|bits| {
+                assert!(
+                    bits.len() == input_bits,
+                    "The table takes {} bits, got {} !",
+                    input_bits,
+                    bits.len()
+                );
+                bits.iter()
+                    .rev()
+                    .cloned()
+                    .chain(std::iter::repeat_n(padding.clone(), 8 - bits.len()))
+                    .collect()
+            })
+            .collect();
+
+        server_key.install(|| self.plan.evaluate_batch(&inputs, server_key))
+    }
+}
+
+impl From<[u8; 256]> for LookupTable {
+    fn from(table: [u8; 256]) -> Self {
+        Self::new(8, 8, table.iter().map(|&x| x as u64).collect())
+    }
+}
+
+/// Looks up tables whose entries are encrypted too, every index selecting an entry of the same table.
+///
+/// The leaves of a `BooleanExpr` are clear, so a secret table cannot be compiled into a plan: each output bit is instead the full mux tree
+/// over the encrypted entries, which cannot be reduced. A table of `2^n` entries of `m` bits costs `(2^n - 1) * m` muxes per index, and
+/// the trees are evaluated level by level, from the least significant bit of the index, each level being a single parallel batch for
+/// all the bits of all the indices, so the depth is `n` muxes. Trivially encrypted entries still fold in the backends which track them.
+///
+/// `indices` are `n` bits and `entries` are `m` bits, most significant first, and the entries of the indices are returned in order.
+pub fn lookup_encrypted_batch<B: Backend>(
+    indices: &[Vec<B::Bit>],
+    entries: &[Vec<B::Bit>],
+    server_key: &B,
+) -> Vec<Vec<B::Bit>> {
+    let n = entries.len().trailing_zeros() as usize;
+    assert!(
+        n > 0 && entries.len() == 1 << n,
+        "An encrypted table has a power of 2 entries, at least 2 !"
+    );
+    let m = entries[0].len();
+    assert!(
+        m > 0 && entries.iter().all(|entry| entry.len() == m),
+        "The entries of an encrypted table have the same number of bits !"
+    );
+    assert!(
+        indices.iter().all(|index| index.len() == n),
+        "The table is indexed with {} bits !",
+        n
+    );
+
+    // The entries of a level are flattened, the bit `bit` of the entry `entry` being at `entry * m + bit`
+    let table: Vec<B::Bit> = entries.concat();
+    let mut candidates: Vec<Vec<B::Bit>> = Vec::new();
+
+    server_key.install(|| {
+        for level in 0..n {
+            let width = (1 << (n - level - 1)) * m;
+            let selected: Vec<B::Bit> = (0..indices.len() * width)
+                .into_par_iter()
+                .map(|i| {
+                    let (index, slot) = (i / width, i % width);
+                    let (entry, bit) = (slot / m, slot % m);
+                    let source = if level == 0 {
+                        &table
+                    } else {
+                        &candidates[index]
+                    };
+                    // The bit `level` of the index, from the least significant one, selects the odd entries
+                    server_key.mux(
+                        &indices[index][n - 1 - level],
+                        &source[(2 * entry + 1) * m + bit],
+                        &source[2 * entry * m + bit],
+                    )
+                })
+                .collect();
+            candidates = selected.chunks(width).map(<[_]>::to_vec).collect();
+        }
+    });
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{ClearBackend, ClientBackend};
+    use crate::counting::CountingBackend;
+    use crate::primitive::FHEByte;
+    use crate::sbox::{INV_S_BOX_DATA, S_BOX_DATA};
+
+    #[test]
+    fn test_lookup_byte_clear() {
+        let backend = ClearBackend;
+        let case_folding: [u8; 256] = std::array::from_fn(|x| (x as u8).to_ascii_lowercase());
+        let bytes: Vec<_> = (0..=255_u8)
+            .map(|x| FHEByte::from_u8_enc(&x, &backend))
+            .collect();
+
+        for table in [case_folding, S_BOX_DATA] {
+            let compiled = LookupTable::from(table).compile();
+            let outputs = FHEByte::lookup_batch(&bytes, &compiled, &backend);
+            for (x, output) in outputs.iter().enumerate() {
+                assert_eq!(output.decrypt_to_u8(&backend), table[x]);
+            }
+        }
+
+        // A class of characters is a table of a single output bit
+        let is_digit = LookupTable::from_predicate(8, |x| (x as u8).is_ascii_digit()).compile();
+        for (x, byte) in bytes.iter().enumerate() {
+            let bits = byte.lookup_bits(&is_digit, &backend);
+            assert_eq!(bits.len(), 1);
+            assert_eq!(backend.decrypt(&bits[0]), (x as u8).is_ascii_digit());
+        }
+    }
+
+    #[test]
+    fn test_lookup_table_clear() {
+        let backend = ClearBackend;
+
+        // 4 bits to 6 bits, and 3 bits to 1 bit whose output is an input bit
+        let square = LookupTable::new(4, 6, (0..16).map(|x| (x * x) >> 2).collect());
+        let second_bit = LookupTable::from_predicate(3, |x| x & 2 != 0);
+
+        for table in [square, second_bit] {
+            let n = table.input_bits();
+            let compiled = table.compile();
+            for x in 0..1 << n {
+                let bits: Vec<bool> = (0..n).rev().map(|i| (x >> i) & 1 != 0).collect();
+                let result = compiled
+                    .evaluate(&bits, &backend)
+                    .iter()
+                    .fold(0, |acc, &bit| (acc << 1) | bit as u64);
+                assert_eq!(result, table.get(x));
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup_table_plan() {
+        // The plan of the S-Box table is the mux tree of `reduce_mux`
+        let s_box = LookupTable::from(S_BOX_DATA).compile();
+        let backend = CountingBackend::default();
+        let byte = FHEByte::from_u8_enc(&0x53, &backend);
+        let (_, counts) = backend.measure(|| byte.lookup(&s_box, &backend).decrypt_to_u8(&backend));
+        assert_eq!(counts.bootstraps, 749);
+    }
+
+    #[test]
+    fn test_lookup_encrypted_clear() {
+        let backend = ClearBackend;
+        let table: Vec<_> = INV_S_BOX_DATA
+            .iter()
+            .map(|x| FHEByte::from_u8_enc(x, &backend))
+            .collect();
+        let bytes: Vec<_> = (0..=255_u8)
+            .map(|x| FHEByte::from_u8_enc(&x, &backend))
+            .collect();
+
+        let outputs = FHEByte::lookup_encrypted_batch(&bytes, &table, &backend);
+        for (x, output) in outputs.iter().enumerate() {
+            assert_eq!(output.decrypt_to_u8(&backend), INV_S_BOX_DATA[x]);
+        }
+
+        // 2 bits to 3 bits
+        let entries: Vec<Vec<bool>> = [0b101, 0b011, 0b110, 0b000]
+            .iter()
+            .map(|x: &u8| (0..3).rev().map(|i| (x >> i) & 1 != 0).collect())
+            .collect();
+        let indices: Vec<Vec<bool>> = (0..4_u8).map(|x| vec![x & 2 != 0, x & 1 != 0]).collect();
+        assert_eq!(
+            lookup_encrypted_batch(&indices, &entries, &backend),
+            entries
+        );
+    }
+
+    #[test]
+    fn test_lookup_encrypted_cost() {
+        let backend = CountingBackend::default();
+        let table: Vec<_> = S_BOX_DATA
+            .iter()
+            .map(|x| FHEByte::from_u8_enc(x, &backend))
+            .collect();
+        let byte = FHEByte::from_u8_enc(&0x53, &backend);
+
+        let (output, counts) = backend.measure(|| byte.lookup_encrypted(&table, &backend));
+        assert_eq!(output.decrypt_to_u8(&backend), S_BOX_DATA[0x53]);
+        assert_eq!(counts.mux, 255 * 8);
+        assert_eq!(counts.depth, 8);
+    }
+}
@@ -0,0 +1,272 @@
+use rayon::prelude::*;
+
+use crate::backend::Backend;
+
+/// The straight-line xor circuits of MixColumns and InvMixColumns on a single column of the state.
+///
+/// The textbook layers compose xor of bytes with multiplications by x, each of them a bootstrap per bit, and the folding of the irreducible
+/// polynomial still leaves 140 xor per column for MixColumns and 217 for InvMixColumns. As both layers are linear over GF(2), each is instead
+/// computed by a short linear straight-line program on the 32 bits of the column, in which every gate is the xor of two earlier signals.
+///
+/// The programs are generated by `paar::search`, the heuristic of Boyar and Peralta with random tie-breaking, from the matrices of
+/// `paar::column_matrix` and with exact distances of at most 4. The seeds were picked among the first 335 for MixColumns and the first 200
+/// for InvMixColumns: 67 gives 95 xor of depth 6 for MixColumns, below the 97 xor of "Shorter Linear Straight-Line Programs for MDS
+/// Matrices" (Kranz, Leander, Stoffelen, Wiemer, 2017), the best known being the 92 xor of "AES MixColumn with 92 XOR gates" (Maximov,
+/// 2019), and 9 gives 129 xor of depth 12 for InvMixColumns, where 123 gives 128 xor but of depth 18. `test_mix_column_search` regenerates
+/// both programs.
+///
+/// The 32 inputs and outputs are the 4 bytes of the column, from the first row, each most significant bit first.
+#[derive(Clone, Copy, Debug)]
+pub struct LinearCircuit {
+    /// The xor of two signals, the inputs being the first 32 signals. The gates are sorted by depth, so that every stage is contiguous.
+    xors: &'static [(usize, usize)],
+    /// The signal of every output
+    outputs: &'static [usize; 32],
+}
+
+pub const MIX_COLUMN: LinearCircuit = LinearCircuit {
+    xors: &MIX_COLUMN_XORS,
+    outputs: &MIX_COLUMN_OUTPUTS,
+};
+
+pub const INV_MIX_COLUMN: LinearCircuit = LinearCircuit {
+    xors: &INV_MIX_COLUMN_XORS,
+    outputs: &INV_MIX_COLUMN_OUTPUTS,
+};
+
+#[rustfmt::skip]
+const MIX_COLUMN_XORS: [(usize, usize); 95] = [
+    (8, 16), (0, 24), (0, 8), (16, 24), (9, 17), (1, 25), (7, 31), (2, 26),
+    (10, 18), (15, 23), (6, 14), (13, 21), (3, 27), (5, 29), (22, 30), (11, 19),
+    (20, 28), (4, 12), (1, 24), (21, 22), (5, 21), (23, 32), (15, 46), (17, 37),
+    (10, 36), (31, 41), (33, 38), (15, 35), (42, 43), (14, 45), (2, 44), (27, 39),
+    (13, 46), (34, 37), (35, 36), (1, 36), (18, 47), (4, 48), (20, 33), (34, 49),
+    (11, 48), (35, 44), (38, 53), (40, 55), (34, 57), (38, 59), (57, 58), (53, 54),
+    (42, 59), (29, 60), (40, 62), (45, 64), (16, 65), (19, 63), (0, 66), (39, 67),
+    (39, 68), (51, 61), (34, 55), (42, 58), (25, 56), (47, 71), (45, 70), (43, 69),
+    (72, 73), (69, 70), (52, 71), (6, 79), (78, 80), (30, 80), (26, 75), (10, 85),
+    (47, 82), (50, 90), (86, 90), (22, 91), (2, 92), (30, 81), (27, 93), (49, 94),
+    (32, 95), (93, 97), (95, 98), (99, 100), (57, 101), (56, 102), (85, 104), (25, 106),
+    (61, 109), (96, 113), (71, 113), (48, 112), (94, 114), (3, 122), (123, 124),
+];
+
+#[rustfmt::skip]
+const MIX_COLUMN_OUTPUTS: [usize; 32] = [
+    119, 108, 118, 110, 114, 81, 115, 76,
+    86, 75, 88, 121, 112, 89, 99, 74,
+    105, 117, 103, 96, 126, 83, 116, 77,
+    84, 87, 82, 125, 111, 120, 107, 78,
+];
+
+#[rustfmt::skip]
+const INV_MIX_COLUMN_XORS: [(usize, usize); 129] = [
+    (2, 18), (10, 26), (9, 25), (1, 17), (8, 16), (0, 24), (15, 31), (11, 27),
+    (3, 19), (14, 30), (6, 22), (4, 20), (12, 28), (6, 7), (22, 23), (7, 30),
+    (32, 33), (21, 34), (29, 35), (34, 36), (36, 39), (37, 43), (0, 38), (16, 35),
+    (1, 40), (17, 40), (37, 39), (31, 37), (39, 40), (42, 44), (23, 48), (5, 49),
+    (13, 50), (41, 48), (44, 53), (24, 55), (15, 51), (54, 55), (34, 59), (9, 56),
+    (2, 57), (34, 48), (32, 60), (35, 48), (7, 62), (24, 64), (42, 65), (19, 63),
+    (3, 63), (0, 64), (23, 67), (51, 66), (58, 71), (63, 69), (52, 66), (71, 72),
+    (10, 72), (36, 69), (37, 73), (64, 75), (38, 76), (8, 77), (55, 84), (20, 78),
+    (43, 79), (26, 86), (46, 78), (83, 88), (15, 78), (62, 89), (47, 89), (29, 85),
+    (38, 85), (33, 94), (12, 95), (11, 93), (48, 96), (87, 97), (41, 92), (68, 92),
+    (82, 92), (70, 92), (70, 98), (75, 99), (21, 93), (14, 100), (51, 100), (73, 98),
+    (100, 102), (22, 103), (43, 104), (51, 105), (52, 106), (40, 107), (12, 110), (73, 109),
+    (47, 116), (82, 117), (45, 118), (14, 121), (48, 106), (124, 125), (108, 125), (96, 124),
+    (67, 123), (48, 123), (119, 128), (63, 128), (78, 131), (66, 126), (97, 134), (86, 133),
+    (2, 134), (60, 135), (90, 136), (46, 139), (91, 140), (13, 141), (86, 145), (74, 144),
+    (48, 142), (116, 149), (49, 149), (74, 152), (132, 153), (44, 153), (132, 154), (104, 157),
+    (122, 158),
+];
+
+#[rustfmt::skip]
+const INV_MIX_COLUMN_OUTPUTS: [usize; 32] = [
+    146, 99, 144, 135, 160, 138, 130, 101,
+    105, 127, 155, 133, 154, 131, 129, 111,
+    137, 115, 151, 150, 156, 147, 114, 112,
+    136, 109, 142, 143, 159, 148, 120, 113,
+];
+
+impl LinearCircuit {
+    pub fn xors(&self) -> &[(usize, usize)] {
+        self.xors
+    }
+
+    /// The depth of every signal, in xor
+    fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![0; 32];
+        for &(lhs, rhs) in self.xors.iter() {
+            depths.push(1 + depths[lhs].max(depths[rhs]));
+        }
+        depths
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depths().into_iter().max().unwrap()
+    }
+
+    /// The gates of every stage, in evaluation order
+    fn stages(&self) -> Vec<&[(usize, usize)]> {
+        let depths = &self.depths()[32..];
+        let mut stages = Vec::new();
+        let mut start = 0;
+        for end in 1..=self.xors.len() {
+            if end == self.xors.len() || depths[end] != depths[start] {
+                stages.push(&self.xors[start..end]);
+                start = end;
+            }
+        }
+        stages
+    }
+
+    pub fn evaluate<B: Backend>(&self, column: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+        self.evaluate_batch(&[column.to_vec()], server_key)
+            .pop()
+            .unwrap()
+    }
+
+    /// Evaluates the circuit on many columns at once, each stage being a single parallel launch over all the columns as for `EvaluationPlan`.
+    pub fn evaluate_batch<B: Backend>(
+        &self,
+        columns: &[Vec<B::Bit>],
+        server_key: &B,
+    ) -> Vec<Vec<B::Bit>> {
+        let mut values: Vec<Vec<B::Bit>> = columns
+            .iter()
+            .map(|column| {
+                assert!(column.len() == 32, "A column has 32 bits !");
+                let mut values = Vec::with_capacity(32 + self.xors.len());
+                values.extend_from_slice(column);
+                values
+            })
+            .collect();
+
+        for stage in self.stages() {
+            let stage_values: Vec<B::Bit> = (0..values.len() * stage.len())
+                .into_par_iter()
+                .map(|i| {
+                    let (lhs, rhs) = stage[i % stage.len()];
+                    let values = &values[i / stage.len()];
+                    server_key.xor(&values[lhs], &values[rhs])
+                })
+                .collect();
+
+            for (values, stage_values) in values.iter_mut().zip(stage_values.chunks(stage.len())) {
+                values.extend_from_slice(stage_values);
+            }
+        }
+
+        values
+            .iter()
+            .map(|values| {
+                self.outputs
+                    .iter()
+                    .map(|&signal| values[signal].clone())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ClearBackend;
+    use crate::counting::CountingBackend;
+    use crate::paar;
+
+    fn clear_mul_gf2(mut x: u8, mut y: u8) -> u8 {
+        let mut product = 0;
+        while y != 0 {
+            if y & 1 != 0 {
+                product ^= x;
+            }
+            x = (x << 1) ^ if x & 0x80 != 0 { 0x1b } else { 0 };
+            y >>= 1;
+        }
+        product
+    }
+
+    fn clear_mix_column(column: [u8; 4], coefficients: [u8; 4]) -> [u8; 4] {
+        std::array::from_fn(|i| {
+            (0..4).fold(0, |acc, j| {
+                acc ^ clear_mul_gf2(coefficients[(j + 4 - i) % 4], column[j])
+            })
+        })
+    }
+
+    fn to_bits(column: [u8; 4]) -> Vec<bool> {
+        column
+            .iter()
+            .flat_map(|x| (0..8).rev().map(move |i| x & (1 << i) != 0))
+            .collect()
+    }
+
+    #[test]
+    fn test_mix_column_clear() {
+        for seed in 0..=255_u8 {
+            let column = [
+                seed,
+                seed.wrapping_mul(0x1d),
+                seed.rotate_left(3) ^ 0xa5,
+                seed.wrapping_add(0x6b),
+            ];
+            let mixed = MIX_COLUMN.evaluate(&to_bits(column), &ClearBackend);
+            assert_eq!(mixed, to_bits(clear_mix_column(column, [2, 3, 1, 1])));
+
+            let unmixed = INV_MIX_COLUMN.evaluate(&to_bits(column), &ClearBackend);
+            assert_eq!(unmixed, to_bits(clear_mix_column(column, [14, 11, 13, 9])));
+            assert_eq!(
+                INV_MIX_COLUMN.evaluate(&mixed, &ClearBackend),
+                to_bits(column)
+            );
+        }
+    }
+
+    // The seeds of `paar::search` which generate the programs
+    const MIX_COLUMN_SEED: u64 = 67;
+    const INV_MIX_COLUMN_SEED: u64 = 9;
+
+    #[test]
+    fn test_mix_column_matrices() {
+        for (circuit, coefficients) in [
+            (MIX_COLUMN, [2, 3, 1, 1]),
+            (INV_MIX_COLUMN, [14, 11, 13, 9]),
+        ] {
+            let signals = paar::signals(circuit.xors);
+            let rows: Vec<u32> = circuit.outputs.iter().map(|&x| signals[x]).collect();
+            assert_eq!(rows, paar::column_matrix(coefficients));
+        }
+    }
+
+    #[test]
+    fn test_mix_column_search() {
+        for (circuit, coefficients, seed) in [
+            (MIX_COLUMN, [2, 3, 1, 1], MIX_COLUMN_SEED),
+            (INV_MIX_COLUMN, [14, 11, 13, 9], INV_MIX_COLUMN_SEED),
+        ] {
+            let (xors, outputs) = paar::search(&paar::column_matrix(coefficients), seed);
+            assert_eq!(xors, circuit.xors);
+            assert_eq!(outputs, circuit.outputs);
+        }
+    }
+
+    #[test]
+    fn test_mix_column_gates() {
+        for (circuit, num_xors) in [(MIX_COLUMN, 95), (INV_MIX_COLUMN, 129)] {
+            let depths = circuit.depths();
+            assert!(depths.is_sorted());
+
+            let backend = CountingBackend::default();
+            let bits: Vec<_> = to_bits([0xdb, 0x13, 0x53, 0x45])
+                .into_iter()
+                .map(|x| backend.input(x))
+                .collect();
+            let (_, counts) = backend.measure(|| circuit.evaluate(&bits, &backend));
+            assert_eq!(circuit.xors().len() as u64, num_xors);
+            assert_eq!(counts.xor, num_xors);
+            assert_eq!(counts.bootstraps, num_xors);
+            assert_eq!(counts.depth as usize, circuit.depth());
+        }
+    }
+}
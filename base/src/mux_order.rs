@@ -0,0 +1,182 @@
+use crate::boolean_tree::{ExprArena, ExprId, ExprNode};
+use crate::plan::EvaluationPlan;
+use crate::sbox::bit_x_s_box;
+
+/// VariableOrder is the order of the selector bits of a mux tree, from the leaves to the root, see `ExprArena::reduce_mux_ordered`.
+///
+/// A mux tree is a BDD of its output bit, whose size depends on the order of its variables. This module searches the orders of the 8
+/// output bits of an S-Box with the fewest unique gates, a gate shared by several output bits being counted once. The search takes a few
+/// seconds, so it is run offline and its results are the `S_BOX_ORDERS` and `INV_S_BOX_ORDERS` from which the shared plans are compiled.
+pub type VariableOrder = [usize; 8];
+
+/// The order of `BooleanExpr::reduce_mux`, Bit0 at the leaves and Bit7 at the root.
+pub const IDENTITY_ORDER: VariableOrder = [0, 1, 2, 3, 4, 5, 6, 7];
+
+/// The orders of the output bits of the S-Box found by `search`, most significant output bit first.
+pub const S_BOX_ORDERS: [VariableOrder; 8] = [
+    [3, 4, 1, 7, 0, 2, 5, 6],
+    [3, 4, 1, 7, 0, 2, 5, 6],
+    [3, 4, 1, 7, 0, 2, 5, 6],
+    [3, 4, 1, 7, 0, 2, 5, 6],
+    [3, 4, 1, 7, 0, 2, 5, 6],
+    [3, 4, 1, 7, 0, 2, 5, 6],
+    [3, 4, 1, 7, 0, 2, 5, 6],
+    [3, 4, 1, 5, 7, 0, 2, 6],
+];
+
+/// The orders of the output bits of the Inv S-Box found by `search`, most significant output bit first.
+pub const INV_S_BOX_ORDERS: [VariableOrder; 8] = [
+    [2, 5, 0, 6, 4, 1, 3, 7],
+    [2, 5, 0, 4, 1, 3, 6, 7],
+    [2, 5, 0, 4, 1, 3, 6, 7],
+    [2, 5, 0, 1, 4, 3, 6, 7],
+    [2, 5, 0, 4, 1, 3, 6, 7],
+    [2, 5, 0, 3, 4, 1, 6, 7],
+    [2, 5, 0, 4, 1, 3, 6, 7],
+    [2, 5, 0, 4, 1, 3, 6, 7],
+];
+
+/// Generates the reduced expressions of the 8 output bits of the S-Box or the Inv S-Box in an arena, most significant bit first, the
+/// mux tree of every output bit having its own selector order.
+pub fn ordered_exprs(
+    arena: &mut ExprArena,
+    data: [u8; 256],
+    orders: &[VariableOrder; 8],
+) -> Vec<ExprId> {
+    (0..8)
+        .rev()
+        .zip(orders.iter())
+        .map(|(x, order)| {
+            let items: Vec<ExprId> = bit_x_s_box(data, x)
+                .into_iter()
+                .map(|bit| arena.operand(bit.into()))
+                .collect();
+            arena.reduce_mux_ordered(&items, order)
+        })
+        .collect()
+}
+
+/// Compiles the plan of the S-Box or the Inv S-Box with the given orders, see `SBoxCircuit::MuxTree`.
+pub fn compile(data: [u8; 256], orders: &[VariableOrder; 8]) -> EvaluationPlan {
+    let mut arena = ExprArena::new();
+    let outputs = ordered_exprs(&mut arena, data, orders);
+    EvaluationPlan::compile_arena(&arena, &outputs)
+}
+
+/// The number of unique gates of the 8 output bits, a gate shared by several outputs being counted once.
+pub fn unique_gates(data: [u8; 256], orders: &[VariableOrder; 8]) -> usize {
+    let mut arena = ExprArena::new();
+    let outputs = ordered_exprs(&mut arena, data, orders);
+    arena
+        .reachable(&outputs)
+        .into_iter()
+        .filter(|&id| !matches!(arena.node(id), ExprNode::Operand(_)))
+        .count()
+}
+
+/// The order, common to the 8 output bits, with the fewest unique gates among all the 8! orders.
+///
+/// This evaluates 40320 orders, so it is meant to be run offline, as `search`.
+pub fn best_common_order(data: [u8; 256]) -> VariableOrder {
+    let mut order = IDENTITY_ORDER;
+    let mut best = (unique_gates(data, &[order; 8]), order);
+    while next_permutation(&mut order) {
+        let gates = unique_gates(data, &[order; 8]);
+        if gates < best.0 {
+            best = (gates, order);
+        }
+    }
+    best.1
+}
+
+/// Sifts the orders: every selector bit of every output is moved to the position which minimises the unique gates of all the outputs,
+/// until no move decreases them. The result is never worse than `orders`.
+pub fn sift(data: [u8; 256], mut orders: [VariableOrder; 8]) -> [VariableOrder; 8] {
+    let mut best = unique_gates(data, &orders);
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for output in 0..8 {
+            for bit in 0..8 {
+                let from = orders[output].iter().position(|&x| x == bit).unwrap();
+                for to in (0..8).filter(|&to| to != from) {
+                    let mut candidate = orders;
+                    if from < to {
+                        candidate[output][from..=to].rotate_left(1);
+                    } else {
+                        candidate[output][to..=from].rotate_right(1);
+                    }
+                    let gates = unique_gates(data, &candidate);
+                    if gates < best {
+                        best = gates;
+                        orders = candidate;
+                        improved = true;
+                    }
+                }
+            }
+        }
+    }
+    orders
+}
+
+/// Searches the orders with the fewest unique gates: the best common order, exhaustively, then refined output by output by `sift`.
+pub fn search(data: [u8; 256]) -> [VariableOrder; 8] {
+    sift(data, [best_common_order(data); 8])
+}
+
+/// Rearranges `order` into the next permutation in lexicographic order, returning false when it was the last one.
+fn next_permutation(order: &mut [usize]) -> bool {
+    let Some(pivot) = (1..order.len()).rev().find(|&i| order[i - 1] < order[i]) else {
+        return false;
+    };
+    let successor = (pivot..order.len())
+        .rev()
+        .find(|&i| order[i] > order[pivot - 1])
+        .unwrap();
+    order.swap(pivot - 1, successor);
+    order[pivot..].reverse();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ClearBackend;
+    use crate::sbox::*;
+
+    #[test]
+    fn test_next_permutation() {
+        let mut order = IDENTITY_ORDER;
+        let mut count = 1;
+        while next_permutation(&mut order) {
+            count += 1;
+        }
+        assert_eq!(count, 40320);
+        assert_eq!(order, [7, 6, 5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_searched_orders() {
+        for (data, orders) in [
+            (S_BOX_DATA, S_BOX_ORDERS),
+            (INV_S_BOX_DATA, INV_S_BOX_ORDERS),
+        ] {
+            // The searched orders have fewer gates than the order of `reduce_mux` and no move of a single selector improves them
+            let gates = unique_gates(data, &orders);
+            assert!(gates < unique_gates(data, &[IDENTITY_ORDER; 8]));
+            assert_eq!(sift(data, orders), orders);
+
+            // Every order gives the same functions
+            let plan = compile(data, &orders);
+            assert_eq!(plan.gates().len(), gates);
+            for x in 0..=255_u8 {
+                let bits: Vec<bool> = (0..8).map(|i| x & (1 << i) != 0).collect();
+                let result = plan
+                    .evaluate(&bits, &ClearBackend)
+                    .iter()
+                    .fold(0, |acc, &bit| (acc << 1) | bit as u8);
+                assert_eq!(result, data[x as usize]);
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+
+/// The largest distance of a target that is computed exactly, the distance of the farther ones being counted on Paar's cancellation
+const EXACT_DISTANCE: usize = 4;
+
+fn mul_gf2(mut x: u8, mut y: u8) -> u8 {
+    let mut product = 0;
+    while y != 0 {
+        if y & 1 != 0 {
+            product ^= x;
+        }
+        x = (x << 1) ^ if x & 0x80 != 0 { 0x1b } else { 0 };
+        y >>= 1;
+    }
+    product
+}
+
+/// The matrix over GF(2) of the circulant map of a column with the given first row, e.g. `[2, 3, 1, 1]` for MixColumns.
+///
+/// The row `i` is the mask of the inputs of the output bit `i`, the bit `j` of the mask being the input bit `j`. The bits of the column are
+/// numbered as in `LinearCircuit`, from the first row, each most significant bit first.
+pub fn column_matrix(coefficients: [u8; 4]) -> [u32; 32] {
+    let mut rows = [0; 32];
+    for j in 0..32 {
+        let mut column = [0u8; 4];
+        column[j / 8] = 0x80 >> (j % 8);
+        for i in 0..4 {
+            let y = (0..4).fold(0, |y, l| {
+                y ^ mul_gf2(coefficients[(l + 4 - i) % 4], column[l])
+            });
+            for k in 0..8 {
+                if y & (0x80 >> k) != 0 {
+                    rows[8 * i + k] |= 1 << j;
+                }
+            }
+        }
+    }
+    rows
+}
+
+/// The mask of the inputs of every signal of the program, the inputs being the first 32 signals.
+pub fn signals(xors: &[(usize, usize)]) -> Vec<u32> {
+    let mut signals: Vec<u32> = (0..32).map(|i| 1 << i).collect();
+    for &(lhs, rhs) in xors {
+        signals.push(signals[lhs] ^ signals[rhs]);
+    }
+    signals
+}
+
+/// The xorshift generator of Marsaglia, which breaks the ties of the search
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Calls `f` on the xor of `acc` with every set of `k` signals from `start`.
+fn combinations(signals: &[u32], k: usize, start: usize, acc: u32, f: &mut dyn FnMut(u32)) {
+    if k == 0 {
+        f(acc);
+        return;
+    }
+    for i in start..signals.len() {
+        combinations(signals, k - 1, i + 1, acc ^ signals[i], f);
+    }
+}
+
+/// The xor of every two signals
+fn pairs(signals: &[u32]) -> HashSet<u32> {
+    let mut pairs = HashSet::new();
+    for (i, lhs) in signals.iter().enumerate() {
+        pairs.extend(signals[i + 1..].iter().map(|rhs| lhs ^ rhs));
+    }
+    pairs
+}
+
+/// Whether the value is the xor of `k` signals, or of `k - 2`, `k - 4`... of them, meeting the sets of `k - 2` signals with the pairs.
+fn is_sum(signals: &[u32], pairs: &HashSet<u32>, value: u32, k: usize) -> bool {
+    match k {
+        0 => value == 0,
+        1 => signals.contains(&value),
+        _ => {
+            let mut found = false;
+            combinations(signals, k - 2, 0, value, &mut |rest| {
+                found |= pairs.contains(&rest);
+            });
+            found
+        }
+    }
+}
+
+/// The number of xor from the signals to the target, if it is at most `EXACT_DISTANCE`.
+fn exact_distance(signals: &[u32], target: u32) -> Option<usize> {
+    let pairs = pairs(signals);
+    (0..=EXACT_DISTANCE).find(|&distance| is_sum(signals, &pairs, target, distance + 1))
+}
+
+/// Searches a linear straight-line program of the matrix, returning its xor and the signal of every output, as `LinearCircuit` holds them.
+///
+/// This is the heuristic of Boyar and Peralta ("A new combinational logic minimization technique with applications to cryptology", 2010):
+/// every step adds the xor of two signals which minimizes the sum of the distances to the targets, then maximizes their Euclidean norm, the
+/// remaining ties being broken by a xorshift generator seeded with `seed` as in "Improved Heuristics for Short Linear Programs" (Tan, Peyrin,
+/// 2019). Enumerating the sums of signals is only tractable for close targets, so the distance of a target is first the number of xor left
+/// in Paar's greedy cancellation of its inputs, and becomes the exact distance once the cancellation is within 2 of `EXACT_DISTANCE` and an
+/// exact distance of at most `EXACT_DISTANCE` is found.
+///
+/// The gates are then sorted by depth, keeping the order of the search within a stage, and the operands of every gate are sorted.
+pub fn search(targets: &[u32; 32], seed: u64) -> (Vec<(usize, usize)>, Vec<usize>) {
+    let mut rng = XorShift(seed);
+    let mut signals = signals(&[]);
+    let mut xors = Vec::new();
+
+    // The signals of Paar's cancellation of every target, and whether its distance is exact
+    let mut cancellations: Vec<HashSet<usize>> = targets
+        .iter()
+        .map(|target| (0..32).filter(|i| target >> i & 1 == 1).collect())
+        .collect();
+    let mut distances: Vec<usize> = cancellations.iter().map(|c| c.len() - 1).collect();
+    let mut exact = [false; 32];
+
+    while distances.iter().any(|&distance| distance != 0) {
+        // The signals one xor closer to every exact target
+        let closer: Vec<Option<Vec<u32>>> = (0..32)
+            .map(|t| {
+                (exact[t] && distances[t] >= 2).then(|| {
+                    let mut closer = Vec::new();
+                    combinations(&signals, distances[t] - 1, 0, targets[t], &mut |x| {
+                        closer.push(x);
+                    });
+                    closer.sort_unstable();
+                    closer
+                })
+            })
+            .collect();
+        let present: HashSet<u32> = signals.iter().copied().collect();
+
+        let mut best: Option<((usize, i64, u64), usize, usize)> = None;
+        for lhs in 0..signals.len() {
+            for rhs in lhs + 1..signals.len() {
+                let x = signals[lhs] ^ signals[rhs];
+                if present.contains(&x) {
+                    continue;
+                }
+                let (mut sum, mut norm) = (0, 0);
+                for t in 0..32 {
+                    let distance = distances[t];
+                    let new_distance = if distance == 1 && x == targets[t] {
+                        0
+                    } else if exact[t] {
+                        match &closer[t] {
+                            Some(closer) if closer.binary_search(&x).is_ok() => distance - 1,
+                            _ => distance,
+                        }
+                    } else if cancellations[t].contains(&lhs) && cancellations[t].contains(&rhs) {
+                        distance - 1
+                    } else {
+                        distance
+                    };
+                    sum += new_distance;
+                    norm += (new_distance * new_distance) as i64;
+                }
+                let key = (sum, -norm, rng.next());
+                if best.as_ref().is_none_or(|(best_key, _, _)| key < *best_key) {
+                    best = Some((key, lhs, rhs));
+                }
+            }
+        }
+
+        let (_, lhs, rhs) = best.unwrap();
+        let x = signals[lhs] ^ signals[rhs];
+        xors.push((lhs, rhs));
+        signals.push(x);
+        let new = signals.len() - 1;
+        let previous_pairs = pairs(&signals[..new]);
+
+        for t in 0..32 {
+            if targets[t] == x {
+                distances[t] = 0;
+                exact[t] = true;
+                continue;
+            }
+            if cancellations[t].contains(&lhs) && cancellations[t].contains(&rhs) {
+                cancellations[t].remove(&lhs);
+                cancellations[t].remove(&rhs);
+                cancellations[t].insert(new);
+            }
+            if exact[t] {
+                // As the distance is exact, the new signal brings the target at most one xor closer
+                if distances[t] >= 2
+                    && is_sum(
+                        &signals[..new],
+                        &previous_pairs,
+                        targets[t] ^ x,
+                        distances[t] - 1,
+                    )
+                {
+                    distances[t] -= 1;
+                }
+            } else {
+                distances[t] = cancellations[t].len() - 1;
+                if distances[t] <= EXACT_DISTANCE + 2 {
+                    if let Some(distance) = exact_distance(&signals, targets[t]) {
+                        distances[t] = distance;
+                        exact[t] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    let outputs: Vec<usize> = targets
+        .iter()
+        .map(|target| signals.iter().position(|x| x == target).unwrap())
+        .collect();
+    sort_by_depth(&xors, &outputs)
+}
+
+/// Renumbers the gates of the program by depth, the order of the gates of the same depth being kept.
+fn sort_by_depth(xors: &[(usize, usize)], outputs: &[usize]) -> (Vec<(usize, usize)>, Vec<usize>) {
+    let mut depths = vec![0; 32];
+    for &(lhs, rhs) in xors {
+        depths.push(1 + depths[lhs].max(depths[rhs]));
+    }
+
+    let mut order: Vec<usize> = (0..xors.len()).collect();
+    order.sort_by_key(|&gate| depths[32 + gate]);
+
+    let mut renumbering: Vec<usize> = (0..32 + xors.len()).collect();
+    for (new, &gate) in order.iter().enumerate() {
+        renumbering[32 + gate] = 32 + new;
+    }
+
+    let sorted = order
+        .iter()
+        .map(|&gate| {
+            let (lhs, rhs) = xors[gate];
+            let (lhs, rhs) = (renumbering[lhs], renumbering[rhs]);
+            (lhs.min(rhs), lhs.max(rhs))
+        })
+        .collect();
+    let outputs = outputs.iter().map(|&signal| renumbering[signal]).collect();
+    (sorted, outputs)
+}
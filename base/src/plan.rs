@@ -0,0 +1,460 @@
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::backend::Backend;
+use crate::boolean_tree::{BooleanExpr, ExprArena, ExprId, ExprNode, Operand};
+use crate::folding::{fold_and, fold_mux, fold_or, fold_xor};
+
+/// The number of operand slots at the start of the value vector of a plan, one per `Operand` in the order of its declaration:
+/// True, False, Bit0, NotBit0, ..., Bit7, NotBit7.
+pub const OPERAND_SLOTS: usize = 18;
+
+/// A gate of an evaluation plan, whose fields are the slots of its inputs in the value vector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Gate {
+    And(usize, usize),
+    Or(usize, usize),
+    Xor(usize, usize),
+    /// Mux(condition, then, otherwise)
+    Mux(usize, usize, usize),
+    /// Not gates do not bootstrap, so they belong to the stage of their input and are evaluated after the other gates of that stage.
+    Not(usize),
+}
+
+impl Gate {
+    fn run<B: Backend>(&self, values: &[B::Bit], server_key: &B) -> B::Bit {
+        match *self {
+            Gate::And(lhs, rhs) => server_key.and(&values[lhs], &values[rhs]),
+            Gate::Or(lhs, rhs) => server_key.or(&values[lhs], &values[rhs]),
+            Gate::Xor(lhs, rhs) => server_key.xor(&values[lhs], &values[rhs]),
+            Gate::Mux(condition, then, otherwise) => {
+                server_key.mux(&values[condition], &values[then], &values[otherwise])
+            }
+            Gate::Not(input) => server_key.not(&values[input]),
+        }
+    }
+
+    pub(crate) fn is_free(&self) -> bool {
+        matches!(self, Gate::Not(_))
+    }
+
+    /// The slots read by the gate, in the order of its fields
+    pub(crate) fn inputs(&self) -> Vec<usize> {
+        match *self {
+            Gate::And(lhs, rhs) | Gate::Or(lhs, rhs) | Gate::Xor(lhs, rhs) => vec![lhs, rhs],
+            Gate::Mux(condition, then, otherwise) => vec![condition, then, otherwise],
+            Gate::Not(input) => vec![input],
+        }
+    }
+
+    /// The bootstraps of the gate with the boolean API of tfhe, as counted by the `CountingBackend`
+    pub(crate) fn bootstraps(&self) -> u32 {
+        match self {
+            Gate::Not(_) => 0,
+            Gate::Mux(..) => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// EvaluationPlan is a set of Boolean expressions compiled into a flat list of gates, ready to be evaluated stage by stage.
+///
+/// The values of a plan live in a single vector: the `OPERAND_SLOTS` operand slots come first, followed by the output of every gate in the order of
+/// the gate list. The gates are sorted by stage and only read slots of earlier stages, so the gates of a stage are evaluated in parallel and their
+/// outputs appended to the vector. The expressions are only hash-consed once, when compiling the plan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvaluationPlan {
+    gates: Vec<Gate>,
+    stage_ends: Vec<usize>,
+    outputs: Vec<usize>,
+}
+
+fn operand_slot(operand: Operand) -> usize {
+    operand as usize
+}
+
+impl EvaluationPlan {
+    /// Compiles the expressions into a plan, sharing the sub-expressions common to several outputs.
+    pub fn compile(outputs: &[BooleanExpr]) -> Self {
+        let mut arena = ExprArena::new();
+        let outputs: Vec<ExprId> = outputs.iter().map(|expr| arena.intern(expr)).collect();
+        Self::compile_arena(&arena, &outputs)
+    }
+
+    /// Compiles expressions of an arena, whose sub-expressions are already shared and whose stages are already known.
+    pub fn compile_arena(arena: &ExprArena, outputs: &[ExprId]) -> Self {
+        let mut grouped_by_stage: Vec<Vec<ExprId>> = Vec::new();
+        for id in arena.reachable(outputs) {
+            let stage = arena.stage(id) as usize;
+            if stage == 0 {
+                // Operands already have their slots
+                continue;
+            }
+            if grouped_by_stage.len() < stage {
+                grouped_by_stage.resize(stage, Vec::new());
+            }
+            grouped_by_stage[stage - 1].push(id);
+        }
+
+        // The ids of the operands are their slots
+        let mut slots: HashMap<ExprId, usize> = HashMap::new();
+        let slot = |slots: &HashMap<ExprId, usize>, id: ExprId| {
+            if id < OPERAND_SLOTS { id } else { slots[&id] }
+        };
+
+        let mut gates = Vec::new();
+        let mut stage_ends = Vec::new();
+        for mut stage in grouped_by_stage {
+            // Sorting as the expressions makes the plan deterministic
+            stage.sort_by(|&lhs, &rhs| arena.compare(lhs, rhs));
+            for id in stage {
+                let gate = match arena.node(id) {
+                    ExprNode::And(lhs, rhs) => Gate::And(slot(&slots, lhs), slot(&slots, rhs)),
+                    ExprNode::Or(lhs, rhs) => Gate::Or(slot(&slots, lhs), slot(&slots, rhs)),
+                    ExprNode::Xor(lhs, rhs) => Gate::Xor(slot(&slots, lhs), slot(&slots, rhs)),
+                    ExprNode::Mux(condition, then, otherwise) => Gate::Mux(
+                        operand_slot(condition),
+                        slot(&slots, then),
+                        slot(&slots, otherwise),
+                    ),
+                    ExprNode::Operand(_) => unreachable!("Operands are of stage 0"),
+                };
+                slots.insert(id, OPERAND_SLOTS + gates.len());
+                gates.push(gate);
+            }
+            stage_ends.push(gates.len());
+        }
+
+        let outputs = outputs.iter().map(|&id| slot(&slots, id)).collect();
+
+        EvaluationPlan {
+            gates,
+            stage_ends,
+            outputs,
+        }
+    }
+
+    pub fn gates(&self) -> &[Gate] {
+        &self.gates
+    }
+
+    /// The gates of every stage, in evaluation order
+    pub fn stages(&self) -> impl Iterator<Item = &[Gate]> {
+        let starts = std::iter::once(0).chain(self.stage_ends.iter().copied());
+        starts
+            .zip(self.stage_ends.iter().copied())
+            .map(|(start, end)| &self.gates[start..end])
+    }
+
+    pub fn num_stages(&self) -> usize {
+        self.stage_ends.len()
+    }
+
+    /// The slots of the outputs in the value vector
+    pub fn outputs(&self) -> &[usize] {
+        &self.outputs
+    }
+
+    /// Fills the operand slots from the 8 input bits, `bits[i]` being the bit of `Operand::Biti`.
+    fn operand_values<B: Backend>(&self, bits: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+        assert!(bits.len() == 8, "A plan is evaluated on 8 bits !");
+
+        let mut values = Vec::with_capacity(OPERAND_SLOTS + self.gates.len());
+        values.push(server_key.trivial(true));
+        values.push(server_key.trivial(false));
+        for bit in bits.iter() {
+            values.push(bit.clone());
+            values.push(server_key.not(bit));
+        }
+
+        values
+    }
+
+    /// Compiles a circuit written against the `Backend` trait, by running it once on a `PlanBuilder`.
+    ///
+    /// The circuit receives the wires of the 8 input bits, `inputs[i]` being the wire of `Operand::Biti`, and returns the wires of its outputs.
+    pub fn trace(circuit: impl FnOnce(&PlanBuilder, &[usize]) -> Vec<usize>) -> Self {
+        let builder = PlanBuilder::default();
+        let inputs: Vec<usize> = (0..8).map(|i| 2 + 2 * i).collect();
+        let outputs = circuit(&builder, &inputs);
+        builder.build(&outputs)
+    }
+
+    /// Evaluates the plan on the 8 input bits, `bits[i]` being the bit of `Operand::Biti`, and returns the outputs in the order they were compiled.
+    pub fn evaluate<B: Backend>(&self, bits: &[B::Bit], server_key: &B) -> Vec<B::Bit> {
+        self.evaluate_batch(&[bits.to_vec()], server_key)
+            .pop()
+            .unwrap()
+    }
+
+    /// Evaluates the plan on many inputs at once, e.g. every byte of many states.
+    ///
+    /// Each stage is evaluated for all the inputs in a single parallel launch, so there are as many launches as stages whatever the number of inputs,
+    /// and every launch holds `inputs.len()` times as many gates as for a single input.
+    pub fn evaluate_batch<B: Backend>(
+        &self,
+        inputs: &[Vec<B::Bit>],
+        server_key: &B,
+    ) -> Vec<Vec<B::Bit>> {
+        let mut values: Vec<Vec<B::Bit>> = inputs
+            .par_iter()
+            .map(|bits| self.operand_values(bits, server_key))
+            .collect();
+
+        for stage in self.stages() {
+            let (gates, free_gates) =
+                stage.split_at(stage.iter().position(Gate::is_free).unwrap_or(stage.len()));
+
+            if !gates.is_empty() {
+                let stage_values: Vec<B::Bit> = (0..values.len() * gates.len())
+                    .into_par_iter()
+                    .map(|i| gates[i % gates.len()].run(&values[i / gates.len()], server_key))
+                    .collect();
+
+                for (values, stage_values) in
+                    values.iter_mut().zip(stage_values.chunks(gates.len()))
+                {
+                    values.extend_from_slice(stage_values);
+                }
+            }
+
+            values.par_iter_mut().for_each(|values| {
+                for gate in free_gates {
+                    let value = gate.run(values, server_key);
+                    values.push(value);
+                }
+            });
+        }
+
+        values
+            .iter()
+            .map(|values| {
+                self.outputs
+                    .iter()
+                    .map(|&slot| values[slot].clone())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// PlanBuilder is the backend of `EvaluationPlan::trace`, which records the gates of a circuit instead of evaluating them.
+///
+/// Its bits are wires: the first `OPERAND_SLOTS` wires are the operands, in the order of their slots, and every other wire is the output of a
+/// recorded gate. Identical gates are recorded once, and the stage of a gate is its depth in bootstraps.
+#[derive(Debug, Default)]
+pub struct PlanBuilder {
+    recorded: Mutex<RecordedGates>,
+}
+
+#[derive(Debug, Default)]
+struct RecordedGates {
+    gates: Vec<(Gate, usize)>,
+    wires: HashMap<Gate, usize>,
+}
+
+impl RecordedGates {
+    fn stage(&self, wire: usize) -> usize {
+        if wire < OPERAND_SLOTS {
+            0
+        } else {
+            self.gates[wire - OPERAND_SLOTS].1
+        }
+    }
+}
+
+impl PlanBuilder {
+    fn record(&self, gate: Gate) -> usize {
+        let mut recorded = self.recorded.lock().unwrap();
+        if let Some(&wire) = recorded.wires.get(&gate) {
+            return wire;
+        }
+
+        let stage = match gate {
+            Gate::And(lhs, rhs) | Gate::Or(lhs, rhs) | Gate::Xor(lhs, rhs) => {
+                recorded.stage(lhs).max(recorded.stage(rhs)) + 1
+            }
+            Gate::Mux(condition, then, otherwise) => {
+                recorded
+                    .stage(condition)
+                    .max(recorded.stage(then))
+                    .max(recorded.stage(otherwise))
+                    + 1
+            }
+            Gate::Not(input) => recorded.stage(input),
+        };
+
+        let wire = OPERAND_SLOTS + recorded.gates.len();
+        recorded.gates.push((gate, stage));
+        recorded.wires.insert(gate, wire);
+        wire
+    }
+
+    /// Sorts the recorded gates by stage, the free gates last within their stage, and assigns them their slots.
+    fn build(self, outputs: &[usize]) -> EvaluationPlan {
+        let recorded = self.recorded.into_inner().unwrap();
+
+        let mut order: Vec<usize> = (0..recorded.gates.len()).collect();
+        order.sort_by_key(|&i| (recorded.gates[i].1, recorded.gates[i].0.is_free()));
+
+        let mut slots: Vec<usize> = (0..OPERAND_SLOTS).collect();
+        slots.resize(OPERAND_SLOTS + recorded.gates.len(), 0);
+        for (position, &i) in order.iter().enumerate() {
+            slots[OPERAND_SLOTS + i] = OPERAND_SLOTS + position;
+        }
+
+        let mut gates = Vec::with_capacity(order.len());
+        let mut stage_ends = Vec::new();
+        for &i in order.iter() {
+            let (gate, stage) = recorded.gates[i];
+            while stage_ends.len() < stage - 1 {
+                stage_ends.push(gates.len());
+            }
+            gates.push(match gate {
+                Gate::And(lhs, rhs) => Gate::And(slots[lhs], slots[rhs]),
+                Gate::Or(lhs, rhs) => Gate::Or(slots[lhs], slots[rhs]),
+                Gate::Xor(lhs, rhs) => Gate::Xor(slots[lhs], slots[rhs]),
+                Gate::Mux(condition, then, otherwise) => {
+                    Gate::Mux(slots[condition], slots[then], slots[otherwise])
+                }
+                Gate::Not(input) => Gate::Not(slots[input]),
+            });
+        }
+        if !gates.is_empty() {
+            stage_ends.push(gates.len());
+        }
+
+        EvaluationPlan {
+            gates,
+            stage_ends,
+            outputs: outputs.iter().map(|&wire| slots[wire]).collect(),
+        }
+    }
+}
+
+impl Backend for PlanBuilder {
+    type Bit = usize;
+
+    fn trivial(&self, value: bool) -> usize {
+        operand_slot(Operand::from(value))
+    }
+
+    fn not(&self, bit: &usize) -> usize {
+        if *bit < OPERAND_SLOTS {
+            // The slots of an operand and of its negation only differ by their last bit
+            return bit ^ 1;
+        }
+        let recorded = self.recorded.lock().unwrap();
+        if let (Gate::Not(input), _) = recorded.gates[bit - OPERAND_SLOTS] {
+            return input;
+        }
+        drop(recorded);
+        self.record(Gate::Not(*bit))
+    }
+
+    fn and(&self, lhs: &usize, rhs: &usize) -> usize {
+        fold_and(self, lhs, rhs)
+            .unwrap_or_else(|| self.record(Gate::And(*lhs.min(rhs), *lhs.max(rhs))))
+    }
+
+    fn or(&self, lhs: &usize, rhs: &usize) -> usize {
+        fold_or(self, lhs, rhs)
+            .unwrap_or_else(|| self.record(Gate::Or(*lhs.min(rhs), *lhs.max(rhs))))
+    }
+
+    fn xor(&self, lhs: &usize, rhs: &usize) -> usize {
+        fold_xor(self, lhs, rhs)
+            .unwrap_or_else(|| self.record(Gate::Xor(*lhs.min(rhs), *lhs.max(rhs))))
+    }
+
+    fn mux(&self, condition: &usize, then: &usize, otherwise: &usize) -> usize {
+        fold_mux(self, condition, then, otherwise)
+            .unwrap_or_else(|| self.record(Gate::Mux(*condition, *then, *otherwise)))
+    }
+
+    fn constant(&self, bit: &usize) -> Option<bool> {
+        match *bit {
+            0 => Some(true),
+            1 => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ClearBackend;
+    use crate::sbox::*;
+
+    #[test]
+    fn test_compile_s_box() {
+        let exprs = generate_reduced_bool_expr(S_BOX_DATA);
+        let plan = EvaluationPlan::compile(&exprs);
+
+        // Every distinct sub-expression but the operands is exactly one gate
+        let unique_gates: usize = stage_exprs(S_BOX_DATA)[1..].iter().map(|x| x.len()).sum();
+        assert_eq!(plan.gates().len(), unique_gates);
+        assert_eq!(plan.outputs().len(), 8);
+
+        // The gates only read the slots of earlier stages
+        let mut available = OPERAND_SLOTS;
+        for stage in plan.stages() {
+            for gate in stage {
+                let inputs = match *gate {
+                    Gate::And(x, y) | Gate::Or(x, y) | Gate::Xor(x, y) => vec![x, y],
+                    Gate::Mux(x, y, z) => vec![x, y, z],
+                    Gate::Not(x) => vec![x],
+                };
+                assert!(inputs.iter().all(|&slot| slot < available));
+            }
+            available += stage.len();
+        }
+
+        assert_eq!(plan, EvaluationPlan::compile(&exprs));
+    }
+
+    #[test]
+    fn test_compile_arena() {
+        // The plans compiled from the hash-consed expressions are the plans of the boxed trees
+        for data in [S_BOX_DATA, INV_S_BOX_DATA] {
+            let mut arena = ExprArena::new();
+            let outputs = reduced_exprs(&mut arena, data);
+            assert_eq!(
+                EvaluationPlan::compile_arena(&arena, &outputs),
+                EvaluationPlan::compile(&generate_reduced_bool_expr(data))
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluate_batch_clear() {
+        let plan = EvaluationPlan::compile(&generate_reduced_bool_expr(S_BOX_DATA));
+        let inputs: Vec<Vec<bool>> = (0..=255_u8)
+            .map(|x| (0..8).map(|i| x & (1 << i) != 0).collect())
+            .collect();
+
+        let outputs = plan.evaluate_batch(&inputs, &ClearBackend);
+
+        assert_eq!(outputs.len(), 256);
+        for (x, output) in outputs.iter().enumerate() {
+            let result = output.iter().fold(0, |acc, &bit| (acc << 1) | bit as u8);
+            assert_eq!(result, S_BOX_DATA[x]);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_plan_clear() {
+        let plan = EvaluationPlan::compile(&generate_reduced_bool_expr(INV_S_BOX_DATA));
+
+        for x in 0..=255_u8 {
+            let bits: Vec<bool> = (0..8).map(|i| x & (1 << i) != 0).collect();
+            let result = plan
+                .evaluate(&bits, &ClearBackend)
+                .iter()
+                .fold(0, |acc, &bit| (acc << 1) | bit as u8);
+            assert_eq!(result, INV_S_BOX_DATA[x as usize]);
+        }
+    }
+}
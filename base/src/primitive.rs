@@ -1,91 +1,60 @@
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::sync::{LazyLock, RwLock};
+use std::collections::VecDeque;
 use tfhe::boolean::prelude::*;
-use tfhe::boolean::server_key::*;
 
-use crate::boolean_tree::{BooleanExpr, Operand, Runnable};
-use crate::sbox::*;
-
-pub static INTERNAL_KEY: RwLock<Option<ServerKey>> = const { RwLock::new(None) };
-
-// These static expressions represents the boolean trees for the SBOX and INV_SBOX operations, and are accessed by the sub_bytes and inv_sub_bytes functions.
-pub static S_BOX_EXPR: RwLock<LazyLock<Vec<BooleanExpr>>> =
-    RwLock::new(LazyLock::new(|| generate_reduced_bool_expr(S_BOX_DATA)));
-pub static INV_S_BOX_EXPR: RwLock<LazyLock<Vec<BooleanExpr>>> =
-    RwLock::new(LazyLock::new(|| generate_reduced_bool_expr(INV_S_BOX_DATA)));
-
-pub fn set_server_key(key: &ServerKey) {
-    let mut guard_internal_key = INTERNAL_KEY.write().unwrap();
-    *guard_internal_key = Some(key.clone());
-}
-
-pub fn unset_server_key() {
-    let mut guard_internal_key = INTERNAL_KEY.write().unwrap();
-    *guard_internal_key = None;
-}
-
-#[inline(always)]
-pub fn with_server_key<F, T>(func: F) -> T
-where
-    F: FnOnce(&ServerKey) -> T + std::marker::Send,
-    T: std::marker::Send,
-{
-    let guard_internal_key = INTERNAL_KEY.read().unwrap();
-    let server_key = &guard_internal_key
-        .as_ref()
-        .expect("Set the server key before calling any functions !!");
-    func(server_key)
-}
+use crate::backend::{Backend, ClientBackend};
+use crate::lut::{self, CompiledTable};
+use crate::plan::EvaluationPlan;
 
 /// FHEByte is a struct that represents a byte in the FHE context
 ///
-/// The FHEByte struct is a wrapper around a VecDeque of boolean Ciphertexts.
+/// The FHEByte struct is a wrapper around a VecDeque of bits of a `Backend`, which are boolean Ciphertexts by default.
 /// This byte is in Big Endian format and implements multiple bit manipulation operations.
 /// The FHEByte struct implements the basic bitwise operations such as XOR, AND, OR, and NOT.
-/// It also implements the sub byte operation, which uses the boolean trees generated by the SBOX_EXPR.
-/// It also implements the multiplication by x in GF(2^8) operation, as is required by the mix columns operation.
+/// It also implements the sub byte operation, which evaluates the compiled S-Box plans held by the backend.
+/// It also implements the multiplication by x in GF(2^8) operation, the building block of the textbook mix columns operation.
 
-#[derive(Clone, Debug)]
-pub struct FHEByte {
-    data: VecDeque<Ciphertext>,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FHEByte<T = Ciphertext> {
+    data: VecDeque<T>,
 }
 
-impl FHEByte {
-    pub fn new(bool_data: &[bool], client_key: &ClientKey) -> Self {
+impl<T: Clone + Send + Sync> FHEByte<T> {
+    pub fn new<C: ClientBackend<Bit = T>>(bool_data: &[bool], client_key: &C) -> Self {
         assert!(
             bool_data.len() == 8,
             "FHEByte has to be initialized with 8 booleans !"
         );
         let data = bool_data
             .into_iter()
-            .map(|x| client_key.encrypt(*x).into())
+            .map(|x| client_key.encrypt(*x))
             .collect();
         Self { data }
     }
 
-    pub fn from_u8_enc(value: &u8, client_key: &ClientKey) -> Self {
-        let data: VecDeque<Ciphertext> = (0..8)
+    pub fn from_u8_enc<C: ClientBackend<Bit = T>>(value: &u8, client_key: &C) -> Self {
+        let data: VecDeque<T> = (0..8)
             .rev()
             .map(|i| client_key.encrypt(value & (1 << i) != 0))
             .collect();
         Self { data }
     }
 
-    pub fn from_u8_clear(value: &u8, server_key: &ServerKey) -> Self {
-        let data: VecDeque<Ciphertext> = (0..8)
+    pub fn from_u8_clear<B: Backend<Bit = T>>(value: &u8, server_key: &B) -> Self {
+        let data: VecDeque<T> = (0..8)
             .rev()
-            .map(|i| server_key.trivial_encrypt(value & (1 << i) != 0))
+            .map(|i| server_key.trivial(value & (1 << i) != 0))
             .collect();
         Self { data }
     }
 
-    pub fn decrypt(&self, client_key: &ClientKey) -> Vec<bool> {
+    pub fn decrypt<C: ClientBackend<Bit = T>>(&self, client_key: &C) -> Vec<bool> {
         self.data.iter().map(|x| client_key.decrypt(x)).collect()
     }
 
-    pub fn decrypt_to_u8(&self, client_key: &ClientKey) -> u8 {
+    pub fn decrypt_to_u8<C: ClientBackend<Bit = T>>(&self, client_key: &C) -> u8 {
         self.decrypt(client_key)
             .iter()
             .enumerate()
@@ -93,57 +62,104 @@ impl FHEByte {
             .sum()
     }
 
-    pub fn xor_in_place(&mut self, rhs: &Self, server_key: &ServerKey) {
-        self.data
-            .par_iter_mut()
-            .zip(rhs.data.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| server_key.xor_assign(x, y))
+    pub(crate) fn is_well_formed(&self) -> bool {
+        self.data.len() == 8
+    }
+
+    /// The bits of the byte, most significant first
+    pub fn bits(&self) -> impl Iterator<Item = &T> {
+        self.data.iter()
     }
 
-    pub fn xor(&self, rhs: &Self, server_key: &ServerKey) -> Self {
+    pub fn from_bits(bits: &[T]) -> Self {
+        assert!(
+            bits.len() == 8,
+            "FHEByte has to be initialized with 8 bits !"
+        );
+        Self {
+            data: bits.iter().cloned().collect(),
+        }
+    }
+
+    pub fn xor_in_place<B: Backend<Bit = T>>(&mut self, rhs: &Self, server_key: &B) {
+        server_key.install(|| {
+            self.data
+                .par_iter_mut()
+                .zip(rhs.data.par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| server_key.xor_assign(x, y))
+        })
+    }
+
+    pub fn xor<B: Backend<Bit = T>>(&self, rhs: &Self, server_key: &B) -> Self {
         let mut result = self.clone();
         result.xor_in_place(rhs, server_key);
         result
     }
 
-    pub fn and_in_place(&mut self, rhs: &Self, server_key: &ServerKey) {
-        self.data
-            .par_iter_mut()
-            .zip(rhs.data.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| server_key.and_assign(x, y))
+    pub fn and_in_place<B: Backend<Bit = T>>(&mut self, rhs: &Self, server_key: &B) {
+        server_key.install(|| {
+            self.data
+                .par_iter_mut()
+                .zip(rhs.data.par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| server_key.and_assign(x, y))
+        })
     }
 
-    pub fn and(&self, rhs: &Self, server_key: &ServerKey) -> Self {
+    pub fn and<B: Backend<Bit = T>>(&self, rhs: &Self, server_key: &B) -> Self {
         let mut result = self.clone();
         result.and_in_place(rhs, server_key);
         result
     }
 
-    pub fn or_in_place(&mut self, rhs: &Self, server_key: &ServerKey) {
-        self.data
-            .par_iter_mut()
-            .zip(rhs.data.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| server_key.or_assign(x, y))
+    pub fn or_in_place<B: Backend<Bit = T>>(&mut self, rhs: &Self, server_key: &B) {
+        server_key.install(|| {
+            self.data
+                .par_iter_mut()
+                .zip(rhs.data.par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| server_key.or_assign(x, y))
+        })
     }
 
-    pub fn or(&self, rhs: &Self, server_key: &ServerKey) -> Self {
+    pub fn or<B: Backend<Bit = T>>(&self, rhs: &Self, server_key: &B) -> Self {
         let mut result = self.clone();
         result.or_in_place(rhs, server_key);
         result
     }
 
-    pub fn not_in_place(&mut self, server_key: &ServerKey) {
-        self.data
-            .par_iter_mut()
-            .for_each_with(server_key, |server_key, x| server_key.not_assign(x))
+    pub fn not_in_place<B: Backend<Bit = T>>(&mut self, server_key: &B) {
+        server_key.install(|| {
+            self.data
+                .par_iter_mut()
+                .for_each_with(server_key, |server_key, x| server_key.not_assign(x))
+        })
     }
 
-    pub fn not(&self, server_key: &ServerKey) -> Self {
+    pub fn not<B: Backend<Bit = T>>(&self, server_key: &B) -> Self {
         let mut result = self.clone();
         result.not_in_place(server_key);
         result
     }
 
+    /// Whether the two byte strings are equal, as an encrypted bit: the xnor of every two bits, reduced by `all`.
+    pub fn equals<B: Backend<Bit = T>>(lhs: &[Self], rhs: &[Self], server_key: &B) -> T {
+        assert!(
+            lhs.len() == rhs.len(),
+            "The byte strings do not have the same length: {} and {} !",
+            lhs.len(),
+            rhs.len()
+        );
+        server_key.install(|| {
+            let lhs: Vec<&T> = lhs.iter().flat_map(Self::bits).collect();
+            let rhs: Vec<&T> = rhs.iter().flat_map(Self::bits).collect();
+            let matches: Vec<T> = lhs
+                .par_iter()
+                .zip(rhs.par_iter())
+                .map(|(x, y)| server_key.not(&server_key.xor(x, y)))
+                .collect();
+            all(matches, server_key)
+        })
+    }
+
     fn rotate_right_in_place(&mut self, shift: usize) -> () {
         self.data.rotate_right(shift);
     }
@@ -164,181 +180,155 @@ impl FHEByte {
         result
     }
 
-    fn shift_right_in_place(&mut self, shift: usize, server_key: &ServerKey) -> () {
+    fn shift_right_in_place<B: Backend<Bit = T>>(&mut self, shift: usize, server_key: &B) -> () {
         let shift = shift.clamp(0, 8);
         for _ in 0..shift {
-            self.data.push_front(server_key.trivial_encrypt(false));
+            self.data.push_front(server_key.trivial(false));
             self.data.pop_back();
         }
     }
 
-    fn shift_left_in_place(&mut self, shift: usize, server_key: &ServerKey) -> () {
+    fn shift_left_in_place<B: Backend<Bit = T>>(&mut self, shift: usize, server_key: &B) -> () {
         let shift = shift.clamp(0, 8);
 
         for _ in 0..shift {
-            self.data.push_back(server_key.trivial_encrypt(false));
+            self.data.push_back(server_key.trivial(false));
             self.data.pop_front();
         }
     }
 
-    fn shift_left(&self, shift: usize, server_key: &ServerKey) -> Self {
+    fn shift_left<B: Backend<Bit = T>>(&self, shift: usize, server_key: &B) -> Self {
         let mut result = self.clone();
         result.shift_left_in_place(shift, server_key);
         result
     }
 
-    fn shift_right(&self, shift: usize, server_key: &ServerKey) -> Self {
+    fn shift_right<B: Backend<Bit = T>>(&self, shift: usize, server_key: &B) -> Self {
         let mut result = self.clone();
         result.shift_right_in_place(shift, server_key);
         result
     }
 
-    pub fn trivial_clear(clear_value: u8, server_key: &ServerKey) -> Self {
+    pub fn trivial_clear<B: Backend<Bit = T>>(clear_value: u8, server_key: &B) -> Self {
         let data = (0..8)
             .rev()
             .into_iter()
-            .map(|shift| server_key.trivial_encrypt(clear_value & (1 << shift) != 0))
+            .map(|shift| server_key.trivial(clear_value & (1 << shift) != 0))
             .collect();
         FHEByte { data }
     }
 
-    pub fn trivial_false(server_key: &ServerKey) -> Self {
+    pub fn trivial_false<B: Backend<Bit = T>>(server_key: &B) -> Self {
         Self::trivial_clear(0, server_key)
     }
 
-    pub fn sub_byte(&self, server_key: &ServerKey) -> Self {
-        let curr_data = self.data.iter().rev().cloned().collect::<Vec<_>>();
-
-        let lazy_lock_sbox = S_BOX_EXPR.read().unwrap();
-        let s_box_exprs: &Vec<BooleanExpr> = lazy_lock_sbox.as_ref();
-        let mut hashset: HashSet<BooleanExpr> = HashSet::new();
-        for expr in s_box_exprs.iter() {
-            expr.to_hashset(&mut hashset);
-        }
-
-        let mut grouped_by_stage: Vec<Vec<BooleanExpr>> = vec![Vec::new(); 8];
-
-        // Iterate over each BooleanExpr and insert them into the appropriate HashSet based on their stage
-        for expr in hashset {
-            let stage = expr.stage() as usize;
-            grouped_by_stage[stage].push(expr);
-        }
-
-        // Initialize HashMap with all the operands that are required for the computation
-        let mut operands: HashMap<Operand, Ciphertext> = HashMap::new();
-        operands.insert(Operand::Bit0, curr_data[0].clone());
-        operands.insert(Operand::Bit1, curr_data[1].clone());
-        operands.insert(Operand::Bit2, curr_data[2].clone());
-        operands.insert(Operand::Bit3, curr_data[3].clone());
-        operands.insert(Operand::Bit4, curr_data[4].clone());
-        operands.insert(Operand::Bit5, curr_data[5].clone());
-        operands.insert(Operand::Bit6, curr_data[6].clone());
-        operands.insert(Operand::Bit7, curr_data[7].clone());
-
-        operands.insert(Operand::NotBit0, server_key.not(&curr_data[0]));
-        operands.insert(Operand::NotBit1, server_key.not(&curr_data[1]));
-        operands.insert(Operand::NotBit2, server_key.not(&curr_data[2]));
-        operands.insert(Operand::NotBit3, server_key.not(&curr_data[3]));
-        operands.insert(Operand::NotBit4, server_key.not(&curr_data[4]));
-        operands.insert(Operand::NotBit5, server_key.not(&curr_data[5]));
-        operands.insert(Operand::NotBit6, server_key.not(&curr_data[6]));
-        operands.insert(Operand::NotBit7, server_key.not(&curr_data[7]));
-
-        operands.insert(Operand::True, server_key.trivial_encrypt(true));
-        operands.insert(Operand::False, server_key.trivial_encrypt(false));
-
-        let mut hash_map: HashMap<BooleanExpr, Ciphertext> = HashMap::new();
-        for i in 0..8 {
-            hash_map.extend(
-                grouped_by_stage[i]
-                    .clone()
-                    .into_iter()
-                    .map(|expr| (expr.clone(), Runnable::new(&operands, &hash_map, expr)))
-                    .collect::<Vec<_>>()
-                    .into_par_iter()
-                    .map_with(server_key, |server_key, (expr, runnable)| {
-                        (expr, runnable.run(server_key))
-                    })
-                    .collect::<HashMap<_, _>>()
-                    .into_iter(),
-            );
-        }
-
-        // Once all BooleanExpr are evaluated, we retrieve the relevant Ciphertexts and store them into the FHEByte.
-        let data = s_box_exprs
-            .iter()
-            .map(|expr| hash_map.get(expr).unwrap().clone())
-            .collect();
-
-        FHEByte { data }
+    pub fn sub_byte<B: Backend<Bit = T>>(&self, server_key: &B) -> Self {
+        Self::sub_byte_batch(std::slice::from_ref(self), server_key)
+            .pop()
+            .unwrap()
     }
 
-    pub fn inv_sub_byte(&self, server_key: &ServerKey) -> Self {
-        let curr_data = self.data.iter().rev().cloned().collect::<Vec<_>>();
+    pub fn inv_sub_byte<B: Backend<Bit = T>>(&self, server_key: &B) -> Self {
+        Self::inv_sub_byte_batch(std::slice::from_ref(self), server_key)
+            .pop()
+            .unwrap()
+    }
 
-        let lazy_lock_inv_sbox = INV_S_BOX_EXPR.read().unwrap();
-        let inv_s_box_exprs: &Vec<BooleanExpr> = lazy_lock_inv_sbox.as_ref();
+    /// Substitutes many bytes at once, evaluating every stage of the S-Box plan for all the bytes in a single parallel batch.
+    pub fn sub_byte_batch<B: Backend<Bit = T>>(bytes: &[Self], server_key: &B) -> Vec<Self> {
+        Self::evaluate_plan_batch(bytes, &server_key.s_box_plans().s_box, server_key)
+    }
 
-        let mut hashset: HashSet<BooleanExpr> = HashSet::new();
-        for expr in inv_s_box_exprs.iter() {
-            expr.to_hashset(&mut hashset);
-        }
+    pub fn inv_sub_byte_batch<B: Backend<Bit = T>>(bytes: &[Self], server_key: &B) -> Vec<Self> {
+        Self::evaluate_plan_batch(bytes, &server_key.s_box_plans().inv_s_box, server_key)
+    }
 
-        let mut grouped_by_stage: Vec<Vec<BooleanExpr>> = vec![Vec::new(); 8];
+    /// Substitutes the byte with a clear table of 256 bytes, e.g. a case folding or a custom substitution.
+    ///
+    /// The table goes through the same mux tree compiler as the S-Box: it is compiled once with `LookupTable::from(table).compile()` and
+    /// held by the caller, see `LookupTable`.
+    pub fn lookup<B: Backend<Bit = T>>(&self, table: &CompiledTable, server_key: &B) -> Self {
+        Self::lookup_batch(std::slice::from_ref(self), table, server_key)
+            .pop()
+            .unwrap()
+    }
+
+    /// Substitutes many bytes at once with a clear table, every stage being a single parallel batch as in `sub_byte_batch`.
+    pub fn lookup_batch<B: Backend<Bit = T>>(
+        bytes: &[Self],
+        table: &CompiledTable,
+        server_key: &B,
+    ) -> Vec<Self> {
+        assert!(
+            table.table().input_bits() == 8 && table.table().output_bits() == 8,
+            "The table does not map a byte to a byte !"
+        );
+        Self::evaluate_plan_batch(bytes, table.plan(), server_key)
+    }
 
-        // Iterate over each BooleanExpr and insert them into the appropriate HashSet based on their stage
-        for expr in hashset {
-            let stage = expr.stage() as usize;
-            grouped_by_stage[stage].push(expr);
-        }
+    /// Evaluates a table of 8 input bits on the byte and returns the bits of the entry, most significant first, e.g. the single bit of a
+    /// character class.
+    pub fn lookup_bits<B: Backend<Bit = T>>(
+        &self,
+        table: &CompiledTable,
+        server_key: &B,
+    ) -> Vec<T> {
+        assert!(
+            table.table().input_bits() == 8,
+            "The table does not take a byte !"
+        );
+        let bits: Vec<T> = self.data.iter().cloned().collect();
+        table.evaluate(&bits, server_key)
+    }
+
+    /// Substitutes the byte with a table of 256 encrypted bytes, e.g. a secret S-Box, see `lut::lookup_encrypted_batch`.
+    pub fn lookup_encrypted<B: Backend<Bit = T>>(&self, table: &[Self], server_key: &B) -> Self {
+        Self::lookup_encrypted_batch(std::slice::from_ref(self), table, server_key)
+            .pop()
+            .unwrap()
+    }
+
+    /// Substitutes many bytes at once with the same table of 256 encrypted bytes, every level of the mux trees being a single parallel
+    /// batch.
+    pub fn lookup_encrypted_batch<B: Backend<Bit = T>>(
+        bytes: &[Self],
+        table: &[Self],
+        server_key: &B,
+    ) -> Vec<Self> {
+        assert!(table.len() == 256, "The table has 256 entries !");
+        server_key.install(|| {
+            let indices: Vec<Vec<T>> = bytes
+                .iter()
+                .map(|byte| byte.bits().cloned().collect())
+                .collect();
+            let entries: Vec<Vec<T>> = table
+                .iter()
+                .map(|byte| byte.bits().cloned().collect())
+                .collect();
 
-        // Initialize HashMap with all the operands that are required for the computation
-        let mut operands: HashMap<Operand, Ciphertext> = HashMap::new();
-        operands.insert(Operand::Bit0, curr_data[0].clone());
-        operands.insert(Operand::Bit1, curr_data[1].clone());
-        operands.insert(Operand::Bit2, curr_data[2].clone());
-        operands.insert(Operand::Bit3, curr_data[3].clone());
-        operands.insert(Operand::Bit4, curr_data[4].clone());
-        operands.insert(Operand::Bit5, curr_data[5].clone());
-        operands.insert(Operand::Bit6, curr_data[6].clone());
-        operands.insert(Operand::Bit7, curr_data[7].clone());
-
-        operands.insert(Operand::NotBit0, server_key.not(&curr_data[0]));
-        operands.insert(Operand::NotBit1, server_key.not(&curr_data[1]));
-        operands.insert(Operand::NotBit2, server_key.not(&curr_data[2]));
-        operands.insert(Operand::NotBit3, server_key.not(&curr_data[3]));
-        operands.insert(Operand::NotBit4, server_key.not(&curr_data[4]));
-        operands.insert(Operand::NotBit5, server_key.not(&curr_data[5]));
-        operands.insert(Operand::NotBit6, server_key.not(&curr_data[6]));
-        operands.insert(Operand::NotBit7, server_key.not(&curr_data[7]));
-
-        operands.insert(Operand::True, server_key.trivial_encrypt(true));
-        operands.insert(Operand::False, server_key.trivial_encrypt(false));
-
-        let mut hash_map: HashMap<BooleanExpr, Ciphertext> = HashMap::new();
-        for i in 0..8 {
-            hash_map.extend(
-                grouped_by_stage[i]
-                    .clone()
-                    .into_iter()
-                    .map(|expr| (expr.clone(), Runnable::new(&operands, &hash_map, expr)))
-                    .collect::<Vec<_>>()
-                    .into_par_iter()
-                    .map_with(server_key, |server_key, (expr, runnable)| {
-                        (expr, runnable.run(server_key))
-                    })
-                    .collect::<HashMap<_, _>>()
-                    .into_iter(),
-            );
-        }
+            lut::lookup_encrypted_batch(&indices, &entries, server_key)
+                .into_iter()
+                .map(|data| FHEByte { data: data.into() })
+                .collect()
+        })
+    }
 
-        // Once all BooleanExpr are evaluated, we retrieve the relevant Ciphertexts and store them into the FHEByte.
-        let data = inv_s_box_exprs
+    /// Evaluates a plan of 8 outputs on every byte. The plan indexes the bits from the least significant one, and returns them from the most significant one.
+    fn evaluate_plan_batch<B: Backend<Bit = T>>(
+        bytes: &[Self],
+        plan: &EvaluationPlan,
+        server_key: &B,
+    ) -> Vec<Self> {
+        let inputs: Vec<Vec<T>> = bytes
             .iter()
-            .map(|expr| hash_map.get(expr).unwrap().clone())
+            .map(|byte| byte.data.iter().rev().cloned().collect())
             .collect();
+        let outputs = server_key.install(|| plan.evaluate_batch(&inputs, server_key));
 
-        FHEByte { data }
+        outputs
+            .into_iter()
+            .map(|data| FHEByte { data: data.into() })
+            .collect()
     }
 
     /// This function multiplies the byte by x in GF(2^8) and returns the result.
@@ -346,32 +336,54 @@ impl FHEByte {
     /// This is achieved by first checking if the most significant bit is set.
     /// If it is, then the byte is shifted left by 1 and then XORed with the irreducible polynomial 0x1b.
     /// Otherwise, the byte is just shifted left by 1.
-    pub fn mul_x_gf2_in_place(&mut self, server_key: &ServerKey) {
-        let conditional_bit = self.data[0].clone();
-        self.shift_left_in_place(1, server_key);
-        let irr_poly = FHEByte::trivial_clear(0x1b, server_key);
-
-        self.data = self
-            .data
-            .par_iter()
-            .zip(irr_poly.data.par_iter())
-            .map_with(server_key, |server_key, (x, y)| {
-                server_key.mux(&conditional_bit, &server_key.xor(x, y), x)
-            })
-            .collect();
+    pub fn mul_x_gf2_in_place<B: Backend<Bit = T>>(&mut self, server_key: &B) {
+        server_key.install(|| {
+            let conditional_bit = self.data[0].clone();
+            self.shift_left_in_place(1, server_key);
+            let irr_poly = FHEByte::trivial_clear(0x1b, server_key);
+
+            self.data = self
+                .data
+                .par_iter()
+                .zip(irr_poly.data.par_iter())
+                .map_with(server_key, |server_key, (x, y)| {
+                    // x ^ (c & y) rather than mux(c, x ^ y, x), so that the known bits of the polynomial fold into copies of x or c
+                    server_key.xor(x, &server_key.and(&conditional_bit, y))
+                })
+                .collect();
+        })
     }
 
-    pub fn mul_x_gf2(&self, server_key: &ServerKey) -> Self {
+    pub fn mul_x_gf2<B: Backend<Bit = T>>(&self, server_key: &B) -> Self {
         let mut result = self.clone();
         result.mul_x_gf2_in_place(server_key);
         result
     }
 }
 
+/// Whether all the bits are true, with a balanced and tree, `true` for no bit.
+pub fn all<B: Backend>(mut bits: Vec<B::Bit>, server_key: &B) -> B::Bit {
+    server_key.install(|| {
+        while bits.len() > 1 {
+            bits = bits
+                .par_chunks(2)
+                .map(|pair| match pair {
+                    [lhs, rhs] => server_key.and(lhs, rhs),
+                    [bit] => bit.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        bits.pop().unwrap_or_else(|| server_key.trivial(true))
+    })
+}
+
 #[cfg(test)]
 
 mod tests {
     use super::*;
+    use crate::backend::ClearBackend;
+    use crate::sbox::{INV_S_BOX_DATA, S_BOX_DATA};
     use tfhe::boolean::gen_keys;
 
     fn clear_mul_x_gf2(x: &u8) -> u8 {
@@ -435,6 +447,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_equals() {
+        let backend = ClearBackend;
+        let bytes = |data: &[u8]| -> Vec<FHEByte<bool>> {
+            data.iter()
+                .map(|x| FHEByte::from_u8_enc(x, &backend))
+                .collect()
+        };
+
+        let x = bytes(&[0x12, 0x34, 0x56]);
+        assert!(FHEByte::equals(&x, &bytes(&[0x12, 0x34, 0x56]), &backend));
+        assert!(!FHEByte::equals(&x, &bytes(&[0x12, 0x35, 0x56]), &backend));
+        assert!(FHEByte::equals(&[], &[], &backend));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_equals_different_lengths() {
+        let backend = ClearBackend;
+        let x = FHEByte::from_u8_enc(&0x12, &backend);
+        FHEByte::equals(&[x.clone(), x.clone()], &[x], &backend);
+    }
+
     #[test]
     fn test_sub_byte() {
         let (client_key, server_key) = gen_keys();
@@ -469,4 +504,26 @@ mod tests {
             )
         }
     }
+
+    #[test]
+    fn test_all_sub_byte_clear() {
+        let backend = ClearBackend;
+
+        for clear_value in 0..=255 {
+            let x = FHEByte::from_u8_enc(&clear_value, &backend);
+
+            assert_eq!(
+                x.sub_byte(&backend).decrypt_to_u8(&backend),
+                S_BOX_DATA[clear_value as usize]
+            );
+            assert_eq!(
+                x.inv_sub_byte(&backend).decrypt_to_u8(&backend),
+                INV_S_BOX_DATA[clear_value as usize]
+            );
+            assert_eq!(
+                x.mul_x_gf2(&backend).decrypt_to_u8(&backend),
+                clear_mul_x_gf2(&clear_value)
+            );
+        }
+    }
 }
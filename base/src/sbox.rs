@@ -1,6 +1,12 @@
 use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock};
 
 use crate::boolean_tree::*;
+use crate::boyar_peralta;
+use crate::mux_order;
+use crate::plan::EvaluationPlan;
 
 /// The S-Box used in AES encryption
 pub const S_BOX_DATA: [u8; 256] = [
@@ -42,7 +48,7 @@ pub const INV_S_BOX_DATA: [u8; 256] = [
     0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
 ];
 
-fn bit_x_s_box(data: [u8; 256], position: u8) -> Vec<bool> {
+pub(crate) fn bit_x_s_box(data: [u8; 256], position: u8) -> Vec<bool> {
     data.iter()
         .rev()
         .map(|&x| (x & (1 << position)) != 0)
@@ -51,15 +57,119 @@ fn bit_x_s_box(data: [u8; 256], position: u8) -> Vec<bool> {
 
 /// Generates the reduced Boolean expressions for the S-Box or the Inv S-Box
 pub fn generate_reduced_bool_expr(data: [u8; 256]) -> Vec<BooleanExpr> {
-    (0..8)
-        .rev()
+    let mut arena = ExprArena::new();
+    reduced_exprs(&mut arena, data)
         .into_iter()
-        .map(|x| bit_x_s_box(data, x))
-        .map(|x| BooleanExpr::from_bool_vec(&x))
-        .map(|x| BooleanExpr::reduce_mux(&x))
+        .map(|id| arena.to_expr(id))
         .collect()
 }
 
+/// Generates the reduced expressions of the 8 output bits of the S-Box or the Inv S-Box in an arena, most significant bit first, with the
+/// selector order of `BooleanExpr::reduce_mux`
+pub fn reduced_exprs(arena: &mut ExprArena, data: [u8; 256]) -> Vec<ExprId> {
+    mux_order::ordered_exprs(arena, data, &[mux_order::IDENTITY_ORDER; 8])
+}
+
+/// SBoxCircuit selects the circuit from which the S-Box and the Inv S-Box plans are compiled.
+///
+/// `MuxTree` is the reduced 8-bit multiplexer of `generate_reduced_bool_expr`, with the selector orders searched by `mux_order` for every
+/// output bit, and `BoyarPeralta` the low gate count circuits of the `boyar_peralta` module, which need fewer bootstraps at the cost of a
+/// deeper circuit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SBoxCircuit {
+    #[default]
+    MuxTree,
+    BoyarPeralta,
+}
+
+impl SBoxCircuit {
+    pub const ALL: [SBoxCircuit; 2] = [SBoxCircuit::MuxTree, SBoxCircuit::BoyarPeralta];
+}
+
+impl fmt::Display for SBoxCircuit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SBoxCircuit::MuxTree => write!(f, "mux-tree"),
+            SBoxCircuit::BoyarPeralta => write!(f, "boyar-peralta"),
+        }
+    }
+}
+
+impl FromStr for SBoxCircuit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mux-tree" => Ok(SBoxCircuit::MuxTree),
+            "boyar-peralta" => Ok(SBoxCircuit::BoyarPeralta),
+            _ => Err(format!(
+                "Invalid S-Box circuit {}, expected mux-tree or boyar-peralta",
+                s
+            )),
+        }
+    }
+}
+
+/// SBoxPlans holds the compiled evaluation plans of the S-Box and of the Inv S-Box used by `sub_byte` and `inv_sub_byte`.
+#[derive(Clone, Debug)]
+pub struct SBoxPlans {
+    pub circuit: SBoxCircuit,
+    pub s_box: EvaluationPlan,
+    pub inv_s_box: EvaluationPlan,
+}
+
+static SHARED_S_BOX_PLANS: LazyLock<Arc<SBoxPlans>> =
+    LazyLock::new(|| Arc::new(SBoxPlans::compile(SBoxCircuit::MuxTree)));
+
+static SHARED_BOYAR_PERALTA_PLANS: LazyLock<Arc<SBoxPlans>> =
+    LazyLock::new(|| Arc::new(SBoxPlans::compile(SBoxCircuit::BoyarPeralta)));
+
+impl SBoxPlans {
+    pub fn new() -> Self {
+        Self::compile(SBoxCircuit::MuxTree)
+    }
+
+    pub fn compile(circuit: SBoxCircuit) -> Self {
+        match circuit {
+            SBoxCircuit::MuxTree => SBoxPlans {
+                circuit,
+                s_box: mux_order::compile(S_BOX_DATA, &mux_order::S_BOX_ORDERS),
+                inv_s_box: mux_order::compile(INV_S_BOX_DATA, &mux_order::INV_S_BOX_ORDERS),
+            },
+            // The plans index the input bits from the least significant one, the circuits from the most significant one
+            SBoxCircuit::BoyarPeralta => SBoxPlans {
+                circuit,
+                s_box: EvaluationPlan::trace(|builder, inputs| {
+                    let bits: Vec<usize> = inputs.iter().rev().copied().collect();
+                    boyar_peralta::s_box(&bits, builder)
+                }),
+                inv_s_box: EvaluationPlan::trace(|builder, inputs| {
+                    let bits: Vec<usize> = inputs.iter().rev().copied().collect();
+                    boyar_peralta::inv_s_box(&bits, builder)
+                }),
+            },
+        }
+    }
+
+    /// The plans compiled once for the whole process, which backends use unless they hold their own.
+    pub fn shared() -> &'static Arc<SBoxPlans> {
+        Self::shared_for(SBoxCircuit::MuxTree)
+    }
+
+    pub fn shared_for(circuit: SBoxCircuit) -> &'static Arc<SBoxPlans> {
+        match circuit {
+            SBoxCircuit::MuxTree => &SHARED_S_BOX_PLANS,
+            SBoxCircuit::BoyarPeralta => &SHARED_BOYAR_PERALTA_PLANS,
+        }
+    }
+}
+
+impl Default for SBoxPlans {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn stage_exprs(data: [u8; 256]) -> Vec<HashSet<BooleanExpr>> {
     // Depreciated method, used for testing purposes
     let s_box_exprs = generate_reduced_bool_expr(data);
@@ -0,0 +1,403 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use tfhe::boolean::prelude::*;
+
+use crate::{FHEByte, Key, State};
+
+/// The magic number opening every envelope written by this crate
+pub const MAGIC: [u8; 4] = *b"TFAE";
+
+/// The version of the envelope format, bumped whenever the serialized representation of the payloads changes
+pub const FORMAT_VERSION: u16 = 1;
+
+/// This enum identifies the tfhe boolean parameter set under which the ciphertexts of an envelope were encrypted.
+///
+/// Ciphertexts encrypted under one parameter set cannot be evaluated with a server key of another, so the set is recorded
+/// alongside the payload and checked when reading it back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParameterSet {
+    Default,
+    DefaultKsPbs,
+    ErrorProb2PowMinus165,
+    ErrorProb2PowMinus165KsPbs,
+    TfheLib,
+}
+
+impl ParameterSet {
+    pub fn parameters(&self) -> BooleanParameters {
+        match self {
+            ParameterSet::Default => DEFAULT_PARAMETERS,
+            ParameterSet::DefaultKsPbs => DEFAULT_PARAMETERS_KS_PBS,
+            ParameterSet::ErrorProb2PowMinus165 => PARAMETERS_ERROR_PROB_2_POW_MINUS_165,
+            ParameterSet::ErrorProb2PowMinus165KsPbs => {
+                PARAMETERS_ERROR_PROB_2_POW_MINUS_165_KS_PBS
+            }
+            ParameterSet::TfheLib => TFHE_LIB_PARAMETERS,
+        }
+    }
+}
+
+/// This enum describes the order of the bytes of a State or a Key in the payload.
+///
+/// `Transposed` is the transposed matrix used by `State` and `Key` in memory, while `RowMajor` is the order of the bytes in the AES block,
+/// as accepted by `from_u8_enc`. Payloads written by this crate are always `Transposed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Layout {
+    Transposed,
+    RowMajor,
+}
+
+/// This enum identifies the type of the payload of an envelope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayloadKind {
+    FHEByte,
+    State,
+    Key,
+    States,
+    Keys,
+}
+
+/// This trait is implemented by the types that can be shipped in an envelope.
+pub trait Payload: Serialize + DeserializeOwned {
+    const KIND: PayloadKind;
+
+    /// Checks the shape of a deserialized payload (8 bits per byte, 16 bytes per state, 16, 24 or 32 bytes per key)
+    fn is_well_formed(&self) -> bool;
+}
+
+impl Payload for FHEByte {
+    const KIND: PayloadKind = PayloadKind::FHEByte;
+
+    fn is_well_formed(&self) -> bool {
+        self.is_well_formed()
+    }
+}
+
+impl Payload for State {
+    const KIND: PayloadKind = PayloadKind::State;
+
+    fn is_well_formed(&self) -> bool {
+        self.is_well_formed()
+    }
+}
+
+impl Payload for Key {
+    const KIND: PayloadKind = PayloadKind::Key;
+
+    fn is_well_formed(&self) -> bool {
+        self.is_well_formed()
+    }
+}
+
+impl Payload for Vec<State> {
+    const KIND: PayloadKind = PayloadKind::States;
+
+    fn is_well_formed(&self) -> bool {
+        self.iter().all(|state| state.is_well_formed())
+    }
+}
+
+impl Payload for Vec<Key> {
+    const KIND: PayloadKind = PayloadKind::Keys;
+
+    fn is_well_formed(&self) -> bool {
+        self.iter().all(|key| key.is_well_formed())
+    }
+}
+
+/// The header following the magic number and the format version
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Header {
+    parameters: ParameterSet,
+    layout: Layout,
+    kind: PayloadKind,
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    ParameterMismatch {
+        expected: ParameterSet,
+        found: ParameterSet,
+    },
+    LayoutMismatch {
+        expected: Layout,
+        found: Layout,
+    },
+    PayloadMismatch {
+        expected: PayloadKind,
+        found: PayloadKind,
+    },
+    MalformedPayload(PayloadKind),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Io(err) => write!(f, "I/O error: {}", err),
+            EnvelopeError::Encoding(err) => write!(f, "Encoding error: {}", err),
+            EnvelopeError::BadMagic(magic) => write!(
+                f,
+                "Not a tfhe-aes envelope: expected magic {:?}, found {:?}",
+                MAGIC, magic
+            ),
+            EnvelopeError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported envelope version {}, this build reads version {}",
+                version, FORMAT_VERSION
+            ),
+            EnvelopeError::ParameterMismatch { expected, found } => write!(
+                f,
+                "The payload was encrypted with the {:?} parameter set, expected {:?}",
+                found, expected
+            ),
+            EnvelopeError::LayoutMismatch { expected, found } => write!(
+                f,
+                "The payload is stored in the {:?} layout, expected {:?}",
+                found, expected
+            ),
+            EnvelopeError::PayloadMismatch { expected, found } => write!(
+                f,
+                "The envelope holds a {:?} payload, expected {:?}",
+                found, expected
+            ),
+            EnvelopeError::MalformedPayload(kind) => {
+                write!(f, "The {:?} payload does not have the expected shape", kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+impl From<std::io::Error> for EnvelopeError {
+    fn from(err: std::io::Error) -> Self {
+        EnvelopeError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for EnvelopeError {
+    fn from(err: bincode::Error) -> Self {
+        EnvelopeError::Encoding(err)
+    }
+}
+
+/// Writes the payload in a versioned envelope: magic number, format version, header (parameter set, layout and payload type) and payload.
+pub fn serialize_into<T: Payload, W: Write>(
+    payload: &T,
+    parameters: ParameterSet,
+    mut writer: W,
+) -> Result<(), EnvelopeError> {
+    let header = Header {
+        parameters,
+        layout: Layout::Transposed,
+        kind: T::KIND,
+    };
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, &header)?;
+    bincode::serialize_into(&mut writer, payload)?;
+    Ok(())
+}
+
+/// Reads a payload written by `serialize_into`, rejecting envelopes that do not match the expected parameter set, layout or payload type.
+pub fn deserialize_from<T: Payload, R: Read>(
+    mut reader: R,
+    parameters: ParameterSet,
+) -> Result<T, EnvelopeError> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(EnvelopeError::BadMagic(magic));
+    }
+
+    let mut version = [0u8; 2];
+    reader.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(EnvelopeError::UnsupportedVersion(version));
+    }
+
+    let header: Header = bincode::deserialize_from(&mut reader)?;
+    if header.parameters != parameters {
+        return Err(EnvelopeError::ParameterMismatch {
+            expected: parameters,
+            found: header.parameters,
+        });
+    }
+    if header.layout != Layout::Transposed {
+        return Err(EnvelopeError::LayoutMismatch {
+            expected: Layout::Transposed,
+            found: header.layout,
+        });
+    }
+    if header.kind != T::KIND {
+        return Err(EnvelopeError::PayloadMismatch {
+            expected: T::KIND,
+            found: header.kind,
+        });
+    }
+
+    let payload: T = bincode::deserialize_from(&mut reader)?;
+    if !payload.is_well_formed() {
+        return Err(EnvelopeError::MalformedPayload(T::KIND));
+    }
+
+    Ok(payload)
+}
+
+pub fn to_bytes<T: Payload>(
+    payload: &T,
+    parameters: ParameterSet,
+) -> Result<Vec<u8>, EnvelopeError> {
+    let mut bytes = Vec::new();
+    serialize_into(payload, parameters, &mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn from_bytes<T: Payload>(bytes: &[u8], parameters: ParameterSet) -> Result<T, EnvelopeError> {
+    deserialize_from(bytes, parameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tfhe::boolean::gen_keys;
+
+    #[test]
+    fn test_round_trip() {
+        let (client_key, _) = gen_keys();
+
+        let state = State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0370734, &client_key);
+        let bytes = to_bytes(&state, ParameterSet::Default).unwrap();
+        let state: State = from_bytes(&bytes, ParameterSet::Default).unwrap();
+        assert_eq!(
+            state.decrypt_to_u128(&client_key),
+            0x3243f6a8_885a308d_313198a2_e0370734
+        );
+
+        let key_data: Vec<u8> = (0..24).collect();
+        let key = Key::from_u8_enc(&key_data, &client_key);
+        let bytes = to_bytes(&key, ParameterSet::Default).unwrap();
+        let key: Key = from_bytes(&bytes, ParameterSet::Default).unwrap();
+        assert_eq!(key.decrypt_to_u8(&client_key), key_data);
+
+        let byte = FHEByte::from_u8_enc(&0xa5, &client_key);
+        let bytes = to_bytes(&byte, ParameterSet::Default).unwrap();
+        let byte: FHEByte = from_bytes(&bytes, ParameterSet::Default).unwrap();
+        assert_eq!(byte.decrypt_to_u8(&client_key), 0xa5);
+    }
+
+    /// A state of trivial ciphertexts, which needs no key
+    fn trivial_state(value: u128) -> State {
+        let bits: Vec<Ciphertext> = (0..128)
+            .rev()
+            .map(|i| Ciphertext::Trivial((value >> i) & 1 != 0))
+            .collect();
+        State::from_bits(&bits)
+    }
+
+    /// An envelope of the given payload type, whose payload is written by `payload`
+    fn envelope(kind: PayloadKind, payload: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(FORMAT_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut bytes, &Header {
+            parameters: ParameterSet::Default,
+            layout: Layout::Transposed,
+            kind,
+        })
+        .unwrap();
+        payload(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_rejects_mismatched_envelopes() {
+        let state = trivial_state(0x3243f6a8_885a308d_313198a2_e0370734);
+        let bytes = to_bytes(&state, ParameterSet::Default).unwrap();
+        assert!(from_bytes::<State>(&bytes, ParameterSet::Default).is_ok());
+
+        assert!(matches!(
+            from_bytes::<State>(&bytes, ParameterSet::TfheLib),
+            Err(EnvelopeError::ParameterMismatch { .. })
+        ));
+        assert!(matches!(
+            from_bytes::<Key>(&bytes, ParameterSet::Default),
+            Err(EnvelopeError::PayloadMismatch { .. })
+        ));
+
+        let mut corrupted = bytes.clone();
+        corrupted[0] ^= 0xff;
+        assert!(matches!(
+            from_bytes::<State>(&corrupted, ParameterSet::Default),
+            Err(EnvelopeError::BadMagic(_))
+        ));
+
+        let mut corrupted = bytes.clone();
+        corrupted[4] = 0xff;
+        assert!(matches!(
+            from_bytes::<State>(&corrupted, ParameterSet::Default),
+            Err(EnvelopeError::UnsupportedVersion(_))
+        ));
+
+        let mut row_major = bytes[..6].to_vec();
+        bincode::serialize_into(&mut row_major, &Header {
+            parameters: ParameterSet::Default,
+            layout: Layout::RowMajor,
+            kind: PayloadKind::State,
+        })
+        .unwrap();
+        assert!(matches!(
+            from_bytes::<State>(&row_major, ParameterSet::Default),
+            Err(EnvelopeError::LayoutMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rejects_malformed_payloads() {
+        let bits = trivial_state(0x3243f6a8_885a308d_313198a2_e0370734).to_bits();
+        let bytes: Vec<Vec<Ciphertext>> = bits.chunks(8).map(<[_]>::to_vec).collect();
+
+        // A key of 15 bytes
+        let key = Key {
+            data: bytes[..15]
+                .iter()
+                .map(|byte| FHEByte::from_bits(byte))
+                .collect(),
+        };
+        let envelope_bytes = to_bytes(&key, ParameterSet::Default).unwrap();
+        assert!(matches!(
+            from_bytes::<Key>(&envelope_bytes, ParameterSet::Default),
+            Err(EnvelopeError::MalformedPayload(PayloadKind::Key))
+        ));
+
+        // A state whose last byte has 7 bits
+        let envelope_bytes = envelope(PayloadKind::State, |payload| {
+            for (i, byte) in bytes.iter().enumerate() {
+                let len = if i == 15 { 7 } else { 8 };
+                bincode::serialize_into(&mut *payload, &byte[..len]).unwrap();
+            }
+        });
+        assert!(matches!(
+            from_bytes::<State>(&envelope_bytes, ParameterSet::Default),
+            Err(EnvelopeError::MalformedPayload(PayloadKind::State))
+        ));
+
+        // A state of 15 bytes is cut short, as a state is always 16 bytes
+        let envelope_bytes = envelope(PayloadKind::State, |payload| {
+            for byte in bytes[..15].iter() {
+                bincode::serialize_into(&mut *payload, byte).unwrap();
+            }
+        });
+        assert!(matches!(
+            from_bytes::<State>(&envelope_bytes, ParameterSet::Default),
+            Err(EnvelopeError::Encoding(_))
+        ));
+    }
+}
@@ -1,19 +1,23 @@
 use crate::Key;
+use crate::backend::{Backend, ClientBackend};
+use crate::mix_columns::{INV_MIX_COLUMN, LinearCircuit, MIX_COLUMN};
 use crate::primitive::*;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
 use tfhe::boolean::prelude::*;
 
 /// This represents the state of a 128-bit block in AES-128. The matrix is represented in a transposed manner, and all algorithms are implemented as such.
 /// For more details for each algorithm, refer to the [Efficient Implementation of AES in 32 bit systems](https://link.springer.com/content/pdf/10.1007/3-540-36400-5_13.pdf) paper.
-#[derive(Clone)]
-pub struct State {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct State<T = Ciphertext> {
     // This matrix is the transposed state matrix.
-    data: [FHEByte; 16],
+    data: [FHEByte<T>; 16],
 }
 
-impl State {
-    pub fn from_u128_enc(value: u128, client_key: &ClientKey) -> Self {
-        let mut data: [FHEByte; 16] = (0..16)
+impl<T: Clone + Send + Sync + Debug> State<T> {
+    pub fn from_u128_enc<C: ClientBackend<Bit = T>>(value: u128, client_key: &C) -> Self {
+        let mut data: [FHEByte<T>; 16] = (0..16)
             .rev()
             .map(|i| FHEByte::from_u8_enc(&{ ((value >> 8 * i) & 0xFF) as u8 }, client_key))
             .collect::<Vec<_>>()
@@ -33,7 +37,7 @@ impl State {
         State { data }
     }
 
-    pub fn from_u8_enc(data: &[u8; 16], client_key: &ClientKey) -> Self {
+    pub fn from_u8_enc<C: ClientBackend<Bit = T>>(data: &[u8; 16], client_key: &C) -> Self {
         let mut data = data.map(|value| FHEByte::from_u8_enc(&value, client_key));
 
         // TRANSPOSE INPUT DATA
@@ -49,211 +53,169 @@ impl State {
         State { data }
     }
 
-    pub fn sub_bytes(&mut self, server_key: &ServerKey) {
-        self.data = self
-            .data
-            .par_iter()
-            .map_with(server_key, |server_key, byte| byte.sub_byte(server_key))
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+    /// Creates a state of trivially encrypted bytes, the big endian bytes of `value` as for `from_u128_enc`.
+    pub fn from_u128_clear<B: Backend<Bit = T>>(value: u128, server_key: &B) -> Self {
+        Self::from_u8_clear(&value.to_be_bytes(), server_key)
     }
 
-    pub fn inv_sub_bytes(&mut self, server_key: &ServerKey) {
-        self.data = self
-            .data
-            .par_iter()
-            .map_with(server_key, |server_key, byte| byte.inv_sub_byte(server_key))
+    pub fn from_u8_clear<B: Backend<Bit = T>>(data: &[u8; 16], server_key: &B) -> Self {
+        let mut data = data.map(|value| FHEByte::from_u8_clear(&value, server_key));
+
+        // TRANSPOSE INPUT DATA
+        data.swap(1, 4);
+        data.swap(2, 8);
+        data.swap(3, 12);
+
+        data.swap(6, 9);
+        data.swap(7, 13);
+
+        data.swap(11, 14);
+
+        State { data }
+    }
+
+    /// Creates a state from its 128 bits, in the order of the bytes of `from_u8_enc` and each most significant bit first.
+    pub fn from_bits(bits: &[T]) -> Self {
+        assert!(bits.len() == 128, "A state has 128 bits !");
+        let mut data: [FHEByte<T>; 16] = bits
+            .chunks(8)
+            .map(FHEByte::from_bits)
             .collect::<Vec<_>>()
             .try_into()
             .unwrap();
+
+        // TRANSPOSE INPUT DATA
+        data.swap(1, 4);
+        data.swap(2, 8);
+        data.swap(3, 12);
+
+        data.swap(6, 9);
+        data.swap(7, 13);
+
+        data.swap(11, 14);
+
+        State { data }
     }
 
-    pub fn mix_columns(&mut self, server_key: &ServerKey) {
-        let data_order: [usize; 16] = [8, 9, 10, 11, 8, 9, 10, 11, 0, 1, 2, 3, 0, 1, 2, 3];
+    /// The 128 bits of the state, in the order of `from_bits`
+    pub fn to_bits(&self) -> Vec<T> {
+        let mut data = self.data.clone();
 
-        let mut y: [FHEByte; 16] = data_order
-            .into_par_iter()
-            .map_with(server_key, |server_key, i| {
-                self.data[i].xor(&self.data[4 + i], server_key)
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+        // TRANSPOSE OUTPUT DATA
+        data.swap(1, 4);
+        data.swap(2, 8);
+        data.swap(3, 12);
 
-        let data_chained = self.data[4..8]
-            .par_iter()
-            .chain(self.data[..4].par_iter())
-            .chain(self.data[12..].par_iter())
-            .chain(self.data[8..12].par_iter());
-        y.par_iter_mut()
-            .zip(data_chained)
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
-
-        self.data
-            .par_iter_mut()
-            .for_each_with(server_key, |server_key, x| x.mul_x_gf2_in_place(server_key));
-
-        y.par_iter_mut()
-            .zip(self.data.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
-
-        let data_chained = self.data[4..8]
-            .par_iter()
-            .chain(self.data[8..12].par_iter())
-            .chain(self.data[12..].par_iter())
-            .chain(self.data[..4].par_iter());
-
-        y.par_iter_mut()
-            .zip(data_chained)
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
-
-        self.data = y
+        data.swap(6, 9);
+        data.swap(7, 13);
+
+        data.swap(11, 14);
+
+        data.iter().flat_map(|byte| byte.bits().cloned()).collect()
     }
 
-    pub fn inv_mix_columns(&mut self, server_key: &ServerKey) {
-        let mut y: [FHEByte; 16] = (0..16_usize)
-            .into_par_iter()
-            .map_with(server_key, |server_key, i| {
-                self.data[i].xor(&self.data[(i + 4) % 16], server_key)
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
-        // y_i = (x_i ^ x_(i + 1) % 4)
+    pub(crate) fn is_well_formed(&self) -> bool {
+        self.data.iter().all(|byte| byte.is_well_formed())
+    }
 
-        let mut temp_0: [FHEByte; 8] = (0..8)
-            .into_par_iter()
-            .map_with(server_key, |server_key, i| {
-                self.data[i].xor(&self.data[i + 8], server_key)
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap(); // (X_i XOR X_(i + 2))
+    pub fn sub_bytes<B: Backend<Bit = T>>(&mut self, server_key: &B) {
+        Self::sub_bytes_batch(std::slice::from_mut(self), server_key);
+    }
 
-        temp_0
-            .par_iter_mut()
-            .for_each_with(server_key, |server_key, x| x.mul_x_gf2_in_place(server_key)); // 02 * (X_i XOR X_(i + 2))
+    pub fn inv_sub_bytes<B: Backend<Bit = T>>(&mut self, server_key: &B) {
+        Self::inv_sub_bytes_batch(std::slice::from_mut(self), server_key);
+    }
 
-        temp_0
-            .par_iter_mut()
-            .for_each_with(server_key, |server_key, x| x.mul_x_gf2_in_place(server_key)); // 04 * (X_i XOR X_(i + 2))
+    /// Substitutes the bytes of all the states in one batch, so that every stage of the S-Box is a single parallel launch over all their bytes.
+    pub fn sub_bytes_batch<B: Backend<Bit = T>>(states: &mut [State<T>], server_key: &B) {
+        let bytes: Vec<_> = states.iter().flat_map(|x| x.data.iter().cloned()).collect();
+        Self::replace_bytes(states, FHEByte::sub_byte_batch(&bytes, server_key));
+    }
 
-        let mut temp_1: [FHEByte; 4] = y[..4]
-            .par_iter()
-            .zip(y[8..12].par_iter())
-            .map_with(server_key, |server_key, (x, y)| x.xor(y, server_key))
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap(); // (X_0 ^ X_1 ^ X_2 ^ X_3)
-
-        let temp_0_iter = temp_0[..4]
-            .par_iter()
-            .chain(temp_0[4..].par_iter())
-            .chain(temp_0[..4].par_iter())
-            .chain(temp_0[4..].par_iter());
-
-        y.par_iter_mut()
-            .for_each_with(server_key, |server_key, x| x.mul_x_gf2_in_place(server_key));
-
-        y.par_iter_mut()
-            .zip(temp_0_iter)
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
-
-        let temp_1_iter = temp_1
-            .par_iter()
-            .chain(temp_1.par_iter())
-            .chain(temp_1.par_iter())
-            .chain(temp_1.par_iter());
-
-        self.data
-            .par_iter_mut()
-            .zip(y.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
+    pub fn inv_sub_bytes_batch<B: Backend<Bit = T>>(states: &mut [State<T>], server_key: &B) {
+        let bytes: Vec<_> = states.iter().flat_map(|x| x.data.iter().cloned()).collect();
+        Self::replace_bytes(states, FHEByte::inv_sub_byte_batch(&bytes, server_key));
+    }
 
-        self.data
-            .par_iter_mut()
-            .zip(temp_1_iter)
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
+    fn replace_bytes(states: &mut [State<T>], bytes: Vec<FHEByte<T>>) {
+        for (state, data) in states.iter_mut().zip(bytes.chunks(16)) {
+            state.data = data.to_vec().try_into().unwrap();
+        }
+    }
 
-        temp_1
-            .par_iter_mut()
-            .for_each_with(server_key, |server_key, x| x.mul_x_gf2_in_place(server_key));
+    pub fn mix_columns<B: Backend<Bit = T>>(&mut self, server_key: &B) {
+        self.apply_linear_layer(&MIX_COLUMN, server_key);
+    }
 
-        temp_1
-            .par_iter_mut()
-            .for_each_with(server_key, |server_key, x| x.mul_x_gf2_in_place(server_key));
+    pub fn inv_mix_columns<B: Backend<Bit = T>>(&mut self, server_key: &B) {
+        self.apply_linear_layer(&INV_MIX_COLUMN, server_key);
+    }
 
-        temp_1
-            .par_iter_mut()
-            .for_each_with(server_key, |server_key, x| x.mul_x_gf2_in_place(server_key)); //08 * (X_0 ^ X_1 ^ X_2 ^ X_3)
+    /// Evaluates the circuit on the 4 columns at once. The data being transposed, the column `j` is made of the bytes `j`, `4 + j`, `8 + j` and `12 + j`.
+    fn apply_linear_layer<B: Backend<Bit = T>>(&mut self, circuit: &LinearCircuit, server_key: &B) {
+        let columns: Vec<Vec<T>> = (0..4)
+            .map(|j| {
+                (0..4)
+                    .flat_map(|i| self.data[4 * i + j].bits().cloned())
+                    .collect()
+            })
+            .collect();
 
-        let temp_1_iter = temp_1
-            .par_iter()
-            .chain(temp_1.par_iter())
-            .chain(temp_1.par_iter())
-            .chain(temp_1.par_iter());
+        let columns = server_key.install(|| circuit.evaluate_batch(&columns, server_key));
 
-        self.data
-            .par_iter_mut()
-            .zip(temp_1_iter)
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
+        for (j, column) in columns.iter().enumerate() {
+            for (i, bits) in column.chunks(8).enumerate() {
+                self.data[4 * i + j] = FHEByte::from_bits(bits);
+            }
+        }
     }
 
-    pub fn xor_key_enc(&mut self, key: &Key, server_key: &ServerKey) {
-        self.data
-            .par_iter_mut()
-            .zip(key.data.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
+    pub fn xor_key_enc<B: Backend<Bit = T>>(&mut self, key: &Key<T>, server_key: &B) {
+        server_key.install(|| {
+            self.data
+                .par_iter_mut()
+                .zip(key.data.par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| {
+                    x.xor_in_place(y, server_key)
+                });
+        })
     }
 
-    pub fn xor_key_clear(&mut self, key: &[u8; 16], server_key: &ServerKey) {
-        let mut key_data = key.clone();
+    pub fn xor_key_clear<B: Backend<Bit = T>>(&mut self, key: &[u8; 16], server_key: &B) {
+        server_key.install(|| {
+            let mut key_data = key.clone();
 
-        // TRANSPOSE INPUT DATA
-        key_data.swap(1, 4);
-        key_data.swap(2, 8);
-        key_data.swap(3, 12);
+            // TRANSPOSE INPUT DATA
+            key_data.swap(1, 4);
+            key_data.swap(2, 8);
+            key_data.swap(3, 12);
 
-        key_data.swap(6, 9);
-        key_data.swap(7, 13);
+            key_data.swap(6, 9);
+            key_data.swap(7, 13);
 
-        key_data.swap(11, 14);
+            key_data.swap(11, 14);
 
-        self.data
-            .par_iter_mut()
-            .zip(key_data.into_par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(&FHEByte::trivial_clear(y, server_key), server_key)
-            });
+            self.data
+                .par_iter_mut()
+                .zip(key_data.into_par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| {
+                    x.xor_in_place(&FHEByte::trivial_clear(y, server_key), server_key)
+                });
+        })
     }
 
-    pub fn xor_state(&mut self, state: &State, server_key: &ServerKey) {
-        self.data
-            .par_iter_mut()
-            .zip(state.data.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| {
-                x.xor_in_place(y, server_key)
-            });
+    pub fn xor_state<B: Backend<Bit = T>>(&mut self, state: &State<T>, server_key: &B) {
+        server_key.install(|| {
+            self.data
+                .par_iter_mut()
+                .zip(state.data.par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| {
+                    x.xor_in_place(y, server_key)
+                });
+        })
     }
 
-    pub fn decrypt_to_u8(&self, client_key: &ClientKey) -> [u8; 16] {
+    pub fn decrypt_to_u8<C: ClientBackend<Bit = T>>(&self, client_key: &C) -> [u8; 16] {
         let mut decrypted_data: [u8; 16] = self
             .data
             .iter()
@@ -281,7 +243,7 @@ impl State {
         decrypted_data
     }
 
-    pub fn decrypt_to_u128(&self, client_key: &ClientKey) -> u128 {
+    pub fn decrypt_to_u128<C: ClientBackend<Bit = T>>(&self, client_key: &C) -> u128 {
         let u8_data = self.decrypt_to_u8(client_key);
         let mut res: u128 = 0;
         for i in 0..15 {
@@ -0,0 +1,116 @@
+use base::*;
+use tfhe::boolean::prelude::*;
+
+/// FheBlockMode is the interface shared by the AES modes of operation: `ECB`, `CBC`, `CFB`, `CTR` and `OFB`.
+///
+/// A mode encrypts or decrypts blocks in place, any number of them per call. A mode object is a stream: it carries its chaining state
+/// (the last ciphertext block of CBC, the shift register of CFB, the output feedback of OFB, the position of CTR) from one call to the
+/// next, so that a long message encrypted chunk by chunk gives the same ciphertext as in a single call. The encryption and the decryption
+/// are two independent streams, both starting from the IV, so the same object decrypts the message it encrypted.
+///
+/// The methods are generic over the backend, so code generic over the mode runs both homomorphically and on the `ClearBackend` or the
+/// `CountingBackend`.
+pub trait FheBlockMode<T = Ciphertext> {
+    fn encrypt<B: Backend<Bit = T>>(&mut self, blocks: &mut [State<T>], server_key: &B);
+
+    fn decrypt<B: Backend<Bit = T>>(&mut self, blocks: &mut [State<T>], server_key: &B);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfb::{CFB, SegmentSize};
+    use crate::test_utils::*;
+    use crate::{cbc::CBC, ctr::CTR, ecb::ECB, ofb::OFB};
+
+    /// Applies the mode to the blocks in chunks of the given sizes
+    fn in_chunks<M: FheBlockMode<bool>>(
+        mode: &mut M,
+        blocks: &[[u8; 16]],
+        chunks: &[usize],
+        decrypt: bool,
+        backend: &ClearBackend,
+    ) -> Vec<[u8; 16]> {
+        assert_eq!(chunks.iter().sum::<usize>(), blocks.len());
+
+        let mut states: Vec<_> = blocks
+            .iter()
+            .map(|block| State::from_u8_enc(block, backend))
+            .collect();
+        let mut rest = &mut states[..];
+        for &chunk in chunks {
+            let (head, tail) = rest.split_at_mut(chunk);
+            if decrypt {
+                mode.decrypt(head, backend);
+            } else {
+                mode.encrypt(head, backend);
+            }
+            rest = tail;
+        }
+        states.iter().map(|x| x.decrypt_to_u8(backend)).collect()
+    }
+
+    /// Checks that a message encrypted in a single call, and in chunks by a fresh mode, gives the same ciphertext, which decrypts in other
+    /// chunks
+    fn check_chunks<M: FheBlockMode<bool>>(new_mode: impl Fn() -> M, backend: &ClearBackend) {
+        let blocks = plaintext_blocks();
+
+        let mut mode = new_mode();
+        let ciphertext = in_chunks(&mut mode, &blocks, &[4], false, backend);
+        assert_eq!(
+            in_chunks(&mut mode, &ciphertext, &[1, 3], true, backend),
+            blocks
+        );
+
+        let mut mode = new_mode();
+        assert_eq!(
+            in_chunks(&mut mode, &blocks, &[2, 0, 2], false, backend),
+            ciphertext
+        );
+        assert_eq!(
+            in_chunks(&mut mode, &ciphertext, &[2, 1, 1], true, backend),
+            blocks
+        );
+    }
+
+    #[test]
+    fn test_block_modes_chunks() {
+        let backend = ClearBackend;
+        let keys = Key::from_u8_enc(&KEY_128, &backend).generate_round_keys(&backend);
+        let iv = State::from_u128_enc(IV, &backend);
+        let counter = INITIAL_COUNTER;
+        let counters: Vec<_> = (0..4)
+            .map(|i| State::from_u128_enc(counter.wrapping_add(i), &backend))
+            .collect();
+
+        check_chunks(|| ECB::new(&keys), &backend);
+        check_chunks(|| CBC::new(&keys, &iv), &backend);
+        check_chunks(|| OFB::new(&keys, &iv), &backend);
+        check_chunks(|| CFB::new(&keys, &iv, SegmentSize::Block), &backend);
+        check_chunks(|| CFB::new(&keys, &iv, SegmentSize::Byte), &backend);
+        check_chunks(|| CTR::new(&keys, &counters), &backend);
+        check_chunks(
+            || CTR::from_counter(&keys, &counter.to_be_bytes()),
+            &backend,
+        );
+
+        // The clear counters are those of the encrypted ones
+        let blocks = [[0; 16]; 4];
+        assert_eq!(
+            in_chunks(
+                &mut CTR::new(&keys, &counters),
+                &blocks,
+                &[4],
+                false,
+                &backend
+            ),
+            in_chunks(
+                &mut CTR::from_counter(&keys, &counter.to_be_bytes()),
+                &blocks,
+                &[4],
+                false,
+                &backend
+            )
+        );
+    }
+}
@@ -1,44 +1,74 @@
+use crate::FheBlockMode;
 use crate::ecb::ECB;
 use base::*;
+use rayon::prelude::*;
+use std::fmt::Debug;
 use tfhe::boolean::prelude::*;
 
-/// CBC mode is the Cipher Block Chaining mode for AES-128
+/// CBC mode is the Cipher Block Chaining mode for AES
+///
+/// The last ciphertext block is kept from one call to the next, for the encryption and for the decryption, so a message can be encrypted
+/// or decrypted in chunks.
 
-pub struct CBC {
-    ecb: ECB,
-    iv: State,
-    n: u8,
+pub struct CBC<T = Ciphertext> {
+    ecb: ECB<T>,
+    encrypt_chain: State<T>,
+    decrypt_chain: State<T>,
 }
 
-impl CBC {
-    pub fn new(keys: &[Key], iv: &State, n: u8) -> Self {
-        CBC {
-            ecb: ECB::new(keys),
-            iv: iv.clone(),
-            n,
-        }
+impl<T: Clone + Send + Sync + Debug> CBC<T> {
+    /// Creates the CBC with public round keys, the chain starting from the encrypted IV.
+    pub fn new_clear<B: Backend<Bit = T>>(
+        round_keys: &[[u8; 16]],
+        iv: &State<T>,
+        server_key: &B,
+    ) -> Self {
+        Self::from_ecb(ECB::new_clear(round_keys, server_key), iv)
     }
 
-    pub fn encrypt(&self, plaintext: &mut [State], server_key: &ServerKey) {
-        plaintext[0].xor_state(&self.iv, server_key);
-        self.ecb.encrypt(&mut plaintext[0], server_key);
+    pub fn new(keys: &[Key<T>], iv: &State<T>) -> Self {
+        Self::from_ecb(ECB::new(keys), iv)
+    }
 
-        for i in 1..self.n as usize {
-            let (prev, curr) = plaintext.split_at_mut(i);
-            curr[0].xor_state(&prev[i - 1], server_key);
-            self.ecb.encrypt(&mut curr[0], server_key);
+    fn from_ecb(ecb: ECB<T>, iv: &State<T>) -> Self {
+        CBC {
+            ecb,
+            encrypt_chain: iv.clone(),
+            decrypt_chain: iv.clone(),
         }
     }
+}
 
-    pub fn decrypt(&self, ciphertexts: &mut [State], server_key: &ServerKey) {
-        for i in (1..self.n as usize).rev() {
-            let (prev, curr) = ciphertexts.split_at_mut(i);
-            self.ecb.decrypt(&mut curr[0], server_key);
-            curr[0].xor_state(&prev[i - 1], server_key);
-        }
+impl<T: Clone + Send + Sync + Debug> FheBlockMode<T> for CBC<T> {
+    fn encrypt<B: Backend<Bit = T>>(&mut self, plaintext: &mut [State<T>], server_key: &B) {
+        server_key.install(|| {
+            for block in plaintext.iter_mut() {
+                block.xor_state(&self.encrypt_chain, server_key);
+                self.ecb.encrypt_block(block, server_key);
+                self.encrypt_chain = block.clone();
+            }
+        })
+    }
 
-        self.ecb.decrypt(&mut ciphertexts[0], server_key);
-        ciphertexts[0].xor_state(&self.iv, server_key);
+    /// Only the encryption is chained: once the ciphertext blocks are known, the blocks are decrypted independently of each other, all in
+    /// the same batches as `ECB::decrypt_blocks`, and then xored with their previous ciphertext blocks.
+    fn decrypt<B: Backend<Bit = T>>(&mut self, ciphertexts: &mut [State<T>], server_key: &B) {
+        let Some(last) = ciphertexts.last().cloned() else {
+            return;
+        };
+
+        server_key.install(|| {
+            let previous: Vec<State<T>> = std::iter::once(self.decrypt_chain.clone())
+                .chain(ciphertexts[..ciphertexts.len() - 1].iter().cloned())
+                .collect();
+
+            self.ecb.decrypt_blocks(ciphertexts, server_key);
+            ciphertexts
+                .par_iter_mut()
+                .zip(previous.par_iter())
+                .for_each(|(block, previous)| block.xor_state(previous, server_key));
+        });
+        self.decrypt_chain = last;
     }
 }
 
@@ -47,7 +77,9 @@ impl CBC {
 mod tests {
 
     use super::*;
-    use std::time::Instant;
+    use crate::test_utils::*;
+    use base::counting::CountingBackend;
+    use base::key_schedule::key_expansion_clear;
     use tfhe::boolean::gen_keys;
 
     #[test]
@@ -57,7 +89,7 @@ mod tests {
         let curr_key = Key::from_u128_enc(0x2b7e1516_28aed2a6a_bf71588_09cf4f3c, &client_key);
         let keys = curr_key.generate_round_keys(&server_key);
         let iv = State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0312122, &client_key);
-        let cbc = CBC::new(&keys, &iv, 2);
+        let mut cbc = CBC::new(&keys, &iv);
 
         let plaintext_block_0 =
             State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0370734, &client_key);
@@ -65,17 +97,8 @@ mod tests {
             State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0372324, &client_key);
         let mut plaintext = vec![plaintext_block_0, plaintext_block_1];
 
-        let start = Instant::now();
         cbc.encrypt(&mut plaintext, &server_key);
-        println!("ENCRYPT TIME TAKEN {:?}", start.elapsed());
-
-        plaintext
-            .iter()
-            .for_each(|x| println!("{:#x?}", x.decrypt_to_u128(&client_key)));
-
-        let start = Instant::now();
         cbc.decrypt(&mut plaintext, &server_key);
-        println!("DECRYPT TIME TAKEN {:?}", start.elapsed());
 
         assert_eq!(
             plaintext[0].decrypt_to_u128(&client_key),
@@ -86,4 +109,78 @@ mod tests {
             0x3243f6a8_885a308d_313198a2_e0372324
         );
     }
+
+    #[test]
+    fn test_cbc_clear() {
+        let backend = ClearBackend;
+
+        for_each_key_size(|key_data| {
+            let keys = Key::from_u8_enc(key_data, &backend).generate_round_keys(&backend);
+            let iv_data = IV.to_be_bytes();
+            let mut cbc = CBC::new(&keys, &State::from_u8_enc(&iv_data, &backend));
+
+            let blocks = plaintext_blocks();
+            let mut states: Vec<_> = blocks
+                .iter()
+                .map(|block| State::from_u8_enc(block, &backend))
+                .collect();
+
+            let mut previous = iv_data;
+            let expected: Vec<_> = blocks
+                .iter()
+                .map(|block| {
+                    let mut input = *block;
+                    input.iter_mut().zip(previous).for_each(|(x, y)| *x ^= y);
+                    previous = clear_encrypt_block(key_data, &input);
+                    previous
+                })
+                .collect();
+
+            cbc.encrypt(&mut states, &backend);
+            for (state, expected) in states.iter().zip(expected) {
+                assert_eq!(state.decrypt_to_u8(&backend), expected);
+            }
+
+            cbc.decrypt(&mut states, &backend);
+            for (state, block) in states.iter().zip(blocks) {
+                assert_eq!(state.decrypt_to_u8(&backend), block);
+            }
+        });
+    }
+
+    #[test]
+    fn test_cbc_decrypt_cost() {
+        let backend = CountingBackend::default();
+        let keys: Vec<_> = key_expansion_clear(&KEY_128)
+            .iter()
+            .map(|round_key| Key::from_u8_enc(round_key, &backend))
+            .collect();
+        let iv = State::from_u8_enc(&[0xf0; 16], &backend);
+        let mut cbc = CBC::new(&keys, &iv);
+        let ecb = ECB::new(&keys);
+
+        // Fresh ciphertext blocks, so that the depths are those of the decryption
+        let mut states: Vec<_> = (0..4)
+            .map(|i| State::from_u8_enc(&[i; 16], &backend))
+            .collect();
+        cbc.encrypt(&mut states, &backend);
+        let mut states: Vec<_> = states
+            .iter()
+            .map(|x| State::from_u8_enc(&x.decrypt_to_u8(&backend), &backend))
+            .collect();
+
+        backend.reset();
+        let mut block = states[0].clone();
+        let (_, block_counts) =
+            backend.measure(|| ecb.decrypt_blocks(std::slice::from_mut(&mut block), &backend));
+        backend.reset();
+        let (_, counts) = backend.measure(|| cbc.decrypt(&mut states, &backend));
+
+        // The 4 blocks are decrypted in parallel, so the depth is that of a single block and its xor
+        assert_eq!(counts.bootstraps, 4 * (block_counts.bootstraps + 128));
+        assert_eq!(counts.depth, block_counts.depth + 1);
+        for (i, state) in states.iter().enumerate() {
+            assert_eq!(state.decrypt_to_u8(&backend), [i as u8; 16]);
+        }
+    }
 }
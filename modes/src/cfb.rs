@@ -0,0 +1,274 @@
+use crate::FheBlockMode;
+use crate::ecb::ECB;
+use base::primitive::FHEByte;
+use base::*;
+use rayon::prelude::*;
+use std::fmt::Debug;
+use tfhe::boolean::prelude::*;
+
+/// SegmentSize is the number of bits `s` of CFB-`s`, encrypted by every evaluation of the cipher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentSize {
+    /// CFB-1
+    Bit,
+    /// CFB-8
+    Byte,
+    /// CFB-128
+    Block,
+}
+
+impl SegmentSize {
+    pub fn bits(&self) -> usize {
+        match self {
+            SegmentSize::Bit => 1,
+            SegmentSize::Byte => 8,
+            SegmentSize::Block => 128,
+        }
+    }
+}
+
+/// CFB is the Cipher Feedback mode for AES, as in NIST SP 800-38A
+///
+/// The message is a stream of segments of 1, 8 or 128 bits. Every segment is xored with the most significant bits of the encryption of a
+/// 128-bit shift register, which starts as the IV and into which the ciphertext segments are shifted. A block of CFB-8 therefore costs 16
+/// encryptions, and a block of CFB-1 128 of them. The encryption is sequential, but all the inputs of the cipher are known from the
+/// ciphertext when decrypting, so the decryption of a call is a single `ECB::encrypt_blocks`.
+///
+/// The shift register is kept from one call to the next, for the encryption and for the decryption, so a message can be encrypted or
+/// decrypted in chunks, of bytes with `encrypt_bytes` / `decrypt_bytes` or of blocks with `FheBlockMode`.
+pub struct CFB<T = Ciphertext> {
+    ecb: ECB<T>,
+    segment_size: SegmentSize,
+    encrypt_register: Vec<T>,
+    decrypt_register: Vec<T>,
+}
+
+impl<T: Clone + Send + Sync + Debug> CFB<T> {
+    /// Creates the CFB with public round keys, the shift register starting from the encrypted IV.
+    pub fn new_clear<B: Backend<Bit = T>>(
+        round_keys: &[[u8; 16]],
+        iv: &State<T>,
+        segment_size: SegmentSize,
+        server_key: &B,
+    ) -> Self {
+        Self::from_ecb(ECB::new_clear(round_keys, server_key), iv, segment_size)
+    }
+
+    pub fn new(keys: &[Key<T>], iv: &State<T>, segment_size: SegmentSize) -> Self {
+        Self::from_ecb(ECB::new(keys), iv, segment_size)
+    }
+
+    fn from_ecb(ecb: ECB<T>, iv: &State<T>, segment_size: SegmentSize) -> Self {
+        CFB {
+            ecb,
+            segment_size,
+            encrypt_register: iv.to_bits(),
+            decrypt_register: iv.to_bits(),
+        }
+    }
+
+    pub fn segment_size(&self) -> SegmentSize {
+        self.segment_size
+    }
+
+    /// Encrypts a stream of bytes, whose number of bits is a multiple of the segment size.
+    pub fn encrypt_bytes<B: Backend<Bit = T>>(&mut self, bytes: &mut [FHEByte<T>], server_key: &B) {
+        let mut bits = Self::bytes_to_bits(bytes);
+        self.encrypt_bits(&mut bits, server_key);
+        Self::bits_to_bytes(&bits, bytes);
+    }
+
+    pub fn decrypt_bytes<B: Backend<Bit = T>>(&mut self, bytes: &mut [FHEByte<T>], server_key: &B) {
+        let mut bits = Self::bytes_to_bits(bytes);
+        self.decrypt_bits(&mut bits, server_key);
+        Self::bits_to_bytes(&bits, bytes);
+    }
+
+    fn bytes_to_bits(bytes: &[FHEByte<T>]) -> Vec<T> {
+        bytes.iter().flat_map(|byte| byte.bits().cloned()).collect()
+    }
+
+    fn bits_to_bytes(bits: &[T], bytes: &mut [FHEByte<T>]) {
+        for (byte, bits) in bytes.iter_mut().zip(bits.chunks(8)) {
+            *byte = FHEByte::from_bits(bits);
+        }
+    }
+
+    fn assert_segments(&self, bits: &[T]) {
+        assert!(
+            bits.len() % self.segment_size.bits() == 0,
+            "CFB-{} encrypts segments of {} bits, the message is {} bits !",
+            self.segment_size.bits(),
+            self.segment_size.bits(),
+            bits.len()
+        );
+    }
+
+    /// Encrypts the bits segment by segment, every segment of ciphertext being shifted into the register before the next one.
+    fn encrypt_bits<B: Backend<Bit = T>>(&mut self, bits: &mut [T], server_key: &B) {
+        self.assert_segments(bits);
+        let s = self.segment_size.bits();
+
+        server_key.install(|| {
+            for segment in bits.chunks_mut(s) {
+                let mut output = State::from_bits(&self.encrypt_register);
+                self.ecb.encrypt_block(&mut output, server_key);
+                let output = output.to_bits();
+                segment
+                    .par_iter_mut()
+                    .zip(output[..s].par_iter())
+                    .for_each(|(x, y)| server_key.xor_assign(x, y));
+
+                self.encrypt_register.drain(..s);
+                self.encrypt_register.extend_from_slice(segment);
+            }
+        })
+    }
+
+    /// Decrypts the bits: the register of every segment is made of the 128 bits preceding it in the IV and the ciphertext, so all the
+    /// registers are encrypted in a single batch.
+    fn decrypt_bits<B: Backend<Bit = T>>(&mut self, bits: &mut [T], server_key: &B) {
+        self.assert_segments(bits);
+        let s = self.segment_size.bits();
+
+        server_key.install(|| {
+            let stream: Vec<T> = self
+                .decrypt_register
+                .iter()
+                .chain(bits.iter())
+                .cloned()
+                .collect();
+            let mut registers: Vec<State<T>> = (0..bits.len() / s)
+                .map(|i| State::from_bits(&stream[i * s..i * s + 128]))
+                .collect();
+
+            self.ecb.encrypt_blocks(&mut registers, server_key);
+            let outputs: Vec<T> = registers
+                .iter()
+                .flat_map(|register| register.to_bits().into_iter().take(s))
+                .collect();
+            bits.par_iter_mut()
+                .zip(outputs.par_iter())
+                .for_each(|(x, y)| server_key.xor_assign(x, y));
+
+            self.decrypt_register = stream[stream.len() - 128..].to_vec();
+        })
+    }
+}
+
+/// The blocks are a stream of `128 / s` segments each, in the order of `State::to_bits`.
+impl<T: Clone + Send + Sync + Debug> FheBlockMode<T> for CFB<T> {
+    fn encrypt<B: Backend<Bit = T>>(&mut self, plaintext: &mut [State<T>], server_key: &B) {
+        let mut bits: Vec<T> = plaintext.iter().flat_map(State::to_bits).collect();
+        self.encrypt_bits(&mut bits, server_key);
+        for (block, bits) in plaintext.iter_mut().zip(bits.chunks(128)) {
+            *block = State::from_bits(bits);
+        }
+    }
+
+    fn decrypt<B: Backend<Bit = T>>(&mut self, ciphertexts: &mut [State<T>], server_key: &B) {
+        let mut bits: Vec<T> = ciphertexts.iter().flat_map(State::to_bits).collect();
+        self.decrypt_bits(&mut bits, server_key);
+        for (block, bits) in ciphertexts.iter_mut().zip(bits.chunks(128)) {
+            *block = State::from_bits(bits);
+        }
+    }
+}
+
+#[cfg(test)]
+
+mod tests {
+
+    use super::*;
+    use crate::test_utils::*;
+
+    fn new_cfb(segment_size: SegmentSize, backend: &ClearBackend) -> CFB<bool> {
+        let keys = Key::from_u8_enc(&KEY_128, backend).generate_round_keys(backend);
+        CFB::new(&keys, &State::from_u128_enc(IV, backend), segment_size)
+    }
+
+    /// CFB128-AES128.Encrypt of NIST SP 800-38A, F.3.13
+    #[test]
+    fn test_cfb128_vectors() {
+        let backend = ClearBackend;
+        let plaintext = [
+            0x6bc1bee2_2e409f96_e93d7e11_7393172a,
+            0xae2d8a57_1e03ac9c_9eb76fac_45af8e51,
+            0x30c81c46_a35ce411_e5fbc119_1a0a52ef,
+            0xf69f2445_df4f9b17_ad2b417b_e66c3710,
+        ];
+        let ciphertext = [
+            0x3b3fd92e_b72dad20_333449f8_e83cfb4a,
+            0xc8a64537_a0b3a93f_cde3cdad_9f1ce58b,
+            0x26751f67_a3cbb140_b1808cf1_87a4f4df,
+            0xc04b0535_7c5d1c0e_eac4c66f_9ff7f2e6,
+        ];
+
+        let mut cfb = new_cfb(SegmentSize::Block, &backend);
+        let mut states: Vec<_> = plaintext
+            .iter()
+            .map(|&x| State::from_u128_enc(x, &backend))
+            .collect();
+
+        cfb.encrypt(&mut states, &backend);
+        for (state, expected) in states.iter().zip(ciphertext) {
+            assert_eq!(state.decrypt_to_u128(&backend), expected);
+        }
+
+        cfb.decrypt(&mut states, &backend);
+        for (state, expected) in states.iter().zip(plaintext) {
+            assert_eq!(state.decrypt_to_u128(&backend), expected);
+        }
+    }
+
+    /// CFB8-AES128.Encrypt of NIST SP 800-38A, F.3.7, whose 18 bytes are not a whole number of blocks
+    #[test]
+    fn test_cfb8_vectors() {
+        let backend = ClearBackend;
+        let plaintext = hex_bytes("6bc1bee22e409f96e93d7e117393172aae2d");
+        let ciphertext = hex_bytes("3b79424c9c0dd436bace9e0ed4586a4f32b9");
+
+        let mut cfb = new_cfb(SegmentSize::Byte, &backend);
+        let mut bytes = to_bytes(&plaintext, &backend);
+        cfb.encrypt_bytes(&mut bytes, &backend);
+        assert_eq!(from_bytes(&bytes, &backend), ciphertext);
+        cfb.decrypt_bytes(&mut bytes, &backend);
+        assert_eq!(from_bytes(&bytes, &backend), plaintext);
+
+        // The same stream in chunks
+        let mut cfb = new_cfb(SegmentSize::Byte, &backend);
+        let mut bytes = to_bytes(&plaintext, &backend);
+        let (head, tail) = bytes.split_at_mut(5);
+        cfb.encrypt_bytes(head, &backend);
+        cfb.encrypt_bytes(tail, &backend);
+        assert_eq!(from_bytes(&bytes, &backend), ciphertext);
+        let (head, tail) = bytes.split_at_mut(11);
+        cfb.decrypt_bytes(head, &backend);
+        cfb.decrypt_bytes(tail, &backend);
+        assert_eq!(from_bytes(&bytes, &backend), plaintext);
+    }
+
+    /// CFB1-AES128.Encrypt of NIST SP 800-38A, F.3.1, carried on over the whole first block of the plaintext of Appendix F, 128 segments
+    /// of which the first 16 are those of the test vector
+    #[test]
+    fn test_cfb1_vectors() {
+        let backend = ClearBackend;
+        let plaintext = hex_bytes("6bc1bee22e409f96e93d7e117393172a");
+        let ciphertext = hex_bytes("68b3a264f838f5f8c3101070d1ab4c2e");
+
+        let mut cfb = new_cfb(SegmentSize::Bit, &backend);
+        let mut bytes = to_bytes(&plaintext, &backend);
+        cfb.encrypt_bytes(&mut bytes, &backend);
+        assert_eq!(from_bytes(&bytes, &backend), ciphertext);
+        cfb.decrypt_bytes(&mut bytes, &backend);
+        assert_eq!(from_bytes(&bytes, &backend), plaintext);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_cfb128_partial_block() {
+        let backend = ClearBackend;
+        let mut cfb = new_cfb(SegmentSize::Block, &backend);
+        cfb.encrypt_bytes(&mut to_bytes(&[0; 15], &backend), &backend);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::ecb::ECB;
+use base::primitive::FHEByte;
+use base::*;
+use rayon::prelude::*;
+use std::fmt::Debug;
+use tfhe::boolean::prelude::*;
+
+/// CMAC is the AES-CMAC message authentication code of NIST SP 800-38B, computed on encrypted messages under an FHE encrypted key
+///
+/// The subkeys K1 and K2 are derived homomorphically from `L = E_K(0)` when the CMAC is created, and the message, whose length is public,
+/// is authenticated with the CBC-MAC chain of `ECB::encrypt_block`, its last block being xored with K1 when complete, and padded and xored
+/// with K2 otherwise. The chain is sequential, so a message of `n` blocks has the depth of `n` encryptions.
+pub struct CMAC<T = Ciphertext> {
+    ecb: ECB<T>,
+    k1: State<T>,
+    k2: State<T>,
+}
+
+/// Doubles an element of GF(2^128) in the big endian convention of CMAC, as `FHEByte::mul_x_gf2` in GF(2^8): the block is shifted left
+/// by 1, and xored with `0x87` when its most significant bit is set.
+///
+/// The bits of `0x87` are known, so the last bit is a copy of the most significant bit and only 3 bits are xored with it.
+fn double<B: Backend>(state: &State<B::Bit>, server_key: &B) -> State<B::Bit>
+where
+    B::Bit: Debug,
+{
+    let bits = state.to_bits();
+    let conditional_bit = &bits[0];
+    let doubled: Vec<B::Bit> = (0..128)
+        .into_par_iter()
+        .map(|i| {
+            let polynomial_bit = i >= 120 && (0x87 >> (127 - i)) & 1 != 0;
+            match (i < 127, polynomial_bit) {
+                (true, true) => server_key.xor(&bits[i + 1], conditional_bit),
+                (true, false) => bits[i + 1].clone(),
+                (false, _) => conditional_bit.clone(),
+            }
+        })
+        .collect();
+    State::from_bits(&doubled)
+}
+
+impl<T: Clone + Send + Sync + Debug> CMAC<T> {
+    /// Creates the CMAC and derives its subkeys, which costs an encryption.
+    pub fn new<B: Backend<Bit = T>>(keys: &[Key<T>], server_key: &B) -> Self {
+        let ecb = ECB::new(keys);
+        server_key.install(|| {
+            let mut l = State::from_u128_clear(0, server_key);
+            ecb.encrypt_block(&mut l, server_key);
+            let k1 = double(&l, server_key);
+            let k2 = double(&k1, server_key);
+            CMAC { ecb, k1, k2 }
+        })
+    }
+
+    /// Creates the CMAC from the encrypted cipher key, running the key expansion in the FHE context.
+    pub fn from_key<B: Backend<Bit = T>>(key: &Key<T>, server_key: &B) -> Self {
+        Self::new(&key.generate_round_keys(server_key), server_key)
+    }
+
+    /// The subkeys K1 and K2.
+    pub fn subkeys(&self) -> (&State<T>, &State<T>) {
+        (&self.k1, &self.k2)
+    }
+
+    /// Computes the 128-bit tag of the message, of any number of bytes.
+    pub fn tag<B: Backend<Bit = T>>(&self, message: &[FHEByte<T>], server_key: &B) -> State<T> {
+        server_key.install(|| {
+            let complete = !message.is_empty() && message.len() % 16 == 0;
+
+            // The last block is padded with a one bit and zeros, which are trivially encrypted
+            let mut bytes = message.to_vec();
+            if !complete {
+                bytes.push(FHEByte::from_u8_clear(&0x80, server_key));
+                bytes.resize(
+                    bytes.len().div_ceil(16) * 16,
+                    FHEByte::trivial_false(server_key),
+                );
+            }
+            let mut blocks: Vec<State<T>> = bytes
+                .chunks(16)
+                .map(|block| {
+                    let bits: Vec<T> = block.iter().flat_map(|x| x.bits().cloned()).collect();
+                    State::from_bits(&bits)
+                })
+                .collect();
+
+            let subkey = if complete { &self.k1 } else { &self.k2 };
+            blocks.last_mut().unwrap().xor_state(subkey, server_key);
+
+            let mut blocks = blocks.into_iter();
+            let mut chain = blocks.next().unwrap();
+            self.ecb.encrypt_block(&mut chain, server_key);
+            for block in blocks {
+                chain.xor_state(&block, server_key);
+                self.ecb.encrypt_block(&mut chain, server_key);
+            }
+            chain
+        })
+    }
+
+    /// Verifies the tag of the message, which can be truncated to its first 8 to 16 bytes, as NIST SP 800-38B recommends at least 64 bits,
+    /// and returns an encrypted bit which is true when it matches.
+    ///
+    /// The tag is encrypted too, e.g. a tag transciphered along with the record, or trivially encrypted with `FHEByte::from_u8_clear` when
+    /// it is public. The bit is compared with `FHEByte::equals`, a balanced tree of `and`, so the server never learns whether it matched.
+    pub fn verify<B: Backend<Bit = T>>(
+        &self,
+        message: &[FHEByte<T>],
+        tag: &[FHEByte<T>],
+        server_key: &B,
+    ) -> T {
+        assert!(
+            (8..=16).contains(&tag.len()),
+            "A CMAC tag has 8 to 16 bytes, it is {} bytes !",
+            tag.len()
+        );
+
+        server_key.install(|| {
+            let expected: Vec<FHEByte<T>> = self
+                .tag(message, server_key)
+                .to_bits()
+                .chunks(8)
+                .map(FHEByte::from_bits)
+                .collect();
+            FHEByte::equals(&expected[..tag.len()], tag, server_key)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_utils::*;
+
+    // The test vectors below are the AES-128 examples of Appendix D.1 of NIST SP 800-38B, whose key and message are those of SP 800-38A
+
+    fn new_cmac(backend: &ClearBackend) -> CMAC<bool> {
+        CMAC::from_key(&Key::from_u8_enc(&KEY_128, backend), backend)
+    }
+
+    #[test]
+    fn test_cmac_subkeys() {
+        let backend = ClearBackend;
+        let cmac = new_cmac(&backend);
+        let (k1, k2) = cmac.subkeys();
+        assert_eq!(
+            k1.decrypt_to_u128(&backend),
+            0xfbeed618_35713366_7c85e08f_7236a8de
+        );
+        assert_eq!(
+            k2.decrypt_to_u128(&backend),
+            0xf7ddac30_6ae266cc_f90bc11e_e46d513b
+        );
+    }
+
+    #[test]
+    fn test_cmac_vectors() {
+        let backend = ClearBackend;
+        let cmac = new_cmac(&backend);
+        let message: Vec<u8> = PLAINTEXT.iter().flat_map(|x| x.to_be_bytes()).collect();
+
+        // Empty, a single block, a partial last block and complete blocks
+        for (len, expected) in [
+            (0, 0xbb1d6929_e9593728_7fa37d12_9b756746),
+            (16, 0x070a16b4_6b4d4144_f79bdd9d_d04a287c),
+            (40, 0xdfa66747_de9ae630_30ca3261_1497c827),
+            (64, 0x51f0bebf_7e3b9d92_fc497417_79363cfe_u128),
+        ] {
+            let bytes = to_bytes(&message[..len], &backend);
+            let tag = cmac.tag(&bytes, &backend);
+            assert_eq!(tag.decrypt_to_u128(&backend), expected);
+
+            let tag = to_bytes(&expected.to_be_bytes(), &backend);
+            assert!(backend.decrypt(&cmac.verify(&bytes, &tag, &backend)));
+            assert!(backend.decrypt(&cmac.verify(&bytes, &tag[..8], &backend)));
+
+            let mut wrong_tag = tag.clone();
+            wrong_tag[3] = FHEByte::from_u8_enc(&(expected.to_be_bytes()[3] ^ 0x10), &backend);
+            assert!(!backend.decrypt(&cmac.verify(&bytes, &wrong_tag, &backend)));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_cmac_short_tag() {
+        let backend = ClearBackend;
+        let cmac = new_cmac(&backend);
+        let tag = to_bytes(&0xbb1d6929_u32.to_be_bytes(), &backend);
+        cmac.verify(&[], &tag, &backend);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::cfb::{CFB, SegmentSize};
+use crate::{FheBlockMode, cbc::CBC, ctr::CTR, ecb::ECB, ofb::OFB};
+use base::counting::{CostReport, Counted, CountingBackend, GateCounts};
+use base::key_schedule::{KeySize, key_expansion_clear};
+use base::*;
+
+/// Cost analysis of the modes with the `CountingBackend`, which evaluates the circuits on plain booleans and counts the gates and bootstraps.
+///
+/// The round keys are expanded in the clear and encrypted, as with the offline key expansion, so that the depths reported are those of the
+/// rounds themselves. The cost of the key expansion is reported by `base::counting::operation_report`.
+fn round_keys(key_size: KeySize, backend: &CountingBackend) -> Vec<Key<Counted<bool>>> {
+    let key_data: Vec<u8> = (0..key_size.bytes() as u8).collect();
+    key_expansion_clear(&key_data)
+        .iter()
+        .map(|round_key| Key::from_u8_enc(round_key, backend))
+        .collect()
+}
+
+fn blocks(n: usize, backend: &CountingBackend) -> Vec<State<Counted<bool>>> {
+    (0..n)
+        .map(|i| State::from_u8_enc(&[i as u8; 16], backend))
+        .collect()
+}
+
+/// Measures every round of the encryption, or of the decryption, of a single block.
+pub fn round_report(key_size: KeySize, decrypt: bool) -> CostReport {
+    let direction = if decrypt { "decryption" } else { "encryption" };
+    let mut report = CostReport::sequential(&format!("{:?} {} rounds", key_size, direction));
+
+    let backend = CountingBackend::default();
+    let ecb = ECB::new(&round_keys(key_size, &backend));
+    let mut state = State::from_u8_enc(&[0; 16], &backend);
+
+    for round in 0..=ecb.rounds() {
+        let label = match round {
+            0 => "Initial AddRoundKey".to_string(),
+            round if round == ecb.rounds() => "Final round".to_string(),
+            round => format!("Round {}", round),
+        };
+
+        let ((), counts) = backend.measure(|| {
+            if decrypt {
+                ecb.decrypt_round(round, std::slice::from_mut(&mut state), &backend)
+            } else {
+                ecb.encrypt_round(round, std::slice::from_mut(&mut state), &backend)
+            }
+        });
+        report.push(&label, counts);
+    }
+
+    report
+}
+
+/// Measures the encryption of `n` fresh blocks in a mode with its own backend, so that the depth is the critical path of the whole mode.
+fn measure_mode<M: FheBlockMode<Counted<bool>>>(
+    mode: &mut M,
+    n: usize,
+    backend: &CountingBackend,
+) -> GateCounts {
+    let mut states = blocks(n, backend);
+    backend.reset();
+    mode.encrypt(&mut states, backend);
+    backend.counts()
+}
+
+/// Measures the encryption of `n` blocks in every mode.
+pub fn mode_report(key_size: KeySize, n: usize) -> CostReport {
+    let mut report = CostReport::new(&format!("{:?} encryption of {} blocks", key_size, n));
+
+    let backend = CountingBackend::default();
+    let mut ecb = ECB::new(&round_keys(key_size, &backend));
+    report.push("ECB", measure_mode(&mut ecb, n, &backend));
+
+    let backend = CountingBackend::default();
+    let key_data: Vec<u8> = (0..key_size.bytes() as u8).collect();
+    let mut ecb = ECB::new_clear(&key_expansion_clear(&key_data), &backend);
+    report.push("ECB (clear key)", measure_mode(&mut ecb, n, &backend));
+
+    let backend = CountingBackend::default();
+    let iv = State::from_u8_enc(&[0xff; 16], &backend);
+    let mut cbc = CBC::new(&round_keys(key_size, &backend), &iv);
+    report.push("CBC", measure_mode(&mut cbc, n, &backend));
+
+    let backend = CountingBackend::default();
+    let counters = blocks(n, &backend);
+    let mut ctr = CTR::new(&round_keys(key_size, &backend), &counters);
+    report.push("CTR", measure_mode(&mut ctr, n, &backend));
+
+    let backend = CountingBackend::default();
+    let iv = State::from_u8_enc(&[0xff; 16], &backend);
+    let mut ofb = OFB::new(&round_keys(key_size, &backend), &iv);
+    report.push("OFB", measure_mode(&mut ofb, n, &backend));
+
+    let backend = CountingBackend::default();
+    let iv = State::from_u8_enc(&[0xff; 16], &backend);
+    let mut cfb = CFB::new(&round_keys(key_size, &backend), &iv, SegmentSize::Block);
+    report.push("CFB-128", measure_mode(&mut cfb, n, &backend));
+
+    let backend = CountingBackend::default();
+    let iv = State::from_u8_enc(&[0xff; 16], &backend);
+    let mut cfb = CFB::new(&round_keys(key_size, &backend), &iv, SegmentSize::Byte);
+    report.push("CFB-8", measure_mode(&mut cfb, n, &backend));
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_report() {
+        for key_size in [KeySize::Aes128, KeySize::Aes192, KeySize::Aes256] {
+            let report = round_report(key_size, false);
+            println!("{}", report);
+            assert_eq!(report.entries.len(), key_size.rounds() + 1);
+
+            let backend = CountingBackend::default();
+            let ecb = ECB::new(&round_keys(key_size, &backend));
+            let mut state = State::from_u8_enc(&[0; 16], &backend);
+            let ((), counts) = backend.measure(|| ecb.encrypt_block(&mut state, &backend));
+
+            // The DAG of the block cancels the double nots of the rounds and needs no trivial encryption
+            let total = GateCounts {
+                not: counts.not,
+                trivial: counts.trivial,
+                ..report.total()
+            };
+            assert_eq!(total, counts);
+
+            let main_round = report.get("Round 1").unwrap();
+            let final_round = report.get("Final round").unwrap();
+            assert!(final_round.bootstraps < main_round.bootstraps);
+        }
+
+        let report = round_report(KeySize::Aes128, true);
+        println!("{}", report);
+        assert_eq!(report.entries.len(), 11);
+    }
+
+    #[test]
+    fn test_mode_report() {
+        let report = mode_report(KeySize::Aes128, 2);
+        println!("{}", report);
+
+        let ecb = report.get("ECB").unwrap();
+        let cbc = report.get("CBC").unwrap();
+        let ctr = report.get("CTR").unwrap();
+        let ofb = report.get("OFB").unwrap();
+        let cfb = report.get("CFB-128").unwrap();
+        let cfb8 = report.get("CFB-8").unwrap();
+        let ecb_clear_key = report.get("ECB (clear key)").unwrap();
+
+        // Both modes encrypt two blocks plus the 256 xors of the chaining
+        assert_eq!(cbc.bootstraps, ecb.bootstraps + 256);
+        assert_eq!(ctr.bootstraps, ecb.bootstraps + 256);
+        assert_eq!(ofb.bootstraps, ecb.bootstraps + 256);
+        assert_eq!(cfb.bootstraps, ecb.bootstraps + 256);
+
+        // CFB-8 encrypts the shift register once per byte
+        assert_eq!(cfb8.bootstraps, 16 * ecb.bootstraps + 256);
+
+        // The blocks of ECB and CTR are independent while those of CBC and OFB are chained
+        assert_eq!(ctr.depth, ecb.depth + 1);
+        assert!(cbc.depth > 2 * ecb.depth);
+        assert!(cfb.depth > 2 * ecb.depth);
+
+        // With a public key, the 11 AddRoundKey of both blocks are free
+        assert_eq!(ecb.bootstraps - ecb_clear_key.bootstraps, 2 * 11 * 128);
+    }
+}
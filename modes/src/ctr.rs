@@ -1,48 +1,122 @@
+use crate::FheBlockMode;
 use crate::ecb::ECB;
 use base::*;
 use rayon::prelude::*;
+use std::fmt::Debug;
 use tfhe::boolean::prelude::*;
 
-/// CTR mode is the counter mode for AES-128
+/// CTR mode is the counter mode for AES
 ///
 /// As there is no way to randomly generate the counter in the FHE context, we have to pass it as an argument.
 /// As such, we generate the counters, encrypt in the FHE context, and then pass them to the CTR. In a client server context,
 /// the client would generate the counters, serialize them, and send them to the server. The server would then deserialize them and use them to create the CTR object.
+///
+/// When the nonce is public, the server generates the counters itself with `from_counter`. The counters are then trivially encrypted, so the
+/// initial AddRoundKey folds into copies and nots of the key bits and the first SubBytes reads the key bits directly.
+///
+/// The position in the counters is kept from one call to the next, for the encryption and for the decryption, so a message can be
+/// encrypted or decrypted in chunks.
+
+pub struct CTR<T = Ciphertext> {
+    ecb: ECB<T>,
+    counters: Counters<T>,
+    encrypt_position: usize,
+    decrypt_position: usize,
+}
 
-pub struct CTR {
-    ecb: ECB,
-    counters: Vec<State>,
+/// The counters of a CTR: encrypted by the client, or generated by the server from a clear initial counter for any number of blocks.
+enum Counters<T> {
+    Encrypted(Vec<State<T>>),
+    Clear(u128),
 }
 
-impl CTR {
-    pub fn new(keys: &[Key], counters: &[State], n: u8) -> Self {
-        assert!(counters.len() == n as usize);
+impl<T: Clone + Send + Sync + Debug> CTR<T> {
+    /// Creates the CTR with public round keys and encrypted counters, one per block.
+    pub fn new_clear<B: Backend<Bit = T>>(
+        round_keys: &[[u8; 16]],
+        counters: &[State<T>],
+        server_key: &B,
+    ) -> Self {
+        Self::from_ecb(
+            ECB::new_clear(round_keys, server_key),
+            Counters::Encrypted(counters.to_vec()),
+        )
+    }
+
+    /// Creates the CTR from encrypted counters, one per block: the blocks of a message, over all the calls, use the counters in order.
+    pub fn new(keys: &[Key<T>], counters: &[State<T>]) -> Self {
+        Self::from_ecb(ECB::new(keys), Counters::Encrypted(counters.to_vec()))
+    }
+
+    /// Creates the CTR from clear counters, generated by the server from `initial_counter` incremented as a 128-bit big endian integer.
+    pub fn from_counter(keys: &[Key<T>], initial_counter: &[u8; 16]) -> Self {
+        Self::from_ecb(
+            ECB::new(keys),
+            Counters::Clear(u128::from_be_bytes(*initial_counter)),
+        )
+    }
+
+    fn from_ecb(ecb: ECB<T>, counters: Counters<T>) -> Self {
         CTR {
-            ecb: ECB::new(keys),
-            counters: counters.to_vec(),
+            ecb,
+            counters,
+            encrypt_position: 0,
+            decrypt_position: 0,
         }
     }
 
-    pub fn encrypt(&self, plaintext: &mut [State], server_key: &ServerKey) {
-        let mut enc_counters = self.counters.to_vec();
-        enc_counters
-            .par_iter_mut()
-            .for_each(|x| self.ecb.encrypt(x, server_key));
-        plaintext
-            .par_iter_mut()
-            .zip(enc_counters.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| x.xor_state(y, server_key));
+    /// Returns the `n` encrypted counters from the `position`-th one, which are xored with the plaintext or the ciphertext blocks.
+    pub fn keystream<B: Backend<Bit = T>>(
+        &self,
+        position: usize,
+        n: usize,
+        server_key: &B,
+    ) -> Vec<State<T>> {
+        server_key.install(|| {
+            let mut keystream = match &self.counters {
+                Counters::Encrypted(counters) => {
+                    assert!(
+                        position + n <= counters.len(),
+                        "There are {} counters for {} blocks !",
+                        counters.len(),
+                        position + n
+                    );
+                    counters[position..position + n].to_vec()
+                }
+                Counters::Clear(initial_counter) => (position as u128..(position + n) as u128)
+                    .map(|i| State::from_u128_clear(initial_counter.wrapping_add(i), server_key))
+                    .collect(),
+            };
+            self.ecb.encrypt_blocks(&mut keystream, server_key);
+            keystream
+        })
+    }
+
+    fn apply_keystream<B: Backend<Bit = T>>(
+        &self,
+        position: usize,
+        blocks: &mut [State<T>],
+        server_key: &B,
+    ) {
+        server_key.install(|| {
+            let keystream = self.keystream(position, blocks.len(), server_key);
+            blocks
+                .par_iter_mut()
+                .zip(keystream.par_iter())
+                .for_each_with(server_key, |server_key, (x, y)| x.xor_state(y, server_key));
+        })
+    }
+}
+
+impl<T: Clone + Send + Sync + Debug> FheBlockMode<T> for CTR<T> {
+    fn encrypt<B: Backend<Bit = T>>(&mut self, plaintext: &mut [State<T>], server_key: &B) {
+        self.apply_keystream(self.encrypt_position, plaintext, server_key);
+        self.encrypt_position += plaintext.len();
     }
 
-    pub fn decrypt(&self, ciphertexts: &mut [State], server_key: &ServerKey) {
-        let mut enc_counters = self.counters.to_vec();
-        enc_counters
-            .par_iter_mut()
-            .for_each(|x| self.ecb.encrypt(x, server_key));
-        ciphertexts
-            .par_iter_mut()
-            .zip(enc_counters.par_iter())
-            .for_each_with(server_key, |server_key, (x, y)| x.xor_state(y, server_key));
+    fn decrypt<B: Backend<Bit = T>>(&mut self, ciphertexts: &mut [State<T>], server_key: &B) {
+        self.apply_keystream(self.decrypt_position, ciphertexts, server_key);
+        self.decrypt_position += ciphertexts.len();
     }
 }
 
@@ -51,7 +125,8 @@ impl CTR {
 mod tests {
 
     use super::*;
-    use std::time::Instant;
+    use crate::test_utils::*;
+    use base::counting::CountingBackend;
     use tfhe::boolean::gen_keys;
 
     #[test]
@@ -64,7 +139,7 @@ mod tests {
             State::from_u128_enc(0x3243f6a8_885a308d_00000000_00000000, &client_key),
             State::from_u128_enc(0x3243f6a8_885a308d_00000000_00000001, &client_key),
         ];
-        let ctr = CTR::new(&keys, &counters, 2);
+        let mut ctr = CTR::new(&keys, &counters);
 
         let plaintext_block_0 =
             State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0370734, &client_key);
@@ -72,13 +147,8 @@ mod tests {
             State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0372324, &client_key);
         let mut plaintext = vec![plaintext_block_0, plaintext_block_1];
 
-        let start = Instant::now();
         ctr.encrypt(&mut plaintext, &server_key);
-        println!("ENCRYPT TIME TAKEN {:?}", start.elapsed());
-
-        let start = Instant::now();
         ctr.decrypt(&mut plaintext, &server_key);
-        println!("DECRYPT TIME TAKEN {:?}", start.elapsed());
 
         assert_eq!(
             plaintext[0].decrypt_to_u128(&client_key),
@@ -89,4 +159,67 @@ mod tests {
             0x3243f6a8_885a308d_313198a2_e0372324
         );
     }
+
+    #[test]
+    fn test_ctr_clear() {
+        let backend = ClearBackend;
+
+        for_each_key_size(|key_data| {
+            let keys = Key::from_u8_enc(key_data, &backend).generate_round_keys(&backend);
+            let counter_data: Vec<[u8; 16]> = (0..4)
+                .map(|i| INITIAL_COUNTER.wrapping_add(i).to_be_bytes())
+                .collect();
+            let counters: Vec<_> = counter_data
+                .iter()
+                .map(|counter| State::from_u8_enc(counter, &backend))
+                .collect();
+            let mut ctr = CTR::new(&keys, &counters);
+
+            let blocks = plaintext_blocks();
+            let mut states: Vec<_> = blocks
+                .iter()
+                .map(|block| State::from_u8_enc(block, &backend))
+                .collect();
+
+            ctr.encrypt(&mut states, &backend);
+            for ((state, block), counter) in states.iter().zip(&blocks).zip(&counter_data) {
+                let mut expected = clear_encrypt_block(key_data, counter);
+                expected.iter_mut().zip(block).for_each(|(x, y)| *x ^= y);
+                assert_eq!(state.decrypt_to_u8(&backend), expected);
+            }
+
+            ctr.decrypt(&mut states, &backend);
+            for (state, block) in states.iter().zip(blocks) {
+                assert_eq!(state.decrypt_to_u8(&backend), block);
+            }
+        });
+    }
+
+    #[test]
+    fn test_ctr_from_counter() {
+        let initial_counter = 0xf0f1f2f3_f4f5f6f7_f8f9fafb_fcfdfffe_u128;
+        let backend = CountingBackend::default();
+        let keys = Key::from_u8_enc(&KEY_128, &backend).generate_round_keys(&backend);
+
+        let ctr = CTR::from_counter(&keys, &initial_counter.to_be_bytes());
+        let (keystream, clear_counter_counts) = backend.measure(|| ctr.keystream(0, 3, &backend));
+        for (i, state) in keystream.iter().enumerate() {
+            let counter = initial_counter.wrapping_add(i as u128).to_be_bytes();
+            assert_eq!(
+                state.decrypt_to_u8(&backend),
+                clear_encrypt_block(&KEY_128, &counter)
+            );
+        }
+
+        // The initial AddRoundKey of clear counters does not bootstrap, and the bytes the clear counters share are only substituted once
+        let counters: Vec<_> = (0..3)
+            .map(|i| State::from_u128_enc(initial_counter.wrapping_add(i), &backend))
+            .collect();
+        let ctr = CTR::new(&keys, &counters);
+        let (_, counts) = backend.measure(|| ctr.keystream(0, 3, &backend));
+        assert!(
+            counts.bootstraps - clear_counter_counts.bootstraps > 3 * 128,
+            "Clear counters save more than the initial AddRoundKey !"
+        );
+    }
 }
@@ -0,0 +1,296 @@
+use crate::{FheBlockMode, cbc::CBC, ctr::CTR, ecb::ECB, ofb::OFB};
+use base::dag::{DagBuilder, GateDag};
+use base::key_schedule::KeySize;
+use base::sbox::SBoxPlans;
+use base::*;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Mode selects the mode of operation lowered by an `EncryptionDag`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    ECB,
+    CBC,
+    CTR,
+    OFB,
+}
+
+impl Mode {
+    /// The number of states passed along with the key: none in ECB, the IV in CBC and OFB, and a counter per block in CTR.
+    pub fn num_ivs(&self, n: usize) -> usize {
+        match self {
+            Mode::ECB => 0,
+            Mode::CBC | Mode::OFB => 1,
+            Mode::CTR => n,
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "ECB" => Ok(Mode::ECB),
+            "CBC" => Ok(Mode::CBC),
+            "CTR" => Ok(Mode::CTR),
+            "OFB" => Ok(Mode::OFB),
+            _ => Err(format!("Invalid mode {}, expected ECB, CBC, CTR or OFB", s)),
+        }
+    }
+}
+
+/// EncryptionDag is the encryption of `n` blocks in a mode, key schedule included, lowered to a single `GateDag`.
+///
+/// The DAG is traced once by running the mode on a `DagBuilder`, and can then encrypt any key, IVs and blocks of the same shape. Its inputs
+/// are the bits of the key, or of the round keys with the offline key expansion, then of the IVs and of the blocks, and its outputs the bits
+/// of the encrypted blocks. Trivially encrypted IVs, such as the clear counters of `CTR::from_counter`, are inputs of the DAG like any
+/// other, so they are not folded.
+#[derive(Clone, Debug)]
+pub struct EncryptionDag {
+    mode: Mode,
+    key_size: KeySize,
+    n: usize,
+    key_expansion_offline: bool,
+    dag: GateDag,
+}
+
+impl EncryptionDag {
+    pub fn new(mode: Mode, key_size: KeySize, n: usize, key_expansion_offline: bool) -> Self {
+        Self::with_s_box_plans(
+            mode,
+            key_size,
+            n,
+            key_expansion_offline,
+            SBoxPlans::shared().clone(),
+        )
+    }
+
+    /// Lowers the S-Box with the given plans, e.g. those of the `FheAesContext` which evaluates the DAG.
+    pub fn with_s_box_plans(
+        mode: Mode,
+        key_size: KeySize,
+        n: usize,
+        key_expansion_offline: bool,
+        s_box_plans: Arc<SBoxPlans>,
+    ) -> Self {
+        assert!(n > 0, "There is at least one block to encrypt !");
+
+        let key_bits = if key_expansion_offline {
+            key_size.round_keys() * 128
+        } else {
+            key_size.bytes() * 8
+        };
+        let iv_bits = mode.num_ivs(n) * 128;
+        let num_inputs = key_bits + iv_bits + n * 128;
+
+        let dag = DagBuilder::new(s_box_plans).trace(num_inputs, |builder, inputs| {
+            let (key_inputs, inputs) = inputs.split_at(key_bits);
+            let (iv_inputs, block_inputs) = inputs.split_at(iv_bits);
+
+            let keys: Vec<Key<usize>> = if key_expansion_offline {
+                key_inputs.chunks(128).map(Key::from_bits).collect()
+            } else {
+                Key::from_bits(key_inputs).generate_round_keys(builder)
+            };
+            let ivs: Vec<State<usize>> = iv_inputs.chunks(128).map(State::from_bits).collect();
+            let mut blocks: Vec<State<usize>> =
+                block_inputs.chunks(128).map(State::from_bits).collect();
+
+            match mode {
+                Mode::ECB => ECB::new(&keys).encrypt(&mut blocks, builder),
+                Mode::CBC => CBC::new(&keys, &ivs[0]).encrypt(&mut blocks, builder),
+                Mode::CTR => CTR::new(&keys, &ivs).encrypt(&mut blocks, builder),
+                Mode::OFB => OFB::new(&keys, &ivs[0]).encrypt(&mut blocks, builder),
+            }
+
+            blocks.iter().flat_map(State::to_bits).collect()
+        });
+
+        EncryptionDag {
+            mode,
+            key_size,
+            n,
+            key_expansion_offline,
+            dag,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn dag(&self) -> &GateDag {
+        &self.dag
+    }
+
+    /// Encrypts the blocks in place with the global scheduler of `GateDag::evaluate`.
+    ///
+    /// `keys` holds the key, or the round keys with the offline key expansion, and `ivs` the IVs of the mode, see `Mode::num_ivs`.
+    pub fn encrypt<B: Backend>(
+        &self,
+        keys: &[Key<B::Bit>],
+        ivs: &[State<B::Bit>],
+        blocks: &mut [State<B::Bit>],
+        server_key: &B,
+    ) where
+        B::Bit: fmt::Debug,
+    {
+        let num_keys = if self.key_expansion_offline {
+            self.key_size.round_keys()
+        } else {
+            1
+        };
+        assert!(keys.len() == num_keys, "The DAG takes {} keys !", num_keys);
+        assert!(
+            ivs.len() == self.mode.num_ivs(self.n),
+            "Wrong number of IVs for {} !",
+            self.mode
+        );
+        assert!(
+            blocks.len() == self.n,
+            "The DAG encrypts {} blocks !",
+            self.n
+        );
+
+        let inputs: Vec<B::Bit> = keys
+            .iter()
+            .flat_map(Key::to_bits)
+            .chain(ivs.iter().flat_map(State::to_bits))
+            .chain(blocks.iter().flat_map(State::to_bits))
+            .collect();
+
+        let outputs = self.dag.evaluate(&inputs, server_key);
+        for (block, bits) in blocks.iter_mut().zip(outputs.chunks(128)) {
+            *block = State::from_bits(bits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use base::counting::CountingBackend;
+    use base::key_schedule::key_expansion_clear;
+
+    #[test]
+    fn test_encryption_dag_clear() {
+        let backend = ClearBackend;
+
+        for_each_key_size(|key_data| {
+            let key_size = KeySize::from_bytes(key_data.len());
+            let key = Key::from_u8_enc(key_data, &backend);
+            let round_keys: Vec<_> = key_expansion_clear(key_data)
+                .iter()
+                .map(|round_key| Key::from_u8_enc(round_key, &backend))
+                .collect();
+            let blocks: Vec<_> = plaintext_blocks()[..2]
+                .iter()
+                .map(|block| State::from_u8_enc(block, &backend))
+                .collect();
+
+            for mode in [Mode::ECB, Mode::CBC, Mode::CTR, Mode::OFB] {
+                let ivs: Vec<_> = (0..mode.num_ivs(2))
+                    .map(|i| State::from_u128_enc(INITIAL_COUNTER + i as u128, &backend))
+                    .collect();
+
+                // The same mode evaluated primitive by primitive
+                let mut expected = blocks.clone();
+                let keys = key.generate_round_keys(&backend);
+                match mode {
+                    Mode::ECB => ECB::new(&keys).encrypt(&mut expected, &backend),
+                    Mode::CBC => CBC::new(&keys, &ivs[0]).encrypt(&mut expected, &backend),
+                    Mode::CTR => CTR::new(&keys, &ivs).encrypt(&mut expected, &backend),
+                    Mode::OFB => OFB::new(&keys, &ivs[0]).encrypt(&mut expected, &backend),
+                }
+
+                for key_expansion_offline in [false, true] {
+                    let dag = EncryptionDag::new(mode, key_size, 2, key_expansion_offline);
+                    let keys = if key_expansion_offline {
+                        round_keys.clone()
+                    } else {
+                        vec![key.clone()]
+                    };
+
+                    let mut states = blocks.clone();
+                    dag.encrypt(&keys, &ivs, &mut states, &backend);
+                    for (state, expected) in states.iter().zip(expected.iter()) {
+                        assert_eq!(
+                            state.decrypt_to_u8(&backend),
+                            expected.decrypt_to_u8(&backend),
+                            "{} {:?}",
+                            mode,
+                            key_size
+                        );
+                    }
+                }
+            }
+
+            let mut states = blocks.clone();
+            EncryptionDag::new(Mode::ECB, key_size, 2, false).encrypt(
+                std::slice::from_ref(&key),
+                &[],
+                &mut states,
+                &backend,
+            );
+            for (state, block) in states.iter().zip(plaintext_blocks()) {
+                assert_eq!(
+                    state.decrypt_to_u8(&backend),
+                    clear_encrypt_block(key_data, &block)
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_encryption_dag_cost() {
+        let backend = CountingBackend::default();
+        let key = Key::from_u8_enc(&KEY_128, &backend);
+        let iv = State::from_u8_enc(&[0xff; 16], &backend);
+        let blocks: Vec<_> = (0..2)
+            .map(|i| State::from_u8_enc(&[i; 16], &backend))
+            .collect();
+
+        backend.reset();
+        let mut states = blocks.clone();
+        let keys = key.generate_round_keys(&backend);
+        CBC::new(&keys, &iv).encrypt(&mut states, &backend);
+        let direct = backend.counts();
+
+        // The DAG holds the same gates, but for the duplicates, and its depth is the critical path of the mode
+        let dag = EncryptionDag::new(Mode::CBC, KeySize::Aes128, 2, false);
+        assert!(dag.dag().bootstraps() <= direct.bootstraps);
+        assert_eq!(dag.dag().depth(), direct.depth);
+
+        backend.reset();
+        let mut dag_states = blocks.clone();
+        dag.encrypt(&[key], &[iv], &mut dag_states, &backend);
+        let counts = backend.counts();
+        assert_eq!(counts.bootstraps, dag.dag().bootstraps());
+        assert_eq!(counts.depth, direct.depth);
+        assert!(
+            dag_states
+                .iter()
+                .zip(states.iter())
+                .all(|(x, y)| x.decrypt_to_u8(&backend) == y.decrypt_to_u8(&backend))
+        );
+    }
+
+    #[test]
+    fn test_mode_from_str() {
+        assert_eq!("cbc".parse::<Mode>(), Ok(Mode::CBC));
+        assert_eq!("Ofb".parse::<Mode>(), Ok(Mode::OFB));
+        assert_eq!(
+            "GCM".parse::<Mode>(),
+            Err("Invalid mode GCM, expected ECB, CBC, CTR or OFB".to_string())
+        );
+    }
+}
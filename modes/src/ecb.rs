@@ -1,52 +1,184 @@
+use crate::FheBlockMode;
+use base::dag::DagBuilder;
+use base::key_schedule::KeySize;
 use base::*;
+use rayon::prelude::*;
+use std::fmt::Debug;
 use tfhe::boolean::prelude::*;
 
-/// ECB mode is the Electronic Codebook mode for AES-128
-pub struct ECB {
-    keys: Vec<Key>,
+/// ECB mode is the Electronic Codebook mode for AES
+///
+/// The number of rounds follows the number of round keys: 11, 13 and 15 round keys give AES-128, AES-192 and AES-256 respectively.
+pub struct ECB<T = Ciphertext> {
+    keys: Vec<Key<T>>,
 }
 
-impl ECB {
-    pub fn new(keys: &[Key]) -> Self {
+impl<T: Clone + Send + Sync + Debug> ECB<T> {
+    pub fn new(keys: &[Key<T>]) -> Self {
+        // Panics if the number of round keys does not match one of the AES key sizes
+        KeySize::from_round_keys(keys.len());
         ECB {
             keys: keys.to_vec(),
         }
     }
 
-    pub fn encrypt(&self, state: &mut State, server_key: &ServerKey) {
-        // Initial round key addition
-        state.xor_key_enc(&self.keys[0], server_key);
+    /// Creates the ECB from public round keys, as returned by `key_expansion_clear`, e.g. to evaluate a public PRP on encrypted data.
+    ///
+    /// The round keys are trivially encrypted, so AddRoundKey folds into free nots instead of bootstrapping 128 xor per round.
+    pub fn new_clear<B: Backend<Bit = T>>(round_keys: &[[u8; 16]], server_key: &B) -> Self {
+        let keys: Vec<_> = round_keys
+            .iter()
+            .map(|round_key| Key::from_u8_clear(round_key, server_key))
+            .collect();
+        Self::new(&keys)
+    }
 
-        // Main rounds
-        for round in 1..10 {
-            state.sub_bytes(server_key);
-            state.shift_rows();
-            state.mix_columns(server_key);
-            state.xor_key_enc(&self.keys[round], server_key);
-        }
+    pub fn rounds(&self) -> usize {
+        self.keys.len() - 1
+    }
 
-        // Final round
-        state.sub_bytes(server_key);
-        state.shift_rows();
-        state.xor_key_enc(&self.keys[10], server_key);
+    /// Encrypts a single block round by round, without lowering it to a `GateDag` as `encrypt_blocks` does: a block has nothing to overlap
+    /// with, and the chained modes such as CBC encryption, OFB, CFB and CMAC call it once per block, CFB-1 even once per bit.
+    pub fn encrypt_block<B: Backend<Bit = T>>(&self, state: &mut State<T>, server_key: &B) {
+        server_key.install(|| {
+            for round in 0..=self.rounds() {
+                self.encrypt_round(round, std::slice::from_mut(state), server_key);
+            }
+        })
     }
 
-    pub fn decrypt(&self, state: &mut State, server_key: &ServerKey) {
-        // Initial round key addition
-        state.xor_key_enc(&self.keys[10], server_key);
+    /// Decrypts a single block round by round, as `encrypt_block`.
+    pub fn decrypt_block<B: Backend<Bit = T>>(&self, state: &mut State<T>, server_key: &B) {
+        server_key.install(|| {
+            for round in 0..=self.rounds() {
+                self.decrypt_round(round, std::slice::from_mut(state), server_key);
+            }
+        })
+    }
 
-        // Main rounds
-        for round in 1..10 {
-            state.inv_shift_rows();
-            state.inv_sub_bytes(server_key);
-            state.xor_key_enc(&self.keys[10 - round], server_key);
-            state.inv_mix_columns(server_key);
-        }
+    /// Encrypts independent blocks together, their rounds being lowered to a single `GateDag` run by the list scheduler of the backend.
+    ///
+    /// The blocks share the round keys, and the independent gates of all the blocks and of consecutive rounds overlap on the thread pool:
+    /// `n` blocks have the depth of a single one, and take less than `n` times its time as long as a single block does not fill the pool.
+    pub fn encrypt_blocks<B: Backend<Bit = T>>(&self, states: &mut [State<T>], server_key: &B) {
+        self.run_rounds(states, server_key, |ecb, states, builder| {
+            for round in 0..=ecb.rounds() {
+                ecb.encrypt_round(round, states, builder);
+            }
+        });
+    }
+
+    /// Decrypts independent blocks together, as `encrypt_blocks`.
+    pub fn decrypt_blocks<B: Backend<Bit = T>>(&self, states: &mut [State<T>], server_key: &B) {
+        self.run_rounds(states, server_key, |ecb, states, builder| {
+            for round in 0..=ecb.rounds() {
+                ecb.decrypt_round(round, states, builder);
+            }
+        });
+    }
+
+    /// Runs the rounds on the wires of the round keys and of the states with `Backend::run_dag`, and replaces the states by the outputs.
+    fn run_rounds<B: Backend<Bit = T>>(
+        &self,
+        states: &mut [State<T>],
+        server_key: &B,
+        rounds: impl FnOnce(&ECB<usize>, &mut [State<usize>], &DagBuilder) + Send,
+    ) {
+        server_key.install(|| {
+            let key_bits = 128 * self.keys.len();
+            let bits: Vec<T> = self
+                .keys
+                .iter()
+                .flat_map(Key::to_bits)
+                .chain(states.iter().flat_map(State::to_bits))
+                .collect();
 
-        // Final round
-        state.inv_shift_rows();
-        state.inv_sub_bytes(server_key);
-        state.xor_key_enc(&self.keys[0], server_key);
+            let outputs = server_key.run_dag(&bits, |builder, wires| {
+                let (key_wires, state_wires) = wires.split_at(key_bits);
+                let ecb = ECB {
+                    keys: key_wires.chunks(128).map(Key::from_bits).collect(),
+                };
+                let mut states: Vec<State<usize>> =
+                    state_wires.chunks(128).map(State::from_bits).collect();
+                rounds(&ecb, &mut states, builder);
+                states.iter().flat_map(State::to_bits).collect()
+            });
+
+            for (state, bits) in states.iter_mut().zip(outputs.chunks(128)) {
+                *state = State::from_bits(bits);
+            }
+        })
+    }
+
+    /// Applies a single round of the encryption to every state, round 0 being the initial round key addition and round `rounds()` the final round.
+    pub fn encrypt_round<B: Backend<Bit = T>>(
+        &self,
+        round: usize,
+        states: &mut [State<T>],
+        server_key: &B,
+    ) {
+        server_key.install(|| {
+            let rounds = self.rounds();
+
+            if round == 0 {
+                // Initial round key addition
+                states
+                    .par_iter_mut()
+                    .for_each(|state| state.xor_key_enc(&self.keys[0], server_key));
+            } else {
+                // Main rounds, and final round without MixColumns
+                State::sub_bytes_batch(states, server_key);
+                states.par_iter_mut().for_each(|state| {
+                    state.shift_rows();
+                    if round < rounds {
+                        state.mix_columns(server_key);
+                    }
+                    state.xor_key_enc(&self.keys[round], server_key);
+                });
+            }
+        })
+    }
+
+    /// Applies a single round of the decryption to every state, numbered in the order in which they are applied as for `encrypt_round`.
+    pub fn decrypt_round<B: Backend<Bit = T>>(
+        &self,
+        round: usize,
+        states: &mut [State<T>],
+        server_key: &B,
+    ) {
+        server_key.install(|| {
+            let rounds = self.rounds();
+
+            if round == 0 {
+                // Initial round key addition
+                states
+                    .par_iter_mut()
+                    .for_each(|state| state.xor_key_enc(&self.keys[rounds], server_key));
+            } else {
+                // Main rounds, and final round without InvMixColumns
+                states
+                    .par_iter_mut()
+                    .for_each(|state| state.inv_shift_rows());
+                State::inv_sub_bytes_batch(states, server_key);
+                states.par_iter_mut().for_each(|state| {
+                    state.xor_key_enc(&self.keys[rounds - round], server_key);
+                    if round < rounds {
+                        state.inv_mix_columns(server_key);
+                    }
+                });
+            }
+        })
+    }
+}
+
+/// ECB encrypts the blocks of a message independently of each other, so they are all encrypted in the same DAG, and has no state.
+impl<T: Clone + Send + Sync + Debug> FheBlockMode<T> for ECB<T> {
+    fn encrypt<B: Backend<Bit = T>>(&mut self, blocks: &mut [State<T>], server_key: &B) {
+        self.encrypt_blocks(blocks, server_key);
+    }
+
+    fn decrypt<B: Backend<Bit = T>>(&mut self, blocks: &mut [State<T>], server_key: &B) {
+        self.decrypt_blocks(blocks, server_key);
     }
 }
 
@@ -55,8 +187,9 @@ impl ECB {
 mod tests {
 
     use super::*;
-    use base::primitive::*;
-    use std::time::Instant;
+    use crate::test_utils::*;
+    use base::counting::CountingBackend;
+    use base::key_schedule::key_expansion_clear;
     use tfhe::boolean::gen_keys;
 
     #[test]
@@ -64,23 +197,19 @@ mod tests {
         let (client_key, server_key) = gen_keys();
 
         let curr_key = Key::from_u128_enc(0x2b7e1516_28aed2a6a_bf71588_09cf4f3c, &client_key);
-        let keys: Vec<_> = curr_key.generate_round_keys(&server_key).to_vec();
+        let keys = curr_key.generate_round_keys(&server_key);
         let mut state = State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0370734, &client_key);
 
         let ecb = ECB::new(&keys);
 
-        let start = Instant::now();
-        ecb.encrypt(&mut state, &server_key);
-        println!("ENCRYPT TIME TAKEN {:?}", start.elapsed());
+        ecb.encrypt_block(&mut state, &server_key);
 
         assert_eq!(
             state.decrypt_to_u128(&client_key),
             0x3925841d_02dc09fb_dc118597_196a0b32
         );
 
-        let start = Instant::now();
-        ecb.decrypt(&mut state, &server_key);
-        println!("DECRYPT TIME TAKEN {:?}", start.elapsed());
+        ecb.decrypt_block(&mut state, &server_key);
 
         assert_eq!(
             state.decrypt_to_u128(&client_key),
@@ -88,28 +217,185 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_ecb_192() {
+        // This test follows the example vector of Appendix C.2 of the FIPS-197 standard
+        let (client_key, server_key) = gen_keys();
+
+        let key_data: Vec<u8> = (0..24).collect();
+        let curr_key = Key::from_u8_enc(&key_data, &client_key);
+        let keys = curr_key.generate_round_keys(&server_key);
+        let mut state = State::from_u128_enc(0x00112233_44556677_8899aabb_ccddeeff, &client_key);
+
+        let ecb = ECB::new(&keys);
+        assert_eq!(ecb.rounds(), 12);
+
+        ecb.encrypt_block(&mut state, &server_key);
+        assert_eq!(
+            state.decrypt_to_u128(&client_key),
+            0xdda97ca4_864cdfe0_6eaf70a0_ec0d7191
+        );
+
+        ecb.decrypt_block(&mut state, &server_key);
+        assert_eq!(
+            state.decrypt_to_u128(&client_key),
+            0x00112233_44556677_8899aabb_ccddeeff
+        );
+    }
+
+    #[test]
+    fn test_ecb_256() {
+        // This test follows the example vector of Appendix C.3 of the FIPS-197 standard
+        let (client_key, server_key) = gen_keys();
+
+        let key_data: Vec<u8> = (0..32).collect();
+        let curr_key = Key::from_u8_enc(&key_data, &client_key);
+        let keys = curr_key.generate_round_keys(&server_key);
+        let mut state = State::from_u128_enc(0x00112233_44556677_8899aabb_ccddeeff, &client_key);
+
+        let ecb = ECB::new(&keys);
+        assert_eq!(ecb.rounds(), 14);
+
+        ecb.encrypt_block(&mut state, &server_key);
+        assert_eq!(
+            state.decrypt_to_u128(&client_key),
+            0x8ea2b7ca_516745bf_eafc4990_4b496089
+        );
+
+        ecb.decrypt_block(&mut state, &server_key);
+        assert_eq!(
+            state.decrypt_to_u128(&client_key),
+            0x00112233_44556677_8899aabb_ccddeeff
+        );
+    }
+
     #[test]
     fn test_ecb_twice() {
         let (client_key, server_key) = gen_keys();
 
         let curr_key = Key::from_u128_enc(0x2b7e1516_28aed2a6a_bf71588_09cf4f3c, &client_key);
-        let keys: Vec<_> = curr_key.generate_round_keys(&server_key).to_vec();
+        let keys = curr_key.generate_round_keys(&server_key);
         let mut state = State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0370734, &client_key);
         let mut state_1 = state.clone();
 
         let ecb = ECB::new(&keys);
+        let context = FheAesContext::new(server_key);
 
-        with_server_key(|server_key| {
-            ecb.encrypt(&mut state, &server_key);
-        });
-
-        with_server_key(|server_key| {
-            ecb.encrypt(&mut state_1, &server_key);
-        });
+        ecb.encrypt_block(&mut state, &context);
+        ecb.encrypt_block(&mut state_1, &context);
 
         assert_eq!(
             state.decrypt_to_u128(&client_key),
             state_1.decrypt_to_u128(&client_key)
         )
     }
+
+    #[test]
+    fn test_ecb_clear() {
+        let backend = ClearBackend;
+
+        for_each_key_size(|key_data| {
+            let keys = Key::from_u8_enc(key_data, &backend).generate_round_keys(&backend);
+            let ecb = ECB::new(&keys);
+
+            for block in plaintext_blocks() {
+                let mut state = State::from_u8_enc(&block, &backend);
+
+                ecb.encrypt_block(&mut state, &backend);
+                assert_eq!(
+                    state.decrypt_to_u8(&backend),
+                    clear_encrypt_block(key_data, &block)
+                );
+
+                ecb.decrypt_block(&mut state, &backend);
+                assert_eq!(state.decrypt_to_u8(&backend), block);
+            }
+        });
+    }
+
+    #[test]
+    fn test_ecb_blocks_clear() {
+        let backend = ClearBackend;
+
+        for_each_key_size(|key_data| {
+            let keys = Key::from_u8_enc(key_data, &backend).generate_round_keys(&backend);
+            let ecb = ECB::new(&keys);
+
+            let blocks = plaintext_blocks();
+            let mut states: Vec<_> = blocks
+                .iter()
+                .map(|block| State::from_u8_enc(block, &backend))
+                .collect();
+
+            ecb.encrypt_blocks(&mut states, &backend);
+            for (state, block) in states.iter().zip(blocks.iter()) {
+                assert_eq!(
+                    state.decrypt_to_u8(&backend),
+                    clear_encrypt_block(key_data, block)
+                );
+            }
+
+            ecb.decrypt_blocks(&mut states, &backend);
+            for (state, block) in states.iter().zip(blocks.iter()) {
+                assert_eq!(state.decrypt_to_u8(&backend), *block);
+            }
+        });
+    }
+
+    #[test]
+    fn test_ecb_blocks_cost() {
+        let backend = CountingBackend::default();
+        let keys: Vec<_> = key_expansion_clear(&KEY_128)
+            .iter()
+            .map(|round_key| Key::from_u8_enc(round_key, &backend))
+            .collect();
+        let ecb = ECB::new(&keys);
+
+        let mut block = State::from_u8_enc(&[0; 16], &backend);
+        let (_, block_counts) = backend.measure(|| ecb.encrypt_block(&mut block, &backend));
+
+        // The blocks are scheduled in the same DAG, so 4 blocks cost 4 times the bootstraps of one with the same depth
+        let mut states: Vec<_> = (0..4)
+            .map(|i| State::from_u8_enc(&[i; 16], &backend))
+            .collect();
+        backend.reset();
+        let (_, counts) = backend.measure(|| ecb.encrypt_blocks(&mut states, &backend));
+        assert_eq!(counts.bootstraps, 4 * block_counts.bootstraps);
+        assert_eq!(counts.depth, block_counts.depth);
+    }
+
+    #[test]
+    fn test_ecb_clear_key() {
+        for_each_key_size(|key_data| {
+            let round_keys = key_expansion_clear(key_data);
+            let block = PLAINTEXT[0].to_be_bytes();
+
+            let backend = CountingBackend::default();
+            let ecb = ECB::new_clear(&round_keys, &backend);
+            let mut state = State::from_u8_enc(&block, &backend);
+            let ((), clear_key_counts) =
+                backend.measure(|| ecb.encrypt_block(&mut state, &backend));
+            assert_eq!(
+                state.decrypt_to_u8(&backend),
+                clear_encrypt_block(key_data, &block)
+            );
+
+            ecb.decrypt_block(&mut state, &backend);
+            assert_eq!(state.decrypt_to_u8(&backend), block);
+
+            // AddRoundKey is free with clear round keys
+            let backend = CountingBackend::default();
+            let keys: Vec<_> = round_keys
+                .iter()
+                .map(|round_key| Key::from_u8_enc(round_key, &backend))
+                .collect();
+            let ecb = ECB::new(&keys);
+            let mut state = State::from_u8_enc(&block, &backend);
+            let ((), counts) = backend.measure(|| ecb.encrypt_block(&mut state, &backend));
+            assert_eq!(
+                counts.bootstraps - clear_key_counts.bootstraps,
+                128 * round_keys.len() as u64
+            );
+        });
+    }
 }
@@ -0,0 +1,214 @@
+use crate::ecb::ECB;
+use base::gf128;
+use base::primitive::{FHEByte, all};
+use base::*;
+use rayon::prelude::*;
+use std::fmt::Debug;
+use tfhe::boolean::prelude::*;
+
+/// GCM is the Galois/Counter Mode for AES, as in NIST SP 800-38D, decrypted homomorphically under an FHE encrypted key
+///
+/// As for the `Transcipher`, the IV, the additional authenticated data, the ciphertext and the tag are produced by any standard AES-GCM
+/// library and stay public, so they enter the FHE context as trivial encryptions. The hash key `H = E_K(0)`, the pre-counter block
+/// `E_K(J0)` and the keystream are encrypted in a single `ECB::encrypt_blocks`. The GHASH of the `m` blocks of the AAD, of the ciphertext
+/// and of their lengths is computed as the sum of the `X_i . H^(m - i + 1)`: the powers of `H` are products of encrypted elements, see
+/// `gf128::powers`, while the products by the clear blocks `X_i` are xors only.
+///
+/// The IV is the 96-bit IV recommended by the specification, other lengths requiring the GHASH of the IV to derive the counters.
+pub struct GCM<T = Ciphertext> {
+    ecb: ECB<T>,
+}
+
+/// The tag lengths allowed by NIST SP 800-38D, in bytes: the 128, 120, 112, 104 and 96-bit tags, and the 64 and 32-bit tags of some
+/// applications.
+pub const TAG_LENGTHS: [usize; 7] = [4, 8, 12, 13, 14, 15, 16];
+
+/// Splits the data into 16-byte blocks read as big endian integers, padding the last block with zeros.
+fn to_elements(data: &[u8]) -> impl Iterator<Item = u128> + '_ {
+    data.chunks(16).map(|chunk| {
+        let mut block = [0u8; 16];
+        block[..chunk.len()].copy_from_slice(chunk);
+        u128::from_be_bytes(block)
+    })
+}
+
+/// The counter block `J0 + i`, only the last 32 bits being incremented.
+fn inc32(j0: u128, i: u32) -> u128 {
+    (j0 & !0xffff_ffff) | (j0 as u32).wrapping_add(i) as u128
+}
+
+/// Xors encrypted bits with clear ones, which is free: the bit is copied, or negated.
+fn xor_clear<B: Backend>(bits: &[B::Bit], clear: &[u8], server_key: &B) -> Vec<B::Bit> {
+    bits.par_iter()
+        .enumerate()
+        .map(|(i, bit)| {
+            if (clear[i / 8] >> (7 - i % 8)) & 1 != 0 {
+                server_key.not(bit)
+            } else {
+                bit.clone()
+            }
+        })
+        .collect()
+}
+
+impl<T: Clone + Send + Sync + Debug> GCM<T> {
+    pub fn new(keys: &[Key<T>]) -> Self {
+        GCM {
+            ecb: ECB::new(keys),
+        }
+    }
+
+    /// Creates the GCM from the encrypted cipher key, running the key expansion in the FHE context.
+    pub fn from_key<B: Backend<Bit = T>>(key: &Key<T>, server_key: &B) -> Self {
+        Self::new(&key.generate_round_keys(server_key))
+    }
+
+    /// Decrypts the ciphertext and verifies its tag, which can be truncated to its first 4, 8 or 12 to 16 bytes, see `TAG_LENGTHS`.
+    ///
+    /// Returns the encrypted plaintext, one byte per byte of the ciphertext, and an encrypted bit which is true when the tag matches. The
+    /// plaintext is returned even if the tag does not match, as the server cannot know it: the client, or a further homomorphic
+    /// computation, decides what to do with it from the bit.
+    pub fn decrypt<B: Backend<Bit = T>>(
+        &self,
+        iv: &[u8; 12],
+        aad: &[u8],
+        ciphertext: &[u8],
+        tag: &[u8],
+        server_key: &B,
+    ) -> (Vec<FHEByte<T>>, T) {
+        assert!(
+            TAG_LENGTHS.contains(&tag.len()),
+            "A GCM tag has 4, 8 or 12 to 16 bytes, it is {} bytes !",
+            tag.len()
+        );
+
+        server_key.install(|| {
+            let mut j0 = [0u8; 16];
+            j0[..12].copy_from_slice(iv);
+            j0[15] = 1;
+            let j0 = u128::from_be_bytes(j0);
+
+            // H, E_K(J0) and the keystream, from J0 + 1, in a single batch
+            let num_blocks = ciphertext.len().div_ceil(16);
+            let mut states: Vec<State<T>> = [0, j0]
+                .into_iter()
+                .chain((1..=num_blocks as u32).map(|i| inc32(j0, i)))
+                .map(|block| State::from_u128_clear(block, server_key))
+                .collect();
+            self.ecb.encrypt_blocks(&mut states, server_key);
+
+            let h = states[0].to_bits();
+            let encrypted_j0 = states[1].to_bits();
+            let keystream: Vec<T> = states[2..].iter().flat_map(State::to_bits).collect();
+
+            let plaintext: Vec<FHEByte<T>> =
+                xor_clear(&keystream[..ciphertext.len() * 8], ciphertext, server_key)
+                    .chunks(8)
+                    .map(FHEByte::from_bits)
+                    .collect();
+
+            // GHASH of the AAD, the ciphertext and the block of their lengths in bits
+            let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+            let blocks: Vec<u128> = to_elements(aad)
+                .chain(to_elements(ciphertext))
+                .chain([lengths])
+                .collect();
+            let powers = gf128::powers(&h, blocks.len(), server_key);
+            let pairs: Vec<(u128, &[T])> = blocks
+                .iter()
+                .zip(powers.iter().rev())
+                .map(|(&block, power)| (block, power.as_slice()))
+                .collect();
+
+            let mut terms = gf128::mul_clear_batch(&pairs, server_key);
+            terms.push(encrypted_j0);
+            let expected_tag = gf128::sum(&terms, server_key);
+
+            // A bit matches when it is the bit of the tag, i.e. its xor with the complement of the tag
+            let complement: Vec<u8> = tag.iter().map(|x| !x).collect();
+            let matches = xor_clear(&expected_tag[..tag.len() * 8], &complement, server_key);
+
+            (plaintext, all(matches, server_key))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_utils::*;
+
+    fn new_gcm(key: &str, backend: &ClearBackend) -> GCM<bool> {
+        let key = Key::from_u8_enc(&hex_bytes(key), backend);
+        GCM::from_key(&key, backend)
+    }
+
+    // The test vectors below are the test cases 2 and 4 of "The Galois/Counter Mode of Operation (GCM)" (McGrew, Viega)
+    #[test]
+    fn test_gcm_zero_key() {
+        let backend = ClearBackend;
+        let gcm = new_gcm("00000000000000000000000000000000", &backend);
+
+        let ciphertext = hex_bytes("0388dace60b6a392f328c2b971b2fe78");
+        let tag = hex_bytes("ab6e47d42cec13bdf53a67b21257bddf");
+        let (plaintext, valid) = gcm.decrypt(&[0; 12], &[], &ciphertext, &tag, &backend);
+
+        assert_eq!(from_bytes(&plaintext, &backend), [0; 16]);
+        assert!(backend.decrypt(&valid));
+    }
+
+    #[test]
+    fn test_gcm_aad() {
+        let backend = ClearBackend;
+        let gcm = new_gcm("feffe9928665731c6d6a8f9467308308", &backend);
+
+        let iv: [u8; 12] = hex_bytes("cafebabefacedbaddecaf888").try_into().unwrap();
+        let aad = hex_bytes("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let ciphertext = hex_bytes(
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+             21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091",
+        );
+        let plaintext = hex_bytes(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        );
+        let tag = hex_bytes("5bc94fbc3221a5db94fae95ae7121a47");
+
+        let (decrypted, valid) = gcm.decrypt(&iv, &aad, &ciphertext, &tag, &backend);
+        assert_eq!(from_bytes(&decrypted, &backend), plaintext);
+        assert!(backend.decrypt(&valid));
+
+        // A truncated tag is checked on its first bytes
+        let (_, valid) = gcm.decrypt(&iv, &aad, &ciphertext, &tag[..12], &backend);
+        assert!(backend.decrypt(&valid));
+
+        // Any change to the tag, the ciphertext or the AAD is detected
+        let mut wrong_tag = tag.clone();
+        wrong_tag[15] ^= 1;
+        let mut wrong_ciphertext = ciphertext.clone();
+        wrong_ciphertext[40] ^= 0x80;
+        let mut wrong_aad = aad.clone();
+        wrong_aad[0] ^= 4;
+
+        for (aad, ciphertext, tag) in [
+            (&aad, &ciphertext, &wrong_tag),
+            (&aad, &wrong_ciphertext, &tag),
+            (&wrong_aad, &ciphertext, &tag),
+        ] {
+            let (_, valid) = gcm.decrypt(&iv, aad, ciphertext, tag, &backend);
+            assert!(!backend.decrypt(&valid));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_gcm_invalid_tag_length() {
+        let backend = ClearBackend;
+        let gcm = new_gcm("00000000000000000000000000000000", &backend);
+
+        let ciphertext = hex_bytes("0388dace60b6a392f328c2b971b2fe78");
+        let tag = hex_bytes("ab6e47d42cec13bdf53a67b21257bddf");
+        gcm.decrypt(&[0; 12], &[], &ciphertext, &tag[..10], &backend);
+    }
+}
@@ -1,4 +1,15 @@
+pub mod block_mode;
 pub mod cbc;
+pub mod cfb;
+pub mod cmac;
+pub mod cost;
 pub mod ctr;
+pub mod dag;
 pub mod ecb;
+pub mod gcm;
 pub mod ofb;
+#[cfg(test)]
+mod test_utils;
+pub mod transcipher;
+
+pub use block_mode::FheBlockMode;
@@ -1,40 +1,71 @@
+use crate::FheBlockMode;
 use crate::ecb::ECB;
 use base::*;
+use std::fmt::Debug;
 use tfhe::boolean::prelude::*;
 
-/// OFB is the Output Feedback mode for AES-128
+/// OFB is the Output Feedback mode for AES
+///
+/// The output feedback is kept from one call to the next, for the encryption and for the decryption, so a message can be encrypted or
+/// decrypted in chunks.
 
-pub struct OFB {
-    ecb: ECB,
-    iv: State,
-    n: u8,
+pub struct OFB<T = Ciphertext> {
+    ecb: ECB<T>,
+    encrypt_feedback: State<T>,
+    decrypt_feedback: State<T>,
 }
 
-impl OFB {
-    pub fn new(keys: &[Key], iv: &State, n: u8) -> Self {
-        OFB {
-            ecb: ECB::new(keys),
-            iv: iv.clone(),
-            n,
-        }
+impl<T: Clone + Send + Sync + Debug> OFB<T> {
+    /// Creates the OFB with public round keys, the output feedback starting from the encrypted IV.
+    pub fn new_clear<B: Backend<Bit = T>>(
+        round_keys: &[[u8; 16]],
+        iv: &State<T>,
+        server_key: &B,
+    ) -> Self {
+        Self::from_ecb(ECB::new_clear(round_keys, server_key), iv)
     }
 
-    pub fn encrypt(&self, plaintext: &mut [State], server_key: &ServerKey) {
-        let mut curr_state = self.iv.clone();
+    pub fn new(keys: &[Key<T>], iv: &State<T>) -> Self {
+        Self::from_ecb(ECB::new(keys), iv)
+    }
 
-        for i in 0..self.n {
-            self.ecb.encrypt(&mut curr_state, server_key);
-            plaintext[i as usize].xor_state(&curr_state, server_key);
+    fn from_ecb(ecb: ECB<T>, iv: &State<T>) -> Self {
+        OFB {
+            ecb,
+            encrypt_feedback: iv.clone(),
+            decrypt_feedback: iv.clone(),
         }
     }
 
-    pub fn decrypt(&self, ciphertexts: &mut [State], server_key: &ServerKey) {
-        let mut curr_state = self.iv.clone();
+    /// Xors the blocks with the next outputs of the feedback, which is updated.
+    fn apply_feedback<B: Backend<Bit = T>>(
+        ecb: &ECB<T>,
+        feedback: &mut State<T>,
+        blocks: &mut [State<T>],
+        server_key: &B,
+    ) {
+        server_key.install(|| {
+            for block in blocks.iter_mut() {
+                ecb.encrypt_block(feedback, server_key);
+                block.xor_state(feedback, server_key);
+            }
+        })
+    }
+}
 
-        for i in 0..self.n {
-            self.ecb.encrypt(&mut curr_state, server_key);
-            ciphertexts[i as usize].xor_state(&curr_state, server_key);
-        }
+/// The output feedback does not depend on the message, so decrypting is encrypting, with its own feedback.
+impl<T: Clone + Send + Sync + Debug> FheBlockMode<T> for OFB<T> {
+    fn encrypt<B: Backend<Bit = T>>(&mut self, plaintext: &mut [State<T>], server_key: &B) {
+        Self::apply_feedback(&self.ecb, &mut self.encrypt_feedback, plaintext, server_key);
+    }
+
+    fn decrypt<B: Backend<Bit = T>>(&mut self, ciphertexts: &mut [State<T>], server_key: &B) {
+        Self::apply_feedback(
+            &self.ecb,
+            &mut self.decrypt_feedback,
+            ciphertexts,
+            server_key,
+        );
     }
 }
 
@@ -43,8 +74,7 @@ impl OFB {
 mod tests {
 
     use super::*;
-    use base::primitive::*;
-    use std::time::Instant;
+    use crate::test_utils::*;
     use tfhe::boolean::gen_keys;
 
     #[test]
@@ -54,7 +84,7 @@ mod tests {
         let curr_key = Key::from_u128_enc(0x2b7e1516_28aed2a6a_bf71588_09cf4f3c, &client_key);
         let keys = curr_key.generate_round_keys(&server_key);
         let iv = State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0312122, &client_key);
-        let ofb = OFB::new(&keys, &iv, 2);
+        let mut ofb = OFB::new(&keys, &iv);
 
         let plaintext_block_0 =
             State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0370734, &client_key);
@@ -62,14 +92,8 @@ mod tests {
             State::from_u128_enc(0x3243f6a8_885a308d_313198a2_e0372324, &client_key);
         let mut plaintext = vec![plaintext_block_0, plaintext_block_1];
 
-        let start = Instant::now();
-
         ofb.encrypt(&mut plaintext, &server_key);
-        println!("ENCRYPT TIME TAKEN {:?}", start.elapsed());
-
-        let start = Instant::now();
         ofb.decrypt(&mut plaintext, &server_key);
-        println!("DECRYPT TIME TAKEN {:?}", start.elapsed());
 
         assert_eq!(
             plaintext[0].decrypt_to_u128(&client_key),
@@ -80,4 +104,42 @@ mod tests {
             0x3243f6a8_885a308d_313198a2_e0372324
         );
     }
+
+    #[test]
+    fn test_ofb_clear() {
+        let backend = ClearBackend;
+
+        for_each_key_size(|key_data| {
+            let keys = Key::from_u8_enc(key_data, &backend).generate_round_keys(&backend);
+            let iv_data = IV.to_be_bytes();
+            let mut ofb = OFB::new(&keys, &State::from_u8_enc(&iv_data, &backend));
+
+            let blocks = plaintext_blocks();
+            let mut states: Vec<_> = blocks
+                .iter()
+                .map(|block| State::from_u8_enc(block, &backend))
+                .collect();
+
+            let mut output = iv_data;
+            let expected: Vec<_> = blocks
+                .iter()
+                .map(|block| {
+                    output = clear_encrypt_block(key_data, &output);
+                    let mut expected = *block;
+                    expected.iter_mut().zip(output).for_each(|(x, y)| *x ^= y);
+                    expected
+                })
+                .collect();
+
+            ofb.encrypt(&mut states, &backend);
+            for (state, expected) in states.iter().zip(expected) {
+                assert_eq!(state.decrypt_to_u8(&backend), expected);
+            }
+
+            ofb.decrypt(&mut states, &backend);
+            for (state, block) in states.iter().zip(blocks) {
+                assert_eq!(state.decrypt_to_u8(&backend), block);
+            }
+        });
+    }
 }
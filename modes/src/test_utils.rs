@@ -0,0 +1,72 @@
+use aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray};
+use aes::{Aes128, Aes192, Aes256};
+use base::{ClearBackend, FHEByte};
+
+// The test vectors below are the keys, IV, initial counter and plaintext of the examples of Appendix F of NIST SP 800-38A
+pub const KEY_128: [u8; 16] = [
+    0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
+];
+pub const KEY_192: [u8; 24] = [
+    0x8e, 0x73, 0xb0, 0xf7, 0xda, 0x0e, 0x64, 0x52, 0xc8, 0x10, 0xf3, 0x2b, 0x80, 0x90, 0x79, 0xe5,
+    0x62, 0xf8, 0xea, 0xd2, 0x52, 0x2c, 0x6b, 0x7b,
+];
+pub const KEY_256: [u8; 32] = [
+    0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d, 0x77, 0x81,
+    0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3, 0x09, 0x14, 0xdf, 0xf4,
+];
+pub const IV: u128 = 0x00010203_04050607_08090a0b_0c0d0e0f;
+pub const INITIAL_COUNTER: u128 = 0xf0f1f2f3_f4f5f6f7_f8f9fafb_fcfdfeff;
+pub const PLAINTEXT: [u128; 4] = [
+    0x6bc1bee2_2e409f96_e93d7e11_7393172a,
+    0xae2d8a57_1e03ac9c_9eb76fac_45af8e51,
+    0x30c81c46_a35ce411_e5fbc119_1a0a52ef,
+    0xf69f2445_df4f9b17_ad2b417b_e66c3710,
+];
+
+/// Runs the test with the key of every AES key size.
+pub fn for_each_key_size(test: impl Fn(&[u8])) {
+    for key in [&KEY_128[..], &KEY_192[..], &KEY_256[..]] {
+        test(key);
+    }
+}
+
+/// The blocks of the plaintext, as bytes.
+pub fn plaintext_blocks() -> Vec<[u8; 16]> {
+    PLAINTEXT.iter().map(|x| x.to_be_bytes()).collect()
+}
+
+/// Encrypts a block with the reference implementation of the `aes` crate, for any of the three key sizes
+pub fn clear_encrypt_block(key: &[u8], block: &[u8; 16]) -> [u8; 16] {
+    let mut block = GenericArray::clone_from_slice(block);
+    match key.len() {
+        16 => Aes128::new_from_slice(key)
+            .unwrap()
+            .encrypt_block(&mut block),
+        24 => Aes192::new_from_slice(key)
+            .unwrap()
+            .encrypt_block(&mut block),
+        32 => Aes256::new_from_slice(key)
+            .unwrap()
+            .encrypt_block(&mut block),
+        _ => panic!("Invalid key length"),
+    }
+    block.into()
+}
+
+/// Parses a hexadecimal string, two digits per byte.
+pub fn hex_bytes(data: &str) -> Vec<u8> {
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+pub fn to_bytes(data: &[u8], backend: &ClearBackend) -> Vec<FHEByte<bool>> {
+    data.iter()
+        .map(|x| FHEByte::from_u8_enc(x, backend))
+        .collect()
+}
+
+pub fn from_bytes(bytes: &[FHEByte<bool>], backend: &ClearBackend) -> Vec<u8> {
+    bytes.iter().map(|x| x.decrypt_to_u8(backend)).collect()
+}
@@ -0,0 +1,257 @@
+use crate::ecb::ECB;
+use base::*;
+use rayon::prelude::*;
+use std::fmt::Debug;
+use tfhe::boolean::prelude::*;
+
+/// Transcipher homomorphically decrypts standard AES ciphertexts under an FHE encrypted key
+///
+/// The ciphertext and the IV/nonce are produced by any standard AES library and stay public: they enter the FHE context as trivial
+/// encryptions, so the server never needs the client key. The result holds the plaintext blocks, encrypted under the key that encrypted the AES key.
+///
+/// As every ciphertext block is known in the clear, the blocks of all three modes are decrypted independently of each other.
+pub struct Transcipher<T = Ciphertext> {
+    ecb: ECB<T>,
+}
+
+/// Splits the ciphertext into 16-byte blocks, the ciphertext of ECB and CBC being a whole number of blocks.
+fn to_blocks(ciphertext: &[u8]) -> Vec<[u8; 16]> {
+    assert!(
+        ciphertext.len() % 16 == 0,
+        "The ciphertext has to be a multiple of 16 bytes, it is {} bytes !",
+        ciphertext.len()
+    );
+    ciphertext
+        .chunks_exact(16)
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect()
+}
+
+/// Splits the ciphertext into 16-byte blocks, padding the last block with zeros.
+fn to_padded_blocks(ciphertext: &[u8]) -> Vec<[u8; 16]> {
+    ciphertext
+        .chunks(16)
+        .map(|chunk| {
+            let mut block = [0u8; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            block
+        })
+        .collect()
+}
+
+impl<T: Clone + Send + Sync + Debug> Transcipher<T> {
+    pub fn new(keys: &[Key<T>]) -> Self {
+        Transcipher {
+            ecb: ECB::new(keys),
+        }
+    }
+
+    /// Creates the transcipher from the encrypted cipher key, running the key expansion in the FHE context.
+    pub fn from_key<B: Backend<Bit = T>>(key: &Key<T>, server_key: &B) -> Self {
+        Self::new(&key.generate_round_keys(server_key))
+    }
+
+    pub fn ecb<B: Backend<Bit = T>>(&self, ciphertext: &[u8], server_key: &B) -> Vec<State<T>> {
+        server_key.install(|| {
+            let mut states: Vec<_> = to_blocks(ciphertext)
+                .iter()
+                .map(|block| State::from_u8_clear(block, server_key))
+                .collect();
+
+            self.ecb.decrypt_blocks(&mut states, server_key);
+
+            states
+        })
+    }
+
+    pub fn cbc<B: Backend<Bit = T>>(
+        &self,
+        ciphertext: &[u8],
+        iv: &[u8; 16],
+        server_key: &B,
+    ) -> Vec<State<T>> {
+        server_key.install(|| {
+            let blocks = to_blocks(ciphertext);
+            let mut states: Vec<_> = blocks
+                .iter()
+                .map(|block| State::from_u8_clear(block, server_key))
+                .collect();
+
+            let previous_blocks = std::iter::once(iv).chain(blocks.iter());
+            self.ecb.decrypt_blocks(&mut states, server_key);
+            states
+                .par_iter_mut()
+                .zip(previous_blocks.collect::<Vec<_>>())
+                .for_each(|(x, previous)| x.xor_key_clear(previous, server_key));
+
+            states
+        })
+    }
+
+    /// The counter block is incremented as a 128-bit big endian integer for every block, starting from `initial_counter`.
+    ///
+    /// The last ciphertext block can be partial, in which case the trailing bytes of the last state are not part of the plaintext.
+    pub fn ctr<B: Backend<Bit = T>>(
+        &self,
+        ciphertext: &[u8],
+        initial_counter: &[u8; 16],
+        server_key: &B,
+    ) -> Vec<State<T>> {
+        server_key.install(|| {
+            let initial_counter = u128::from_be_bytes(*initial_counter);
+            let blocks = to_padded_blocks(ciphertext);
+
+            let mut states: Vec<_> = (0..blocks.len())
+                .map(|i| {
+                    let counter = initial_counter.wrapping_add(i as u128).to_be_bytes();
+                    State::from_u8_clear(&counter, server_key)
+                })
+                .collect();
+
+            self.ecb.encrypt_blocks(&mut states, server_key);
+            states
+                .par_iter_mut()
+                .zip(blocks)
+                .for_each(|(state, block)| state.xor_key_clear(&block, server_key));
+
+            states
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_utils::*;
+    use tfhe::boolean::gen_keys;
+
+    fn new_clear_transcipher(key: &[u8]) -> Transcipher<bool> {
+        Transcipher::from_key(&Key::from_u8_enc(key, &ClearBackend), &ClearBackend)
+    }
+
+    fn decrypt_blocks(states: &[State<bool>]) -> Vec<u8> {
+        states
+            .iter()
+            .flat_map(|state| state.decrypt_to_u8(&ClearBackend))
+            .collect()
+    }
+
+    fn xor(lhs: &[u8; 16], rhs: &[u8; 16]) -> [u8; 16] {
+        std::array::from_fn(|i| lhs[i] ^ rhs[i])
+    }
+
+    #[test]
+    fn test_transcipher_ecb_clear() {
+        for_each_key_size(|key| {
+            let blocks = plaintext_blocks();
+            let ciphertext: Vec<u8> = blocks
+                .iter()
+                .flat_map(|block| clear_encrypt_block(key, block))
+                .collect();
+
+            let plaintext = new_clear_transcipher(key).ecb(&ciphertext, &ClearBackend);
+            assert_eq!(decrypt_blocks(&plaintext), blocks.concat());
+        });
+    }
+
+    #[test]
+    fn test_transcipher_cbc_clear() {
+        for_each_key_size(|key| {
+            let blocks = plaintext_blocks();
+            let mut previous = IV.to_be_bytes();
+            let mut ciphertext = Vec::new();
+            for block in blocks.iter() {
+                previous = clear_encrypt_block(key, &xor(block, &previous));
+                ciphertext.extend(previous);
+            }
+
+            let plaintext =
+                new_clear_transcipher(key).cbc(&ciphertext, &IV.to_be_bytes(), &ClearBackend);
+            assert_eq!(decrypt_blocks(&plaintext), blocks.concat());
+        });
+    }
+
+    #[test]
+    fn test_transcipher_ctr_clear() {
+        for_each_key_size(|key| {
+            let blocks = plaintext_blocks();
+            let ciphertext: Vec<u8> = blocks
+                .iter()
+                .enumerate()
+                .flat_map(|(i, block)| {
+                    let counter = INITIAL_COUNTER.wrapping_add(i as u128).to_be_bytes();
+                    xor(block, &clear_encrypt_block(key, &counter))
+                })
+                .collect();
+
+            // The last block is partial, and its trailing bytes are not part of the plaintext
+            let plaintext = new_clear_transcipher(key).ctr(
+                &ciphertext[..60],
+                &INITIAL_COUNTER.to_be_bytes(),
+                &ClearBackend,
+            );
+            assert_eq!(plaintext.len(), 4);
+            assert_eq!(decrypt_blocks(&plaintext)[..60], blocks.concat()[..60]);
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_transcipher_partial_block() {
+        new_clear_transcipher(&KEY_128).ecb(&[0; 20], &ClearBackend);
+    }
+
+    #[test]
+    fn test_transcipher_ecb() {
+        let (client_key, server_key) = gen_keys();
+
+        let key = Key::from_u8_enc(&KEY_128, &client_key);
+        let transcipher = Transcipher::from_key(&key, &server_key);
+        let ciphertext = 0x3ad77bb4_0d7a3660_a89ecaf3_2466ef97_u128.to_be_bytes();
+
+        let plaintext = transcipher.ecb(&ciphertext, &server_key);
+
+        assert_eq!(plaintext[0].decrypt_to_u128(&client_key), PLAINTEXT[0]);
+    }
+
+    #[test]
+    fn test_transcipher_cbc() {
+        let (client_key, server_key) = gen_keys();
+
+        let key = Key::from_u8_enc(&KEY_128, &client_key);
+        let transcipher = Transcipher::from_key(&key, &server_key);
+        let ciphertext: Vec<u8> = [
+            0x7649abac_8119b246_cee98e9b_12e9197d_u128,
+            0x5086cb9b_507219ee_95db113a_917678b2,
+        ]
+        .iter()
+        .flat_map(|x| x.to_be_bytes())
+        .collect();
+
+        let plaintext = transcipher.cbc(&ciphertext, &IV.to_be_bytes(), &server_key);
+
+        assert_eq!(plaintext[0].decrypt_to_u128(&client_key), PLAINTEXT[0]);
+        assert_eq!(plaintext[1].decrypt_to_u128(&client_key), PLAINTEXT[1]);
+    }
+
+    #[test]
+    fn test_transcipher_ctr() {
+        let (client_key, server_key) = gen_keys();
+
+        let key = Key::from_u8_enc(&KEY_128, &client_key);
+        let transcipher = Transcipher::from_key(&key, &server_key);
+        let ciphertext: Vec<u8> = [
+            0x874d6191_b620e326_1bef6864_990db6ce_u128,
+            0x9806f66b_7970fdff_8617187b_b9fffdff,
+        ]
+        .iter()
+        .flat_map(|x| x.to_be_bytes())
+        .collect();
+
+        let plaintext = transcipher.ctr(&ciphertext, &INITIAL_COUNTER.to_be_bytes(), &server_key);
+
+        assert_eq!(plaintext[0].decrypt_to_u128(&client_key), PLAINTEXT[0]);
+        assert_eq!(plaintext[1].decrypt_to_u128(&client_key), PLAINTEXT[1]);
+    }
+}
@@ -1,13 +1,16 @@
-use std::array;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
-use aes::Aes128;
-use base::key_schedule::key_expansion_clear;
-use base::{Key, State};
+use aes::{Aes128, Aes192, Aes256};
+use base::counting::{mux_order_report, operation_report, s_box_report};
+use base::key_schedule::{key_expansion_clear, KeySize};
+use base::sbox::SBoxCircuit;
+use base::{Backend, FheAesContext, Key, State};
 use clap::Parser;
 use hex;
-use modes::{cbc::CBC, ctr::CTR, ecb::ECB, ofb::OFB};
+use modes::cost::{mode_report, round_report};
+use modes::dag::{EncryptionDag, Mode};
+use modes::{cbc::CBC, ctr::CTR, ecb::ECB, ofb::OFB, FheBlockMode};
 use rand::Rng;
 use tfhe::boolean::gen_keys;
 use tfhe::boolean::prelude::*;
@@ -16,7 +19,7 @@ use tfhe::boolean::prelude::*;
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short = 'n', long = "number-of-outputs", default_value_t = 1)]
-    number_of_outputs: u8,
+    number_of_outputs: usize,
 
     #[arg(short, long)]
     iv: String,
@@ -29,13 +32,42 @@ struct Args {
 
     #[arg(short, long, default_value = "ECB")]
     mode: String,
+
+    #[arg(short = 'g', long = "count-gates", default_value_t = false)]
+    count_gates: bool,
+
+    #[arg(short = 's', long = "s-box", default_value_t = SBoxCircuit::MuxTree)]
+    s_box: SBoxCircuit,
+
+    #[arg(short = 'd', long = "dag", default_value_t = false)]
+    dag: bool,
 }
 
-enum Mode {
-    ECB,
-    CBC,
-    CTR,
-    OFB,
+/// Clear AES reference implementation used to check the FHE results, for any of the three key sizes
+enum AesClear {
+    Aes128(Aes128),
+    Aes192(Aes192),
+    Aes256(Aes256),
+}
+
+impl AesClear {
+    fn new(key: &[u8]) -> Self {
+        match key.len() {
+            16 => AesClear::Aes128(Aes128::new(GenericArray::from_slice(key))),
+            24 => AesClear::Aes192(Aes192::new(GenericArray::from_slice(key))),
+            32 => AesClear::Aes256(Aes256::new(GenericArray::from_slice(key))),
+            _ => panic!("Invalid key length: {}", key.len()),
+        }
+    }
+
+    fn encrypt_block(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            AesClear::Aes128(aes) => aes.encrypt_block(block),
+            AesClear::Aes192(aes) => aes.encrypt_block(block),
+            AesClear::Aes256(aes) => aes.encrypt_block(block),
+        }
+    }
 }
 
 fn main() {
@@ -46,15 +78,28 @@ fn main() {
     println!("Key: {}", args.key);
     println!("Key Expansion Offline: {}", args.key_expansion_offline);
     println!("Mode: {}", args.mode);
+    println!("S-Box: {}", args.s_box);
+    println!("Gate DAG: {}", args.dag);
 
-    let key = parse_hex_16(&args.key).expect("Invalid key format");
+    let key = parse_hex_key(&args.key).expect("Invalid key format");
     let iv = parse_hex_16(&args.iv).expect("Invalid IV format");
-    let mode = parse_mode(&args.mode).expect("Invalid Mode format");
-    let key_expansion_offline = args.key_expansion_offline;
+    let mode: Mode = args.mode.parse().expect("Invalid Mode format");
     let number_of_outputs = args.number_of_outputs;
 
+    if args.count_gates {
+        // Gate counts on plain booleans, no FHE evaluation is run
+        let key_size = KeySize::from_bytes(key.len());
+        println!("{}", s_box_report());
+        println!("{}", mux_order_report());
+        println!("{}", operation_report(key_size));
+        println!("{}", round_report(key_size, false));
+        println!("{}", round_report(key_size, true));
+        println!("{}", mode_report(key_size, number_of_outputs));
+        return;
+    }
+
     let mut rng = rand::rng();
-    let mut random_test_blocks = Vec::with_capacity(args.number_of_outputs as usize);
+    let mut random_test_blocks = Vec::with_capacity(args.number_of_outputs);
     for _ in 0..args.number_of_outputs {
         let mut block = [0u8; 16];
         rng.fill(&mut block);
@@ -62,53 +107,31 @@ fn main() {
     }
 
     let (client_key, server_key) = gen_keys();
+    let context = FheAesContext::with_s_box_circuit(server_key, args.s_box);
+
+    let test = TestCase {
+        mode,
+        key: &key,
+        iv: &iv,
+        blocks: &random_test_blocks,
+        key_expansion_offline: args.key_expansion_offline,
+    };
 
-    match mode {
-        Mode::ECB => test_ecb(
-            &key,
-            &random_test_blocks,
-            key_expansion_offline,
-            &server_key,
-            &client_key,
-        ),
-        Mode::CBC => test_cbc(
-            &key,
-            &iv,
-            &random_test_blocks,
-            key_expansion_offline,
-            number_of_outputs,
-            &server_key,
-            &client_key,
-        ),
-        Mode::CTR => test_ctr(
-            &key,
-            &iv,
-            &random_test_blocks,
-            key_expansion_offline,
-            number_of_outputs,
-            &server_key,
-            &client_key,
-        ),
-        Mode::OFB => test_ofb(
-            &key,
-            &iv,
-            &random_test_blocks,
-            key_expansion_offline,
-            number_of_outputs,
-            &server_key,
-            &client_key,
-        ),
+    if args.dag {
+        test_dag(&test, &context, &client_key);
+        return;
     }
+
+    test_mode(&test, &context, &client_key);
 }
 
-fn parse_mode(input: &str) -> Result<Mode, String> {
-    match input {
-        "ECB" => Ok(Mode::ECB),
-        "CBC" => Ok(Mode::CBC),
-        "CTR" => Ok(Mode::CTR),
-        "OFB" => Ok(Mode::OFB),
-        _ => Err(format!("Invalid mode: {}", input)),
-    }
+/// The inputs of a run: the mode, its key and IV, and the clear blocks to encrypt
+struct TestCase<'a> {
+    mode: Mode,
+    key: &'a [u8],
+    iv: &'a [u8; 16],
+    blocks: &'a [[u8; 16]],
+    key_expansion_offline: bool,
 }
 
 fn parse_hex_16(hex_str: &str) -> Result<[u8; 16], String> {
@@ -124,95 +147,86 @@ fn parse_hex_16(hex_str: &str) -> Result<[u8; 16], String> {
     Ok(array)
 }
 
-fn test_ecb(
-    key: &[u8; 16],
-    blocks: &[[u8; 16]],
-    key_expansion_offline: bool,
-    server_key: &ServerKey,
-    client_key: &ClientKey,
-) {
-    println!("---Testing ECB mode---");
-
-    let aes_clear = Aes128::new(GenericArray::from_slice(key));
-    let mut expected_result = blocks.to_vec();
-
-    for block in expected_result.iter_mut() {
-        aes_clear.encrypt_block(GenericArray::from_mut_slice(block));
+fn parse_hex_key(hex_str: &str) -> Result<Vec<u8>, String> {
+    if ![32, 48, 64].contains(&hex_str.len()) {
+        return Err(format!(
+            "Must be 32, 48 or 64 hex characters (16, 24 or 32 bytes), it is currently {} characters.",
+            hex_str.len()
+        ));
     }
+    hex::decode(hex_str).map_err(|_| "Failed to decode hex".to_string())
+}
 
-    let keys = key_expansion(key, key_expansion_offline, server_key, client_key);
-
-    // ENCRYPTION
-    println!("---Begin Encryption---");
-    let ecb = ECB::new(&keys);
-
-    let mut encrypted_blocks = blocks
-        .iter()
-        .map(|x| State::from_u8_enc(x, client_key))
-        .collect::<Vec<_>>(); // Convert into State Matrixes and encrypt with FHE
-
-    let start = Instant::now();
-    encrypted_blocks
-        .iter_mut()
-        .for_each(|x| ecb.encrypt(x, server_key)); // Encrypt with AES
-
-    println!(
-        "AES of #{:?} outputs computed in: {:?}",
-        blocks.len(),
-        start.elapsed()
-    );
-
-    assert_eq!(
-        encrypted_blocks
-            .iter()
-            .map(|x| x.decrypt_to_u8(client_key))
-            .collect::<Vec<_>>(),
-        expected_result
-    );
-
-    // DECRYPTION
-    println!("---Decryption---");
-
-    let start = Instant::now();
-    encrypted_blocks
-        .iter_mut()
-        .for_each(|x| ecb.decrypt(x, server_key)); // Decrypt with AES
-    println!(
-        "AES of #{:?} outputs decrypted in: {:?}",
-        blocks.len(),
-        start.elapsed()
-    );
+fn test_mode(test: &TestCase, context: &FheAesContext, client_key: &ClientKey) {
+    let TestCase {
+        mode,
+        key,
+        iv,
+        blocks,
+        key_expansion_offline,
+    } = *test;
+    println!("---Testing {} mode---", mode);
 
-    assert_eq!(
-        encrypted_blocks
-            .iter()
-            .map(|x| x.decrypt_to_u8(client_key))
-            .collect::<Vec<_>>(),
-        blocks.to_vec()
-    );
+    let keys = key_expansion(key, key_expansion_offline, context, client_key);
 
-    println!("ECB mode test passed");
+    match mode {
+        Mode::ECB => {
+            let aes_clear = AesClear::new(key);
+            let mut expected_result = blocks.to_vec();
+            expected_result
+                .iter_mut()
+                .for_each(|block| aes_clear.encrypt_block(block));
+            run_mode(
+                mode,
+                ECB::new(&keys),
+                blocks,
+                &expected_result,
+                context,
+                client_key,
+            )
+        }
+        Mode::CBC => run_mode(
+            mode,
+            CBC::new(&keys, &State::from_u8_enc(iv, client_key)),
+            blocks,
+            &cbc_encrypt_clear(blocks, key, iv),
+            context,
+            client_key,
+        ),
+        Mode::CTR => {
+            let counters = generate_counters(iv, blocks.len());
+            // The nonce is public, so the server generates the counters in the clear
+            run_mode(
+                mode,
+                CTR::from_counter(&keys, &initial_counter(iv)),
+                blocks,
+                &ctr_encrypt_clear(blocks, key, &counters),
+                context,
+                client_key,
+            )
+        }
+        Mode::OFB => run_mode(
+            mode,
+            OFB::new(&keys, &State::from_u8_enc(iv, client_key)),
+            blocks,
+            &ofb_encrypt_clear(blocks, key, iv),
+            context,
+            client_key,
+        ),
+    }
 }
 
-fn test_cbc(
-    key: &[u8; 16],
-    iv: &[u8; 16],
+/// Encrypts the blocks in any mode, checks them against the clear reference, and decrypts them back
+fn run_mode<M: FheBlockMode>(
+    mode: Mode,
+    mut fhe_mode: M,
     blocks: &[[u8; 16]],
-    key_expansion_offline: bool,
-    number_of_outputs: u8,
-    server_key: &ServerKey,
+    expected_result: &[[u8; 16]],
+    context: &FheAesContext,
     client_key: &ClientKey,
 ) {
-    println!("Testing CBC mode");
-
-    let expected_result = cbc_encrypt_clear(blocks, key, iv);
-
-    let keys = key_expansion(key, key_expansion_offline, server_key, client_key);
-    let iv = State::from_u8_enc(iv, client_key);
     // ENCRYPTION
     println!("---Begin Encryption---");
-    let cbc: CBC = CBC::new(&keys, &iv, number_of_outputs);
-
     let start = Instant::now();
     let mut encrypted_blocks = blocks
         .iter()
@@ -221,12 +235,8 @@ fn test_cbc(
     println!("Conversion to FHE Time Taken: {:?}", start.elapsed());
 
     let start = Instant::now();
-    cbc.encrypt(&mut encrypted_blocks, server_key); // Encrypt with AES
-    println!(
-        "AES of #{:?} outputs computed in: {:?}",
-        blocks.len(),
-        start.elapsed()
-    );
+    fhe_mode.encrypt(&mut encrypted_blocks, context); // Encrypt with AES
+    print_throughput("computed", blocks.len(), start.elapsed());
 
     assert_eq!(
         encrypted_blocks
@@ -240,12 +250,8 @@ fn test_cbc(
     println!("---Decryption---");
 
     let start = Instant::now();
-    cbc.decrypt(&mut encrypted_blocks, server_key); // Decrypt with AES
-    println!(
-        "AES of #{:?} outputs decrypted in: {:?}",
-        blocks.len(),
-        start.elapsed()
-    );
+    fhe_mode.decrypt(&mut encrypted_blocks, context); // Decrypt with AES
+    print_throughput("decrypted", blocks.len(), start.elapsed());
 
     assert_eq!(
         encrypted_blocks
@@ -255,107 +261,90 @@ fn test_cbc(
         blocks.to_vec()
     );
 
-    println!("CBC mode test passed");
+    println!("{} mode test passed", mode);
 }
 
-fn test_ctr(
-    key: &[u8; 16],
-    iv: &[u8; 16],
-    blocks: &[[u8; 16]],
-    key_expansion_offline: bool,
-    number_of_outputs: u8,
-    server_key: &ServerKey,
-    client_key: &ClientKey,
-) {
-    println!("Testing CTR mode");
-    let counters = generate_counters(iv, number_of_outputs);
-    let expected_result = ctr_encrypt_clear(blocks, key, &counters);
-
-    let keys = key_expansion(key, key_expansion_offline, server_key, client_key);
-
-    // ENCRYPTION
-    println!("---Begin Encryption---");
-    let encrypted_counters = counters
-        .iter()
-        .map(|x| State::from_u8_enc(x, client_key))
-        .collect::<Vec<_>>(); // Convert into State Matrixes and encrypt with FHE
-    let ctr = CTR::new(&keys, &encrypted_counters, number_of_outputs);
+/// Prints the time taken by `n` blocks, and the time and throughput per block
+fn print_throughput(verb: &str, n: usize, elapsed: Duration) {
+    println!("AES of #{:?} outputs {} in: {:?}", n, verb, elapsed);
+    if n > 0 {
+        println!(
+            "Per block: {:?} ({:.3} blocks/s)",
+            elapsed.div_f64(n as f64),
+            n as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
 
-    let mut encrypted_blocks = blocks
-        .iter()
-        .map(|x| State::from_u8_enc(x, client_key))
-        .collect::<Vec<_>>(); // Convert into State Matrixes and encrypt with FHE
+fn test_dag(test: &TestCase, context: &FheAesContext, client_key: &ClientKey) {
+    let TestCase {
+        mode,
+        key,
+        iv,
+        blocks,
+        key_expansion_offline,
+    } = *test;
+    println!("Testing {} mode as a single gate DAG", mode);
+
+    let (ivs, expected_result) = match mode {
+        Mode::ECB => {
+            let aes_clear = AesClear::new(key);
+            let mut expected_result = blocks.to_vec();
+            expected_result
+                .iter_mut()
+                .for_each(|block| aes_clear.encrypt_block(block));
+            (Vec::new(), expected_result)
+        }
+        Mode::CBC => (vec![*iv], cbc_encrypt_clear(blocks, key, iv)),
+        Mode::CTR => {
+            let counters = generate_counters(iv, blocks.len());
+            let expected_result = ctr_encrypt_clear(blocks, key, &counters);
+            (counters, expected_result)
+        }
+        Mode::OFB => (vec![*iv], ofb_encrypt_clear(blocks, key, iv)),
+    };
 
+    // LOWERING
+    println!("---Lowering to a gate DAG---");
     let start = Instant::now();
-    ctr.encrypt(&mut encrypted_blocks, server_key); // Encrypt with AES
-    println!(
-        "AES of #{:?} outputs computed in: {:?}",
+    let dag = EncryptionDag::with_s_box_plans(
+        mode,
+        KeySize::from_bytes(key.len()),
         blocks.len(),
-        start.elapsed()
+        key_expansion_offline,
+        context.s_box_plans().clone(),
     );
-
-    assert_eq!(
-        encrypted_blocks
-            .iter()
-            .map(|x| x.decrypt_to_u8(client_key))
-            .collect::<Vec<_>>(),
-        expected_result
-    );
-
-    // DECRYPTION
-    println!("---Decryption---");
-
-    let start = Instant::now();
-    ctr.decrypt(&mut encrypted_blocks, server_key); // Decrypt with AES
     println!(
-        "AES of #{:?} outputs decrypted in: {:?}",
-        blocks.len(),
+        "DAG of {} gates, {} bootstraps and depth {} lowered in: {:?}",
+        dag.dag().num_gates(),
+        dag.dag().bootstraps(),
+        dag.dag().depth(),
         start.elapsed()
     );
 
-    assert_eq!(
-        encrypted_blocks
+    let keys: Vec<Key> = if key_expansion_offline {
+        key_expansion_clear(key)
             .iter()
-            .map(|x| x.decrypt_to_u8(client_key))
-            .collect::<Vec<_>>(),
-        blocks.to_vec()
-    );
-
-    println!("CTR mode test passed");
-}
-
-fn test_ofb(
-    key: &[u8; 16],
-    iv: &[u8; 16],
-    blocks: &[[u8; 16]],
-    key_expansion_offline: bool,
-    number_of_outputs: u8,
-    server_key: &ServerKey,
-    client_key: &ClientKey,
-) {
-    println!("Testing OFB mode");
-
-    let expected_result = ofb_encrypt_clear(blocks, key, iv);
-
-    let keys = key_expansion(key, key_expansion_offline, server_key, client_key);
-    let iv = State::from_u8_enc(iv, client_key);
+            .map(|clear_key| Key::from_u8_enc(clear_key, client_key))
+            .collect()
+    } else {
+        vec![Key::from_u8_enc(key, client_key)]
+    };
+    let ivs: Vec<_> = ivs
+        .iter()
+        .map(|iv| State::from_u8_enc(iv, client_key))
+        .collect();
 
     // ENCRYPTION
     println!("---Begin Encryption---");
-    let ofb = OFB::new(&keys, &iv, number_of_outputs);
-
     let mut encrypted_blocks = blocks
         .iter()
         .map(|x| State::from_u8_enc(x, client_key))
         .collect::<Vec<_>>(); // Convert into State Matrixes and encrypt with FHE
 
     let start = Instant::now();
-    ofb.encrypt(&mut encrypted_blocks, server_key); // Encrypt with AES
-    println!(
-        "AES of #{:?} outputs computed in: {:?}",
-        blocks.len(),
-        start.elapsed()
-    );
+    dag.encrypt(&keys, &ivs, &mut encrypted_blocks, context); // Key schedule and AES in a single DAG
+    print_throughput("computed", blocks.len(), start.elapsed());
 
     assert_eq!(
         encrypted_blocks
@@ -365,34 +354,15 @@ fn test_ofb(
         expected_result
     );
 
-    // DECRYPTION
-    println!("---Decryption---");
-
-    let start = Instant::now();
-    ofb.decrypt(&mut encrypted_blocks, server_key); // Decrypt with AES
-    println!(
-        "AES of #{:?} outputs decrypted in: {:?}",
-        blocks.len(),
-        start.elapsed()
-    );
-
-    assert_eq!(
-        encrypted_blocks
-            .iter()
-            .map(|x| x.decrypt_to_u8(client_key))
-            .collect::<Vec<_>>(),
-        blocks.to_vec()
-    );
-
-    println!("OFB mode test passed");
+    println!("{} gate DAG test passed", mode);
 }
 
 fn key_expansion(
-    key: &[u8; 16],
+    key: &[u8],
     key_expansion_offline: bool,
-    server_key: &ServerKey,
+    context: &FheAesContext,
     client_key: &ClientKey,
-) -> [Key; 11] {
+) -> Vec<Key> {
     // KEY EXPANSION
     println!(
         "---Key Expansion ({:})---",
@@ -404,12 +374,14 @@ fn key_expansion(
     );
 
     let start = Instant::now();
-    let keys: [Key; 11] = if key_expansion_offline {
-        let clear_keys = key_expansion_clear(key);
-        array::from_fn(|i| Key::from_u8_enc(&clear_keys[i], client_key))
+    let keys: Vec<Key> = if key_expansion_offline {
+        key_expansion_clear(key)
+            .iter()
+            .map(|clear_key| Key::from_u8_enc(clear_key, client_key))
+            .collect()
     } else {
         let curr_key = Key::from_u8_enc(key, client_key);
-        curr_key.generate_round_keys(server_key)
+        curr_key.generate_round_keys(context)
     };
 
     println!("AES key expansion took: {:?}", start.elapsed());
@@ -417,8 +389,8 @@ fn key_expansion(
     keys
 }
 
-fn cbc_encrypt_clear(blocks: &[[u8; 16]], key: &[u8; 16], iv: &[u8; 16]) -> Vec<[u8; 16]> {
-    let aes = Aes128::new(GenericArray::from_slice(key));
+fn cbc_encrypt_clear(blocks: &[[u8; 16]], key: &[u8], iv: &[u8; 16]) -> Vec<[u8; 16]> {
+    let aes = AesClear::new(key);
     let mut prev_cipher = *iv; // Start with IV
     let mut ciphertext = Vec::with_capacity(blocks.len());
     let mut blocks = blocks.to_vec();
@@ -430,8 +402,7 @@ fn cbc_encrypt_clear(blocks: &[[u8; 16]], key: &[u8; 16], iv: &[u8; 16]) -> Vec<
         }
 
         // Encrypt block
-        let mut block_arr = GenericArray::from_mut_slice(block);
-        aes.encrypt_block(&mut block_arr);
+        aes.encrypt_block(block);
 
         // Store ciphertext and update previous block
         ciphertext.push(*block);
@@ -441,10 +412,16 @@ fn cbc_encrypt_clear(blocks: &[[u8; 16]], key: &[u8; 16], iv: &[u8; 16]) -> Vec<
     ciphertext
 }
 
-fn generate_counters(iv: &[u8; 16], number_of_outputs: u8) -> Vec<[u8; 16]> {
-    let mut counters = Vec::with_capacity(number_of_outputs as usize);
+/// The first counter, made of the nonce of the IV and a block counter of 0
+fn initial_counter(iv: &[u8; 16]) -> [u8; 16] {
     let mut counter = iv.clone();
     counter[8..16].fill(0); // Clear the counter part of the IV
+    counter
+}
+
+fn generate_counters(iv: &[u8; 16], number_of_outputs: usize) -> Vec<[u8; 16]> {
+    let mut counters = Vec::with_capacity(number_of_outputs);
+    let mut counter = initial_counter(iv);
 
     for _ in 0..number_of_outputs {
         counters.push(counter);
@@ -465,13 +442,12 @@ fn increment_counter(mut counter: [u8; 16]) -> [u8; 16] {
     counter
 }
 
-fn ctr_encrypt_clear(blocks: &[[u8; 16]], key: &[u8; 16], counters: &[[u8; 16]]) -> Vec<[u8; 16]> {
+fn ctr_encrypt_clear(blocks: &[[u8; 16]], key: &[u8], counters: &[[u8; 16]]) -> Vec<[u8; 16]> {
     let mut result = counters.to_vec();
-    let aes = Aes128::new(GenericArray::from_slice(key));
+    let aes = AesClear::new(key);
 
     for i in 0..result.len() {
-        let mut counter_arr = GenericArray::from_mut_slice(&mut result[i]);
-        aes.encrypt_block(&mut counter_arr);
+        aes.encrypt_block(&mut result[i]);
 
         for j in 0..16 {
             result[i][j] ^= blocks[i][j];
@@ -481,12 +457,11 @@ fn ctr_encrypt_clear(blocks: &[[u8; 16]], key: &[u8; 16], counters: &[[u8; 16]])
     result
 }
 
-fn ofb_encrypt_clear(blocks: &[[u8; 16]], key: &[u8; 16], iv: &[u8; 16]) -> Vec<[u8; 16]> {
+fn ofb_encrypt_clear(blocks: &[[u8; 16]], key: &[u8], iv: &[u8; 16]) -> Vec<[u8; 16]> {
     let mut result = blocks.to_vec();
-    let aes = Aes128::new(GenericArray::from_slice(key));
+    let aes = AesClear::new(key);
 
     let mut curr_cipher = iv.clone();
-    let mut curr_cipher = GenericArray::from_mut_slice(&mut curr_cipher);
     aes.encrypt_block(&mut curr_cipher);
 
     for i in 0..result.len() {